@@ -0,0 +1,204 @@
+//! Admin socket, serves `Shell` commands to admin clients
+//!
+//! Nodes running without a terminal can be administered by connecting to the admin socket, either
+//! a Unix socket or a TCP port on a loopback address. Connections are not authenticated, so access
+//! is only limited by who can reach the socket.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::str::FromStr;
+use std::thread::Builder;
+
+use app::{App, AppHandle};
+use shell;
+
+/// Address of an admin socket, `unix:<path>` or `<IP>:<port>`
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdminAddr {
+    Tcp(SocketAddr),
+    Unix(String)
+}
+
+/// A connection to an admin socket
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream)
+}
+
+/// Starts listening on `addr`, serving each connection on its own thread.
+pub fn spawn(app: &App, addr: &AdminAddr) -> io::Result<()> {
+    let app = app.handle();
+
+    match *addr {
+        AdminAddr::Tcp(addr) => {
+            let listener = try!(TcpListener::bind(addr));
+
+            try!(thread("Admin").spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => serve(&app, Connection::Tcp(stream)),
+                        Err(e) => println!("Admin connection error: {}", e)
+                    }
+                }
+            }));
+        },
+        AdminAddr::Unix(ref path) => {
+            // Remove the socket of a previous run, but nothing else
+            if let Ok(metadata) = fs::metadata(path) {
+                if metadata.file_type().is_socket() {
+                    try!(fs::remove_file(path));
+                }
+            }
+
+            let listener = try!(UnixListener::bind(path));
+
+            try!(thread("Admin").spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => serve(&app, Connection::Unix(stream)),
+                        Err(e) => println!("Admin connection error: {}", e)
+                    }
+                }
+            }));
+        }
+    }
+
+    println!("Admin listening on: {}", addr);
+
+    Ok(())
+}
+
+/// Runs `Shell` commands from `connection` on a new thread.
+fn serve(app: &AppHandle, connection: Connection) {
+    let app = app.clone();
+
+    let reader = match connection.try_clone() {
+        Ok(reader) => BufReader::new(reader),
+        Err(e) => {
+            println!("Admin connection error: {}", e);
+            return;
+        }
+    };
+
+    let spawned = thread("Admin.connection").spawn(move || {
+        shell::serve(app, reader, connection);
+    });
+
+    if let Err(e) = spawned {
+        println!("Admin connection error: {}", e);
+    }
+}
+
+impl Connection {
+    pub fn connect(addr: &AdminAddr) -> io::Result<Connection> {
+        match *addr {
+            AdminAddr::Tcp(ref addr) => TcpStream::connect(addr).map(Connection::Tcp),
+            AdminAddr::Unix(ref path) => UnixStream::connect(path).map(Connection::Unix)
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        match *self {
+            Connection::Tcp(ref stream) => stream.try_clone().map(Connection::Tcp),
+            Connection::Unix(ref stream) => stream.try_clone().map(Connection::Unix)
+        }
+    }
+
+    /// Signals the end of commands, while replies can still be read.
+    pub fn close_write(&self) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref stream) => stream.shutdown(Shutdown::Write),
+            Connection::Unix(ref stream) => stream.shutdown(Shutdown::Write)
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Tcp(ref mut stream) => stream.read(buf),
+            Connection::Unix(ref mut stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Tcp(ref mut stream) => stream.write(buf),
+            Connection::Unix(ref mut stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref mut stream) => stream.flush(),
+            Connection::Unix(ref mut stream) => stream.flush()
+        }
+    }
+}
+
+impl fmt::Display for AdminAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AdminAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            AdminAddr::Unix(ref path) => write!(f, "unix:{}", path)
+        }
+    }
+}
+
+impl FromStr for AdminAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AdminAddr, String> {
+        if s.starts_with("unix:") {
+            match &s[5..] {
+                "" => Err("Missing socket path".into()),
+                path => Ok(AdminAddr::Unix(path.into()))
+            }
+        }
+        else {
+            s.parse().map(AdminAddr::Tcp).map_err(|_| format!("Expected unix:<path> or IP:port, got {:?}", s))
+        }
+    }
+}
+
+fn thread(name: &str) -> Builder {
+    Builder::new().name(name.into())
+}
+
+#[test]
+fn test_admin() {
+    let dir = "test_data/admin";
+    let path = format!("{}/admin.sock", dir);
+
+    fs::create_dir_all(dir).unwrap();
+
+    assert_eq!(format!("unix:{}", path).parse(), Ok(AdminAddr::Unix(path.clone())));
+    assert_eq!("127.0.0.1:1300".parse(), Ok(AdminAddr::Tcp("127.0.0.1:1300".parse().unwrap())));
+    assert!("unix:".parse::<AdminAddr>().is_err());
+    assert!("moo".parse::<AdminAddr>().is_err());
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let app = App::new(id);
+    let addr = AdminAddr::Unix(path);
+
+    // A socket left over from a previous run is replaced
+    spawn(&app, &addr).unwrap();
+    spawn(&app, &addr).unwrap();
+
+    let mut connection = Connection::connect(&addr).unwrap();
+    let mut output = String::new();
+
+    // Sessions end with `exit` instead of shutting the node down
+    connection.write_all(b"moo\nstats\nexit\n").unwrap();
+    connection.read_to_string(&mut output).unwrap();
+
+    assert!(output.contains("Bad command"));
+    assert!(output.contains("\"zones\""));
+    assert!(! app.handle().is_stopping());
+}
@@ -1,18 +1,38 @@
 //! Represents the entire application.
 //!
 //! `handle` Contains handles of all processes.
+//!
+//! `AppHandle::shutdown` stops the application in order, so no data is lost on a planned restart.
 
+use std::process;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
 
+use clock::Clock;
 use command::Call;
 use cluster::{ClusterHandle, ClusterChannel};
-use manager::{ManagerHandle, ManagerChannel};
+use delegate::{DelegationStrategy, SizeStrategy};
+use manager::{self, EvictionPolicy, ManagerHandle, ManagerChannel, MemoryBudget};
 use replica::Replica;
-use store::{StoreHandle, StoreChannel};
+use store::{self, StoreHandle, StoreChannel};
+
+/// Time to wait for peers to receive pending messages on shutdown (in s)
+const PEER_FLUSH_TIMEOUT: u64 = 5;
+
+/// Time to wait for each Zone to be saved on shutdown (in s)
+const ZONE_FLUSH_TIMEOUT: u64 = 30;
 
 pub struct App {
     pub id: Replica,
+    pub clock: Arc<Clock>,
+    pub delegation: Arc<DelegationStrategy>,
+    pub eviction: EvictionPolicy,
+    pub memory: MemoryBudget,
+    pub store_threads: usize,
+    pub zone_threads: usize,
 
     pub cluster: ClusterHandle,
     pub manager: ManagerHandle,
@@ -20,17 +40,23 @@ pub struct App {
 
     pub channels: Channels,
 
-    pub stats: Arc<Stats>
+    pub stats: Arc<Stats>,
+    pub stopping: Arc<AtomicBool>
 }
 
 /// The shareable reference to the App
 #[derive(Clone)]
 pub struct AppHandle {
+    pub id: Replica,
+    pub clock: Arc<Clock>,
+    pub delegation: Arc<DelegationStrategy>,
+
     pub cluster: ClusterHandle,
     pub manager: ManagerHandle,
     pub store: StoreHandle,
 
-    pub stats: Arc<Stats>
+    pub stats: Arc<Stats>,
+    pub stopping: Arc<AtomicBool> // Set once shutdown has started, no new commands are accepted
 }
 
 #[derive(Clone)]
@@ -64,6 +90,7 @@ pub struct ClientStats {
 
 #[derive(Default, Serialize)]
 pub struct ClusterStats {
+    pub ack_timeouts: Stat,
     pub broadcast: Stat,
     pub handle_cluster_message: Stat,
     pub replicas: Stat,
@@ -75,6 +102,8 @@ pub struct StoreStats {
     pub reads: Stat,
     pub reads_pending: Stat,
     pub reads_errors: Stat,
+    pub log_appends: Stat,
+    pub log_errors: Stat,
     pub writes: Stat,
     pub writes_pending: Stat,
     pub writes_errors: Stat
@@ -82,15 +111,24 @@ pub struct StoreStats {
 
 #[derive(Default, Serialize)]
 pub struct ZoneStats {
+    pub conflicts: Stat,
+    pub errors: Stat,
+    pub evictions: Stat,
     pub local_active: Stat,
-    pub local_loaded: Stat
+    pub local_bytes: Stat,
+    pub local_loaded: Stat,
+    pub reloads: Stat,
+    pub tombstones_cleared: Stat
 }
 
 #[derive(Default, Serialize)]
 pub struct CommandStats {
     pub bind: Stat,
+    pub cas: Stat,
+    pub incr: Stat,
     pub kill: Stat,
     pub read: Stat,
+    pub unbind: Stat,
     pub write: Stat
 }
 
@@ -108,6 +146,12 @@ impl App {
 
         App {
             id: id,
+            clock: Arc::new(Clock::new()),
+            delegation: Arc::new(SizeStrategy::default()),
+            eviction: Default::default(),
+            memory: Default::default(),
+            store_threads: store::NUM_THREADS,
+            zone_threads: manager::NUM_THREADS,
 
             cluster: cluster.handle(),
             manager: manager.handle(),
@@ -119,17 +163,23 @@ impl App {
                 store: Some(store)
             },
 
-            stats: Default::default()
+            stats: Default::default(),
+            stopping: Arc::new(AtomicBool::new(false))
         }
     }
 
     pub fn handle(&self) -> AppHandle {
         AppHandle {
+            id: self.id.clone(),
+            clock: self.clock.clone(),
+            delegation: self.delegation.clone(),
+
             cluster: self.cluster.clone(),
             manager: self.manager.clone(),
             store: self.store.clone(),
 
-            stats: self.stats.clone()
+            stats: self.stats.clone(),
+            stopping: self.stopping.clone()
         }
     }
 
@@ -138,6 +188,66 @@ impl App {
     }
 }
 
+impl AppHandle {
+    /// Returns true once shutdown has started.
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting commands, saves all dirty Zones, waits for the Store and Peers to finish
+    /// pending work, then exits. Only the first call shuts down, later calls return.
+    pub fn shutdown(&self) {
+        if self.stopping.swap(true, Ordering::SeqCst) {
+            return; // already shutting down
+        }
+
+        println!("Shutting down...");
+
+        // Queued commands may create Zones, so repeat until no new Zones show up
+        loop {
+            let zones = self.manager.list();
+            let flushing: Vec<_> = zones.iter().map(|zone| zone.flush()).collect();
+            let (tx, rx) = channel();
+
+            // Zone replies can't be waited for with a timeout, so wait on a separate thread
+            thread::spawn(move|| {
+                for flushed in flushing {
+                    flushed.recv().is_ok(); // retired Zones drop the reply
+
+                    if tx.send(()).is_err() {
+                        return; // gave up waiting
+                    }
+                }
+            });
+
+            let timeout = Duration::from_secs(ZONE_FLUSH_TIMEOUT);
+            let saved = (0..zones.len()).take_while(|_| rx.recv_timeout(timeout).is_ok()).count();
+
+            if saved < zones.len() {
+                println!("  Saved {} zones, {} could not be saved in time", saved, zones.len() - saved);
+                break;
+            }
+
+            if self.manager.list().len() <= zones.len() {
+                println!("  Saved {} zones", zones.len());
+                break;
+            }
+        }
+
+        self.store.flush();
+        println!("  Store flushed");
+
+        if self.cluster.flush(Duration::from_secs(PEER_FLUSH_TIMEOUT)) {
+            println!("  Peers flushed");
+        }
+        else {
+            println!("  Some peers did not receive all messages");
+        }
+
+        process::exit(0);
+    }
+}
+
 impl Stats {
     pub fn to_json(&self) -> String {
         use serde_json;
@@ -150,8 +260,11 @@ impl CommandStats {
     pub fn increment(&self, call: &Call) {
         match call {
             &Call::Bind => self.bind.increment(),
+            &Call::Cas => self.cas.increment(),
+            &Call::Incr => self.incr.increment(),
             &Call::Kill => self.kill.increment(),
             &Call::Read => self.read.increment(),
+            &Call::Unbind => self.unbind.increment(),
             &Call::Write => self.write.increment()
         };
     }
@@ -162,10 +275,18 @@ impl Stat {
         self.value.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn add(&self, value: usize) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+
     pub fn decrement(&self) {
         self.value.fetch_sub(1, Ordering::Relaxed);
     }
 
+    pub fn sub(&self, value: usize) {
+        self.value.fetch_sub(value, Ordering::Relaxed);
+    }
+
     pub fn set(&self, value: usize) {
         self.value.store(value, Ordering::Relaxed);
     }
@@ -0,0 +1,70 @@
+//! Connects to the admin socket of a running node, and runs shell commands on it
+
+extern crate qumulus;
+
+use std::io::{self, BufRead, Write};
+use std::thread;
+
+use qumulus::admin::{AdminAddr, Connection};
+
+fn main() {
+    let args: Vec<_> = std::env::args().collect();
+
+    if args.len() < 2 {
+        println!("Usage: {} <unix:path | IP:port> [command...]", &args[0]);
+        println!("Runs a single command, or commands read from stdin, e.g. stats, active, zone.dump <path>.");
+
+        std::process::exit(2);
+    }
+
+    let addr: AdminAddr = match args[1].parse() {
+        Ok(addr) => addr,
+        Err(err) => {
+            println!("Invalid admin address: {}", err);
+            std::process::exit(2);
+        }
+    };
+
+    let connection = match Connection::connect(&addr) {
+        Ok(connection) => connection,
+        Err(err) => {
+            println!("Could not connect to {}: {}", addr, err);
+            std::process::exit(2);
+        }
+    };
+
+    let mut writer = connection.try_clone().expect("Could not clone connection");
+    let mut reader = connection;
+
+    // Print replies until the node closes the connection
+    let replies = thread::spawn(move || {
+        let stdout = io::stdout();
+
+        io::copy(&mut reader, &mut stdout.lock()).is_ok(); // ignore if node goes away
+    });
+
+    let sent = if args.len() > 2 {
+        writeln!(writer, "{}\nexit", args[2..].join(" "))
+    }
+    else {
+        let stdin = io::stdin();
+        let mut sent = Ok(());
+
+        for line in stdin.lock().lines() {
+            sent = line.and_then(|line| writeln!(writer, "{}", line));
+
+            if sent.is_err() {
+                break;
+            }
+        }
+
+        sent
+    };
+
+    if let Err(err) = sent.and_then(|_| writer.close_write()) {
+        println!("Connection to {} lost: {}", addr, err);
+    }
+
+    replies.join().unwrap();
+    println!();
+}
@@ -0,0 +1,61 @@
+//! Checks a data directory of a stopped node, and optionally repairs it
+
+extern crate qumulus;
+
+use qumulus::store::fsck;
+
+fn main() {
+    let args: Vec<_> = std::env::args().collect();
+
+    let (repair, dir) = match args.len() {
+        2 => (false, &args[1]),
+        3 if args[1] == "--repair" => (true, &args[2]),
+        _ => {
+            println!("Usage: {} [--repair] <data directory>", &args[0]);
+            println!("Checks zone files, logs and delegations. With --repair, torn logs are truncated,");
+            println!("broken or orphaned files are moved to {}/ and the manifest is rebuilt.", fsck::QUARANTINE);
+
+            std::process::exit(2);
+        }
+    };
+
+    let report = match fsck::check(dir) {
+        Err(err) => {
+            println!("Could not check {}: {}", dir, err);
+            std::process::exit(2);
+        },
+        Ok(report) => report
+    };
+
+    for issue in report.issues.iter() {
+        println!("  {}", issue);
+    }
+
+    println!("Checked {} zones, {} logs: {} issues", report.zones, report.logs, report.issues.len());
+
+    if report.issues.is_empty() {
+        return;
+    }
+
+    if ! repair {
+        std::process::exit(1);
+    }
+
+    match fsck::repair(dir, report.issues) {
+        Err(err) => {
+            println!("Could not repair {}: {}", dir, err);
+            std::process::exit(2);
+        },
+        Ok(unrepaired) => {
+            for issue in unrepaired.iter() {
+                println!("  Not repaired: {}", issue);
+            }
+
+            println!("Repaired {}", dir);
+
+            if ! unrepaired.is_empty() {
+                std::process::exit(1);
+            }
+        }
+    }
+}
@@ -5,6 +5,7 @@ use std::io::prelude::*;
 use std::io::BufReader;
 use std::mem;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 
 use mioco::sync::mpsc::{channel, Receiver, Sender};
@@ -15,12 +16,18 @@ use serde_json;
 use serde_json::Value;
 
 use app::AppHandle;
-use command::Command;
+use clock::Pending;
+use command::{Call, Command};
 use node::{DelegatedMatch, Update};
 use path::Path;
+use zone::ZoneResult;
+
+/// Source of unique client ids, used to scope binds to a connection
+static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(1);
 
 pub struct Client {
     app: AppHandle,
+    id: u64,
     stream: TcpStream,
     tx: Sender<String>
 }
@@ -32,6 +39,7 @@ impl Client {
 
         let client = Client {
             app: app,
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed) as u64,
             stream: stream,
             tx: tx
         };
@@ -58,7 +66,7 @@ impl Client {
 
         let reader = BufReader::new(self.stream.try_clone().unwrap());
 
-        let (commands_tx, commands_rx) = mioco::sync::mpsc::channel::<Command>();
+        let (commands_tx, commands_rx) = mioco::sync::mpsc::channel::<(Command, Pending)>();
 
         let commands_rx = Arc::new(Mutex::new(commands_rx));
 
@@ -66,12 +74,14 @@ impl Client {
         for _ in 0..1000 {
             let commands_rx = commands_rx.clone();
             let app = self.app.clone();
+            let client = self.id;
             let tx = self.tx.clone();
 
             mioco::spawn(move|| {
                 loop {
                     // quit if disconnected
-                    let command = match commands_rx.lock() {
+                    // The timestamp stays pending until the command's changes are replicated
+                    let (command, _pending) = match commands_rx.lock() {
                         Ok(c) => match c.recv() {
                             Ok(c) => c,
                             Err(_) => return
@@ -79,7 +89,7 @@ impl Client {
                         Err(_) => return
                     };
 
-                    process(&app, &tx, command);
+                    process(&app, client, &tx, command);
                 }
             });
         }
@@ -88,9 +98,16 @@ impl Client {
         for line in reader.lines() {
             match line {
                 Ok(line) => {
-                    match Command::from_json(&line) {
+                    let pending = self.app.clock.issue();
+
+                    match Command::from_json(&line, pending.timestamp()) {
                         Ok(command) => {
-                            commands_tx.send(command).unwrap();
+                            if self.app.is_stopping() {
+                                error(&self.app, &self.tx, command.id, "Shutting down");
+                                continue;
+                            }
+
+                            commands_tx.send((command, pending)).unwrap();
                         },
                         Err(e) => {
                             self.tx.send("[0,\"error\",\"".to_string() + &e + "\"]").unwrap();
@@ -132,19 +149,32 @@ impl Client {
 }
 
 /// Process a single command from client. Recursively dispatch for delegated zones.
-fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
+fn process(app: &AppHandle, client: u64, tx: &Sender<String>, mut command: Command) {
+    if command.exact() {
+        return process_exact(app, client, tx, command);
+    }
+
     let resolved_path = command.path.resolved();
     let (prefix, zone) = app.manager.find_nearest(&resolved_path);
 
+    // Prefix may contain buckets, which are not part of the client's path
     let c = Command {
-        path: command.path.slice(prefix.len()),
+        path: command.path.slice(prefix.without_buckets().len()),
         params: mem::replace(&mut command.params, Value::Null),
         ..command
     };
 
     app.stats.clients.commands.increment(&c.call);
 
-    let mut result = zone.dispatch(c, tx);
+    let mut result = zone.dispatch(c, client, tx);
+
+    if let Some(message) = result.error {
+        return error(app, tx, command.id, message);
+    }
+
+    if ! acknowledged(&mut result) {
+        return error(app, tx, command.id, "Not acknowledged");
+    }
 
     let mut queue: VecDeque<DelegatedMatch> = VecDeque::new();
 
@@ -168,11 +198,18 @@ fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
 
         let c = Command {
             path: delegated.match_spec,
-            params: Value::Null,
+            params: match command.call {
+                Call::Unbind => command.params.clone(),
+                _ => Value::Null
+            },
             ..command
         };
 
-        let result = zone.dispatch(c, tx);
+        let result = zone.dispatch(c, client, tx);
+
+        if let Some(message) = result.error {
+            return error(app, tx, command.id, message);
+        }
 
         for mut d in result.delegated {
             let mut path = delegated.path.clone();
@@ -189,7 +226,7 @@ fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
         let response = vec![
             id.into(),
             left.into(),
-            path.to_json(),
+            path.without_buckets().to_json(),
             update.map_or(Value::Null, |u| u.to_json())
         ];
 
@@ -200,6 +237,84 @@ fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
     }
 }
 
+/// Process a command from client that is decided by the `Zone` owning the exact path, following
+/// delegations made since the nearest `Zone` was found.
+///
+/// Replies with the deciding `Zone`. Conditional writes reply with `[ applied, current ]`, where
+/// `current` is only set if the write was not applied.
+fn process_exact(app: &AppHandle, client: u64, tx: &Sender<String>, command: Command) {
+    let resolved_path = command.path.resolved();
+    let (mut prefix, mut zone) = app.manager.find_nearest(&resolved_path);
+
+    app.stats.clients.commands.increment(&command.call);
+
+    loop {
+        let c = Command {
+            path: command.path.slice(prefix.without_buckets().len()),
+            params: command.params.clone(),
+            ..command
+        };
+
+        let mut result = zone.dispatch(c, client, tx);
+
+        if let Some(message) = result.error {
+            return error(app, tx, command.id, message);
+        }
+
+        if ! acknowledged(&mut result) {
+            return error(app, tx, command.id, "Not acknowledged");
+        }
+
+        match result.delegated.into_iter().next() {
+            Some(mut d) => {
+                prefix.append(&mut d.path);
+                zone = app.manager.load(&prefix);
+            },
+            None => {
+                let update = result.update.map_or(Value::Null, |u| u.to_json());
+
+                let outcome = match result.applied {
+                    Some(applied) => Value::Array(vec![applied.into(), update]),
+                    None => update
+                };
+
+                let response = vec![
+                    command.id.into(),
+                    0.into(),
+                    prefix.without_buckets().to_json(),
+                    outcome
+                ];
+
+                app.stats.clients.replies.increment();
+
+                tx.send(serde_json::to_string(&response).unwrap()).unwrap_or_default();
+
+                return;
+            }
+        }
+    }
+}
+
+/// Waits for changes of a command to be logged, and for changes of a durable command to be
+/// persisted, also by delegated Zones, and acknowledged by peers. Returns false if they were not.
+fn acknowledged(result: &mut ZoneResult) -> bool {
+    let succeeded = |rx: Receiver<Result<(), String>>| rx.recv().map_or(false, |result| result.is_ok());
+
+    let appended = result.appended.drain(..).all(&succeeded);
+    let synced = appended && result.synced.drain(..).all(&succeeded);
+
+    synced && result.acked.take().map_or(true, |acked| acked.recv().is_ok())
+}
+
+/// Replies to a command with an error.
+fn error(app: &AppHandle, tx: &Sender<String>, id: u64, message: &str) {
+    let response: Vec<Value> = vec![id.into(), "error".into(), message.into()];
+
+    app.stats.clients.replies.increment();
+
+    tx.send(serde_json::to_string(&response).unwrap()).unwrap_or_default();
+}
+
 fn pinger(tx: Sender<String>) {
     mioco::spawn(move|| {
         loop {
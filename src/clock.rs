@@ -0,0 +1,210 @@
+//! Hybrid logical clock, used to timestamp all changes.
+//!
+//! Timestamps combine wall clock time in milliseconds (upper 48 bits) with a logical counter
+//! (lower 16 bits). Timestamps issued by a `Clock` always increase, even if the wall clock goes
+//! backwards, and are always newer than any timestamp the `Clock` has observed from other
+//! replicas. This makes last-writer-wins comparable across replicas and restarts.
+//!
+//! Timestamps issued for changes are tracked as pending until the changes are passed on for
+//! replication, so heartbeats never claim a timestamp that changes still in flight are older than.
+//!
+//! The `Clock` also tracks the stable timestamp, before which all replicas are known to have
+//! received all changes. Tombstones older than the stable timestamp minus a configurable horizon
+//! can be cleared.
+
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use time;
+
+const LOGICAL_BITS: u64 = 16;
+
+/// Remote timestamps further ahead than this (in ms) are ignored
+const MAX_DRIFT_MS: u64 = 60 * 1000;
+
+/// Default age (in ms) of stable deletions before tombstones are cleared
+const TOMBSTONE_HORIZON_MS: u64 = 60 * 60 * 1000;
+
+pub struct Clock {
+    last: Mutex<u64>,
+    pending: Arc<Mutex<BTreeSet<u64>>>,
+    stable: Mutex<u64>,
+    tombstone_horizon: Mutex<u64>
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock {
+            last: Mutex::new(0),
+            pending: Arc::new(Mutex::new(BTreeSet::new())),
+            stable: Mutex::new(0),
+            tombstone_horizon: Mutex::new(TOMBSTONE_HORIZON_MS << LOGICAL_BITS)
+        }
+    }
+
+    /// Returns a new timestamp, newer than any previously issued or observed.
+    pub fn now(&self) -> u64 {
+        let physical = physical_now();
+        let mut last = self.last.lock().unwrap();
+
+        *last = if physical > *last { physical } else { *last + 1 };
+        *last
+    }
+
+    /// Returns a new timestamp for a change, pending until the returned `Pending` is dropped.
+    pub fn issue(&self) -> Pending {
+        // Hold the lock while issuing, so `replicated` can not return a newer timestamp first
+        let mut pending = self.pending.lock().unwrap();
+        let timestamp = self.now();
+
+        pending.insert(timestamp);
+
+        Pending { timestamp: timestamp, pending: self.pending.clone() }
+    }
+
+    /// Returns a timestamp before which all changes have been passed on for replication.
+    pub fn replicated(&self) -> u64 {
+        let pending = self.pending.lock().unwrap();
+        let now = self.now();
+
+        pending.iter().next().cloned().unwrap_or(now)
+    }
+
+    /// Advances clock past a timestamp received from another replica. Timestamps far ahead of the
+    /// wall clock are ignored, so a bad timestamp can not run the clock out of range.
+    pub fn observe(&self, timestamp: u64) {
+        let mut last = self.last.lock().unwrap();
+
+        if timestamp <= *last {
+            return;
+        }
+
+        if timestamp > physical_now() + (MAX_DRIFT_MS << LOGICAL_BITS) {
+            warn!("Ignoring observed timestamp {}, far ahead of local clock", timestamp);
+            return;
+        }
+
+        *last = timestamp;
+    }
+
+    /// Sets the stable timestamp, i.e. all replicas have received changes older than `timestamp`.
+    pub fn set_stable(&self, timestamp: u64) {
+        let mut stable = self.stable.lock().unwrap();
+
+        if timestamp > *stable {
+            *stable = timestamp;
+        }
+    }
+
+    /// Sets how long (in ms) stable deletions are kept before tombstones are cleared.
+    pub fn set_tombstone_horizon(&self, ms: u64) {
+        *self.tombstone_horizon.lock().unwrap() = ms << LOGICAL_BITS;
+    }
+
+    /// Returns the timestamp before which tombstones can be cleared.
+    pub fn tombstone_horizon(&self) -> u64 {
+        let stable = *self.stable.lock().unwrap();
+        let horizon = *self.tombstone_horizon.lock().unwrap();
+
+        stable.saturating_sub(horizon)
+    }
+}
+
+/// A timestamp issued for a change that has not been passed on for replication yet.
+pub struct Pending {
+    timestamp: u64,
+    pending: Arc<Mutex<BTreeSet<u64>>>
+}
+
+impl Pending {
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.timestamp);
+    }
+}
+
+/// Returns the wall clock part of a timestamp (or timestamp difference) in ms.
+pub fn millis(timestamp: u64) -> u64 {
+    timestamp >> LOGICAL_BITS
+}
+
+/// Returns wall clock time in ms.
+pub fn wall_millis() -> u64 {
+    millis(physical_now())
+}
+
+/// Returns wall clock time in the timestamp representation, with a zero logical counter.
+fn physical_now() -> u64 {
+    let now = time::get_time();
+    let ms = now.sec as u64 * 1000 + now.nsec as u64 / 1000000;
+
+    ms << LOGICAL_BITS
+}
+
+#[test]
+fn test_now() {
+    let clock = Clock::new();
+
+    let a = clock.now();
+    let b = clock.now();
+
+    assert!(b > a);
+    assert!(a >= physical_now() - (1000 << LOGICAL_BITS));
+}
+
+#[test]
+fn test_observe() {
+    let clock = Clock::new();
+
+    let ahead = physical_now() + (1000 << LOGICAL_BITS);
+
+    clock.observe(ahead);
+    assert!(clock.now() > ahead);
+
+    // Observing older timestamps does not move the clock back
+    let last = clock.now();
+
+    clock.observe(1);
+    assert!(clock.now() > last);
+
+    // Timestamps far ahead are ignored
+    clock.observe(u64::max_value());
+    assert!(clock.now() < physical_now() + (MAX_DRIFT_MS << LOGICAL_BITS));
+}
+
+#[test]
+fn test_replicated() {
+    let clock = Clock::new();
+
+    let a = clock.issue();
+    let b = clock.issue();
+    let last = b.timestamp();
+
+    assert_eq!(clock.replicated(), a.timestamp());
+
+    drop(a);
+    assert_eq!(clock.replicated(), b.timestamp());
+
+    drop(b);
+    assert!(clock.replicated() > last);
+}
+
+#[test]
+fn test_tombstone_horizon() {
+    let clock = Clock::new();
+
+    assert_eq!(clock.tombstone_horizon(), 0);
+
+    clock.set_tombstone_horizon(1000);
+    clock.set_stable(5000 << LOGICAL_BITS);
+    assert_eq!(clock.tombstone_horizon(), 4000 << LOGICAL_BITS);
+
+    // Stable timestamp never goes back
+    clock.set_stable(1);
+    assert_eq!(clock.tombstone_horizon(), 4000 << LOGICAL_BITS);
+}
@@ -1,14 +1,18 @@
 //! Cluster manager. Handles Cluster and Sharding (TODO)
 
+use std::cmp;
 use std::collections::{HashMap};
 use std::net::{SocketAddr,TcpListener,TcpStream};
 use std::sync::Arc;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread::Builder;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, Builder};
+use std::time::Duration;
 
 use bincode;
+use mioco;
 
 use app::{App, AppHandle};
+use clock;
 use node::NodeTree;
 use path::Path;
 use replica::Replica;
@@ -25,6 +29,12 @@ pub struct ClusterChannel {
     rx: Receiver<ClusterCall>
 }
 
+/// Interval between heartbeats to Peers.
+const HEARTBEAT_INTERVAL: u64 = 10;
+
+/// Acknowledgements pending for longer than this (in ms) fail at the next heartbeat.
+const ACK_TIMEOUT_MS: u64 = 10 * 1000;
+
 /// The Cluster manager.
 pub struct Cluster {
     app: AppHandle,
@@ -32,21 +42,45 @@ pub struct Cluster {
     id: Replica,
     peers: HashMap<Replica, Peer>,
     replicas: Vec<Replica>,
+    received: HashMap<Replica, u64>, // Latest heartbeat timestamp received from each Replica
+    stable: HashMap<Replica, u64>,   // Latest received timestamp reported by each Replica
+    acks: HashMap<u64, PendingAck>,  // Replicated data waiting to be acknowledged by Replicas
+    next_ack: u64,
     rx: Receiver<ClusterCall>
 }
 
+/// Replicated data of a durable call, waiting for acknowledgements.
+struct PendingAck {
+    remaining: usize,
+    sent: u64,
+    tx: mioco::sync::mpsc::Sender<()>
+}
+
 /// Intra-Cluster Messages.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum ClusterMessage {
     /// Data to be merged for Path
     Merge(Path, NodeTree),
-    Sync
+    /// Data to be merged for Path, acknowledged to the sending Replica with `Ack`
+    MergeAcked(Replica, u64, Path, NodeTree),
+    Ack(u64),
+    Sync,
+    /// Sent periodically by a Replica with the timestamp before which it has sent all its changes,
+    /// and the timestamp before which it has received all messages from all Replicas.
+    Heartbeat(Replica, u64, u64)
 }
 
 /// Interface to Peer.
 #[derive(Clone, Debug)]
 pub struct Peer {
-    tx: Sender<Arc<ClusterMessage>>
+    tx: Sender<PeerCall>
+}
+
+/// Used for dispatching calls to a Peer.
+#[derive(Debug)]
+enum PeerCall {
+    Send(Arc<ClusterMessage>),
+    Flush(Sender<()>) // Dropped once messages sent before are written
 }
 
 /// Peer internal state.
@@ -54,27 +88,29 @@ pub struct PeerState {
     addr: SocketAddr,
     pending: Option<Arc<ClusterMessage>>,
     stream: Option<TcpStream>,
-    rx: Receiver<Arc<ClusterMessage>>
+    rx: Receiver<PeerCall>
 }
 
 pub struct Server {
 }
 
 /// Used for dispatching calls via message passing.
-#[derive(Debug)]
 pub enum ClusterCall {
-    Add(Replica),
+    Add(Replica, SocketAddr),
+    Flush(Sender<()>),
     HandleClusterMessage(ClusterMessage),
+    Heartbeat(u64),
     Replicate(Path, NodeTree),
+    ReplicateAcked(Path, NodeTree, usize, mioco::sync::mpsc::Sender<()>),
     Sync,
     SyncAll,
     SyncZone(Path)
 }
 
 impl ClusterHandle {
-    /// Add a new Replica to cluster.
-    pub fn add(&self, replica: Replica) {
-        self.send(ClusterCall::Add(replica));
+    /// Add a new Replica to cluster, connecting to it at `peer`.
+    pub fn add(&self, replica: Replica, peer: SocketAddr) {
+        self.send(ClusterCall::Add(replica, peer));
     }
 
     /// Syncs all Zones.
@@ -97,13 +133,36 @@ impl ClusterHandle {
         self.send(ClusterCall::Replicate(path.clone(), data));
     }
 
+    /// Replicate data to all replicas, notifying `tx` once `acks` replicas received it. `tx` is
+    /// dropped if that does not happen in time.
+    pub fn replicate_acked(&self, path: &Path, data: NodeTree, acks: usize, tx: mioco::sync::mpsc::Sender<()>) {
+        self.send(ClusterCall::ReplicateAcked(path.clone(), data, acks, tx));
+    }
+
+    /// Waits up to `timeout` for messages sent so far to be written to all Peers. Returns false if
+    /// some were not.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let (tx, rx) = channel();
+
+        self.send(ClusterCall::Flush(tx));
+
+        // Each Peer drops its sender once flushed
+        rx.recv_timeout(timeout) == Err(RecvTimeoutError::Disconnected)
+    }
+
     /// Handles a message from the cluster.
     pub fn handle_cluster_message(&self, msg: ClusterMessage) {
         self.send(ClusterCall::HandleClusterMessage(msg));
     }
 
+    /// Sends a heartbeat to all Peers, `replicated` being the timestamp before which all local
+    /// changes have been passed to Cluster.
+    pub fn heartbeat(&self, replicated: u64) {
+        self.send(ClusterCall::Heartbeat(replicated));
+    }
+
     fn send(&self, call: ClusterCall) {
-        self.tx.send(call).expect("Cluster process not running");
+        self.tx.send(call).ok().expect("Cluster process not running");
     }
 }
 
@@ -130,21 +189,38 @@ impl Cluster {
             handle: app.cluster.clone(),
             peers: HashMap::new(),
             replicas: vec![],
+            received: HashMap::new(),
+            stable: HashMap::new(),
+            acks: HashMap::new(),
+            next_ack: 0,
             rx: rx.rx
         }
     }
 
-    /// Start the Cluster "process".
-    pub fn spawn(app: &mut App) {
+    /// Start the Cluster "process", listening for peers on `addr`.
+    pub fn spawn(app: &mut App, addr: SocketAddr) {
         let mut cluster = Cluster::new(app);
 
         thread("Cluster").spawn(move || {
-            cluster.run();
+            cluster.run(addr);
         }).expect("Cluster spawn failed");
     }
 
-    pub fn run(&mut self) {
-        Server::spawn(&self.id.peer_addr(), self.handle.clone());
+    pub fn run(&mut self, addr: SocketAddr) {
+        Server::spawn(&addr, self.handle.clone());
+
+        let handle = self.handle.clone();
+        let clock = self.app.clock.clone();
+
+        thread("Cluster.heartbeat").spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(HEARTBEAT_INTERVAL));
+
+                // Taken before queueing, so changes replicated before are sent before the heartbeat
+                handle.heartbeat(clock.replicated());
+            }
+        }).expect("Cluster heartbeat spawn failed");
+
         self.message_loop();
     }
 
@@ -153,9 +229,12 @@ impl Cluster {
             let call = self.rx.recv().expect("Cluster rx broken");
 
             match call {
-                ClusterCall::Add(replica) => self.add(replica),
+                ClusterCall::Add(replica, peer) => self.add(replica, peer),
+                ClusterCall::Flush(tx) => self.flush(tx),
                 ClusterCall::HandleClusterMessage(msg) => self.handle_cluster_message(msg),
+                ClusterCall::Heartbeat(replicated) => self.heartbeat(replicated),
                 ClusterCall::Replicate(path, data) => self.replicate(path, data),
+                ClusterCall::ReplicateAcked(path, data, acks, tx) => self.replicate_acked(path, data, acks, tx),
                 ClusterCall::Sync => self.sync(),
                 ClusterCall::SyncAll => self.sync_all(),
                 ClusterCall::SyncZone(path) => self.sync_zone(path)
@@ -164,22 +243,96 @@ impl Cluster {
     }
 
     /// Handles a message from the cluster.
-    fn handle_cluster_message(&self, msg: ClusterMessage) {
+    fn handle_cluster_message(&mut self, msg: ClusterMessage) {
         self.app.stats.cluster.handle_cluster_message.increment();
 
         match msg {
-            ClusterMessage::Merge(path, data) => {
-                // TODO thread pool
-                let zone = self.app.manager.load(&path);
+            ClusterMessage::Merge(path, data) => self.merge(path, data),
+            ClusterMessage::MergeAcked(replica, id, path, data) => {
+                self.merge(path, data);
 
-                zone.merge(data, false);
+                if let Some(peer) = self.peers.get(&replica) {
+                    peer.send(Arc::new(ClusterMessage::Ack(id)));
+                }
             },
-            ClusterMessage::Sync => self.sync()
+            ClusterMessage::Ack(id) => {
+                let done = match self.acks.get_mut(&id) {
+                    Some(pending) => {
+                        pending.remaining -= 1;
+                        pending.remaining == 0
+                    },
+                    None => false // already timed out
+                };
+
+                if done {
+                    if let Some(pending) = self.acks.remove(&id) {
+                        pending.tx.send(()).is_ok(); // ignore if caller goes away
+                    }
+                }
+            },
+            ClusterMessage::Sync => self.sync(),
+            ClusterMessage::Heartbeat(replica, timestamp, received) => {
+                // Peer streams are ordered, so all changes older than `timestamp` have arrived
+                self.app.clock.observe(timestamp);
+
+                let latest = self.received.entry(replica.clone()).or_insert(0);
+                *latest = cmp::max(*latest, timestamp);
+
+                let stable = self.stable.entry(replica).or_insert(0);
+                *stable = cmp::max(*stable, received);
+            }
         }
     }
 
-    /// Add a new Replica to Cluster
-    pub fn add(&mut self, replica: Replica) {
+    /// Merges data received from a Replica.
+    fn merge(&self, path: Path, data: NodeTree) {
+        // Ancestor visibility is not a timestamp, so only observe data
+        self.app.clock.observe(data.node.timestamp());
+
+        // TODO thread pool
+        // Zone may have been folded back into its parent, so route through nearest Zone.
+        // Delegated data is passed on to unloaded Zones by their parents.
+        let (prefix, zone) = self.app.manager.find_nearest(&path);
+
+        if prefix.len() == path.len() {
+            zone.merge(data, false);
+        }
+        else {
+            zone.merge(data.node.prepend_path(&path.path[prefix.len()..]).noop_vis(), false);
+        }
+    }
+
+    /// Sends a heartbeat to all Peers and updates the stable timestamp of the `Clock`. Fails
+    /// acknowledgements that have been pending for too long.
+    ///
+    /// Changes are timestamped before they are replicated, so the heartbeat carries `replicated`,
+    /// the timestamp before which all local changes have been passed to Cluster.
+    pub fn heartbeat(&mut self, replicated: u64) {
+        let now = self.app.clock.now();
+
+        let before = self.acks.len();
+
+        // Dropping the sender fails the waiting call
+        self.acks.retain(|_, pending| clock::millis(now.saturating_sub(pending.sent)) < ACK_TIMEOUT_MS);
+        self.app.stats.cluster.ack_timeouts.add(before - self.acks.len());
+
+        // All messages from all Replicas older than `received` have been received
+        let received = self.replicas.iter()
+            .map(|r| self.received.get(r).cloned().unwrap_or(0))
+            .fold(replicated, cmp::min);
+
+        self.broadcast(ClusterMessage::Heartbeat(self.id.clone(), replicated, received));
+
+        // All Replicas have received all messages older than `stable`
+        let stable = self.replicas.iter()
+            .map(|r| self.stable.get(r).cloned().unwrap_or(0))
+            .fold(received, cmp::min);
+
+        self.app.clock.set_stable(stable);
+    }
+
+    /// Add a new Replica to Cluster, connecting to it at `peer`
+    pub fn add(&mut self, replica: Replica, peer: SocketAddr) {
         if replica == self.id {
             return;
         }
@@ -191,12 +344,19 @@ impl Cluster {
         self.replicas.push(replica.clone());
         self.app.stats.cluster.replicas.increment();
 
-        let peer = Peer::spawn(replica.peer_addr());
+        let peer = Peer::spawn(peer);
 
         self.peers.insert(replica, peer);
         // TODO: sync?
     }
 
+    /// Asks all Peers to drop a clone of `tx` once their pending messages are written.
+    pub fn flush(&self, tx: Sender<()>) {
+        for (_addr, peer) in &self.peers {
+            peer.flush(tx.clone());
+        }
+    }
+
     /// Replicates data to all replicas.
     pub fn replicate(&self, path: Path, data: NodeTree) {
         self.app.stats.cluster.replicate.increment();
@@ -210,6 +370,27 @@ impl Cluster {
         }
     }
 
+    /// Replicates data to all replicas, notifying `tx` once `acks` replicas received it.
+    pub fn replicate_acked(&mut self, path: Path, data: NodeTree, acks: usize, tx: mioco::sync::mpsc::Sender<()>) {
+        self.app.stats.cluster.replicate.increment();
+
+        if self.peers.len() < acks {
+            return; // can never be acknowledged, dropping `tx` fails the call
+        }
+
+        let id = self.next_ack;
+
+        self.next_ack += 1;
+
+        let message = Arc::new(ClusterMessage::MergeAcked(self.id.clone(), id, path, data));
+
+        for (_addr, peer) in &self.peers {
+            peer.send(message.clone());
+        }
+
+        self.acks.insert(id, PendingAck { remaining: acks, sent: self.app.clock.now(), tx: tx });
+    }
+
     /// Synchronize each Zone to all Peers.
     pub fn sync(&self) {
         self.app.store.each_zone(|path| {
@@ -273,7 +454,12 @@ impl Peer {
 
     /// Sends a message to this remote Peer
     pub fn send(&self, msg: Arc<ClusterMessage>) {
-        self.tx.send(msg).expect("Peer channel disconnected");
+        self.tx.send(PeerCall::Send(msg)).expect("Peer channel disconnected");
+    }
+
+    /// Drops `tx` once messages sent so far are written.
+    pub fn flush(&self, tx: Sender<()>) {
+        self.tx.send(PeerCall::Flush(tx)).expect("Peer channel disconnected");
     }
 }
 
@@ -297,7 +483,11 @@ impl PeerState {
                 Some(m) => m,
                 None => {
                     match self.rx.recv() {
-                        Ok(m) => m,
+                        Ok(PeerCall::Send(m)) => m,
+                        Ok(PeerCall::Flush(tx)) => {
+                            drop(tx); // all earlier messages are written
+                            continue;
+                        },
                         Err(_) => return
                     }
                 }
@@ -393,9 +583,87 @@ fn test_cluster() {
     let mut app = app::App::new(id);
     let mut cluster = Cluster::new(&mut app);
 
-    cluster.add("127.0.0.1:1000".parse().unwrap());
-    cluster.add("127.0.0.1:1001".parse().unwrap());
-    cluster.add("127.0.0.1:1002".parse().unwrap());
+    for replica in ["127.0.0.1:1000", "127.0.0.1:1001", "127.0.0.1:1002"].iter() {
+        let replica: Replica = replica.parse().unwrap();
+        let peer = replica.peer_addr();
+
+        cluster.add(replica, peer);
+    }
 
     assert_eq!(cluster.replicas, replicas);
 }
+
+#[test]
+fn test_heartbeat() {
+    use app;
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let mut app = app::App::new(id);
+    let mut cluster = Cluster::new(&mut app);
+
+    app.clock.set_tombstone_horizon(0);
+
+    // Single Replica is stable up to its oldest unreplicated change
+    let pending = app.clock.issue();
+    let timestamp = pending.timestamp();
+
+    cluster.heartbeat(app.clock.replicated());
+    assert_eq!(app.clock.tombstone_horizon(), timestamp);
+
+    drop(pending);
+    cluster.heartbeat(app.clock.replicated());
+    assert!(app.clock.tombstone_horizon() > timestamp);
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let mut app = app::App::new(id);
+    let mut cluster = Cluster::new(&mut app);
+
+    app.clock.set_tombstone_horizon(0);
+
+    // Track Replica without connecting to it
+    let peer: Replica = "127.0.0.1:1001".parse().unwrap();
+    cluster.replicas.push(peer.clone());
+
+    // Not stable until the peer reports what it has received
+    cluster.handle_cluster_message(ClusterMessage::Heartbeat(peer.clone(), 2000, 0));
+    cluster.heartbeat(app.clock.replicated());
+    assert_eq!(app.clock.tombstone_horizon(), 0);
+
+    cluster.handle_cluster_message(ClusterMessage::Heartbeat(peer.clone(), 3000, 1000));
+    cluster.heartbeat(app.clock.replicated());
+    assert_eq!(app.clock.tombstone_horizon(), 1000);
+}
+
+#[test]
+fn test_ack() {
+    use app;
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let mut app = app::App::new(id);
+    let mut cluster = Cluster::new(&mut app);
+
+    // Not enough Replicas to ever acknowledge
+    let (tx, rx) = mioco::sync::mpsc::channel();
+
+    cluster.replicate_acked(Path::empty(), Default::default(), 1, tx);
+    assert!(rx.recv().is_err());
+
+    let (tx, rx) = mioco::sync::mpsc::channel();
+
+    cluster.acks.insert(1, PendingAck { remaining: 2, sent: app.clock.now(), tx: tx });
+
+    cluster.handle_cluster_message(ClusterMessage::Ack(1));
+    assert_eq!(cluster.acks.len(), 1);
+
+    cluster.handle_cluster_message(ClusterMessage::Ack(1));
+    assert!(cluster.acks.is_empty());
+    assert!(rx.recv().is_ok());
+
+    // Pending for too long
+    let (tx, rx) = mioco::sync::mpsc::channel();
+
+    cluster.acks.insert(2, PendingAck { remaining: 1, sent: 0, tx: tx });
+
+    cluster.heartbeat(app.clock.replicated());
+    assert!(rx.recv().is_err());
+}
@@ -2,9 +2,8 @@
 
 use serde_json;
 use serde_json::Value;
-use time;
 
-use path::Path;
+use path::{is_bucket, Path};
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Command {
@@ -12,23 +11,29 @@ pub struct Command {
     pub call: Call,
     pub path: Path,
     pub params: Value,
-    pub timestamp: u64
+    pub timestamp: u64,
+    pub durable: bool, // Reply only once changes are persisted
+    pub acks: usize    // Reply only once this many peers received changes
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Call {
     Bind,
+    Cas,
+    Incr,
     Kill,
     Read,
+    Unbind,
     Write
 }
 
 impl Command {
-    pub fn from_json(json: &str) -> Result<Command, String> {
+    /// Parses a command, timestamping it with `timestamp`.
+    pub fn from_json(json: &str, timestamp: u64) -> Result<Command, String> {
         let data: Value = try!(serde_json::from_str(json).or(Err("Bad JSON")));
         let data = try!(data.as_array().ok_or("Not array"));
 
-        if data.len() != 4 {
+        if data.len() != 4 && data.len() != 5 {
             return Err("Wrong number of elements".to_string());
         }
 
@@ -39,57 +44,167 @@ impl Command {
         let mut path_string: Vec<String> = vec![];
 
         for p in path.iter() {
-            path_string.push(try!(p.as_str().ok_or("Bad path")).to_string());
+            let p = try!(p.as_str().ok_or("Bad path"));
+
+            // Bucket keys are reserved for splitting wide nodes
+            if is_bucket(p) {
+                return Err("Bad path".to_string());
+            }
+
+            path_string.push(p.to_string());
         }
 
         let params = data[3].clone();
 
+        let (durable, acks) = match data.get(4) {
+            Some(options) => try!(parse_options(options)),
+            None => (false, 0)
+        };
+
         let call = match call {
             "bind" => Call::Bind,
+            "cas" => Call::Cas,
+            "incr" => Call::Incr,
             "kill" => Call::Kill,
             "read" => Call::Read,
+            "unbind" => Call::Unbind,
             "write" => Call::Write,
             _ => return Err("Bad call".to_string())
         };
 
+        // Conditional writes / increments apply to exactly one node
+        if (call == Call::Cas || call == Call::Incr) && path_string.iter().any(|p| p.starts_with("*")) {
+            return Err("Bad path".to_string());
+        }
+
+        if (call == Call::Write || call == Call::Cas) && has_bucket_key(&params) {
+            return Err("Bad key".to_string());
+        }
+
+        // Only scalar values are compared, maps would match any map
+        if call == Call::Cas && params.get("expect").map_or(false, |e| e.is_object() || e.is_array()) {
+            return Err("Bad expect".to_string());
+        }
+
+        if call == Call::Incr && ! params.is_i64() {
+            return Err("Bad increment".to_string());
+        }
+
         Ok(Command {
             id: id,
             call: call,
             path: Path { path: path_string },
             params: params,
-            timestamp: time::precise_time_ns()
+            timestamp: timestamp,
+            durable: durable,
+            acks: acks
         })
     }
 
+    /// Returns true if the call must be decided by the `Zone` owning the exact path.
+    ///
+    /// Right now, only `Call::Cas` and `Call::Incr` fall into this category
+    pub fn exact(&self) -> bool {
+        match self.call {
+            Call::Cas | Call::Incr => true,
+            _ => false
+        }
+    }
+
     /// Returns true if delegated data requires separate calls.
     ///
-    /// Right now, only `Call::Bind` and Call::Read` fall into this category
+    /// Right now, only `Call::Bind`, `Call::Read` and `Call::Unbind` fall into this category
     pub fn recursive(&self) -> bool {
         match self.call {
-            Call::Bind | Call::Read => true,
+            Call::Bind | Call::Read | Call::Unbind => true,
             _ => false
         }
     }
 }
 
+/// Parses command options, e.g. `{ "durable": true, "acks": 2 }`. Acks imply durability.
+fn parse_options(options: &Value) -> Result<(bool, usize), String> {
+    let options = try!(options.as_object().ok_or("Bad options"));
+
+    let durable = match options.get("durable") {
+        Some(durable) => try!(durable.as_bool().ok_or("Bad durable")),
+        None => false
+    };
+
+    let acks = match options.get("acks") {
+        Some(acks) => try!(acks.as_u64().ok_or("Bad acks")) as usize,
+        None => 0
+    };
+
+    if acks > 0 && ! durable {
+        return Err("Bad acks".to_string());
+    }
+
+    Ok((durable, acks))
+}
+
+/// Returns true if any object in `value` uses a key reserved for buckets.
+fn has_bucket_key(value: &Value) -> bool {
+    match *value {
+        Value::Object(ref map) => map.iter().any(|(k, v)| is_bucket(k) || has_bucket_key(v)),
+        _ => false
+    }
+}
+
 #[test]
 fn test_from_json() {
-    let result = Command::from_json("[ 42, [], 42 ]");
+    let result = Command::from_json("[ 42, [], 42 ]", 1000);
     assert!(result.is_err());
 
-    let result = Command::from_json(r#"[ 1, "write", [], 42 ]"#).unwrap();
+    let result = Command::from_json(r#"[ 1, "write", [], 42 ]"#, 1000).unwrap();
     assert_eq!(result.call, Call::Write);
 
-    let result = Command::from_json(r#"[ 1, "moo", [], 42 ]"#);
+    let result = Command::from_json(r#"[ 1, "moo", [], 42 ]"#, 1000);
     assert!(result.is_err());
 
-    let result = Command::from_json(r#"[ 1, "bind", [ 42 ], 42 ]"#);
+    let result = Command::from_json(r#"[ 1, "bind", [ 42 ], 42 ]"#, 1000);
     assert!(result.is_err());
 
-    let result = Command::from_json(r#"[ 1, "bind", [ "moo" ], 42 ]"#).unwrap();
+    let result = Command::from_json(r#"[ 1, "bind", [ "moo" ], 42 ]"#, 1000).unwrap();
     assert_eq!(result.call, Call::Bind);
     assert_eq!(result.path, Path::new(vec!["moo".to_string()]));
 
-    let result = Command::from_json(r#"[ 1, "bind", [ "moo", 42 ], 42 ]"#);
+    let result = Command::from_json(r#"[ 1, "bind", [ "moo", 42 ], 42 ]"#, 1000);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "cas", [ "moo" ], { "value": 42 } ]"#, 1000).unwrap();
+    assert_eq!(result.call, Call::Cas);
+
+    let result = Command::from_json(r#"[ 1, "cas", [ "moo", "*" ], { "value": 42 } ]"#, 1000);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "cas", [ "moo" ], { "expect": { "cow": 1 }, "value": 42 } ]"#, 1000);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "incr", [ "moo" ], -1 ]"#, 1000).unwrap();
+    assert_eq!(result.call, Call::Incr);
+    assert!(result.exact());
+
+    let result = Command::from_json(r#"[ 1, "incr", [ "moo" ], 0.5 ]"#, 1000);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 2, "unbind", [ "moo" ], 1 ]"#, 1000).unwrap();
+    assert_eq!(result.call, Call::Unbind);
+    assert!(result.recursive());
+
+    let result = Command::from_json(r#"[ 1, "read", [ "moo", "*0-1" ], null ]"#, 1000);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "write", [ "moo" ], { "cow": { "*0-1": 1 } } ]"#, 1000);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "write", [ "moo" ], 42, { "durable": true, "acks": 2 } ]"#, 1000).unwrap();
+    assert!(result.durable);
+    assert_eq!(result.acks, 2);
+
+    let result = Command::from_json(r#"[ 1, "write", [ "moo" ], 42, { "acks": 2 } ]"#, 1000);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "write", [ "moo" ], 42, true ]"#, 1000);
     assert!(result.is_err());
 }
@@ -0,0 +1,386 @@
+//! Node configuration
+//!
+//! Settings are read from a JSON file given with `--config`, then overridden by environment
+//! variables, then by command line options. Each setting has the same name in all three, with
+//! dashes instead of underscores on the command line, e.g. `"data_dir": "/var/lib/qumulus"` in the
+//! file or `--data-dir /var/lib/qumulus`. The environment variables are those nodes were
+//! configured with before, e.g. `CLUSTER`.
+//!
+//! Settings are checked as they are read, and the whole configuration once read, so a node with a
+//! bad configuration does not start.
+//!
+//! Peers listen on their id's port + 100 unless configured otherwise. Entries of the `cluster` give
+//! other addresses as `<id>@<peer address>`, e.g. `10.0.0.2:8888@10.0.0.2:9999` for a node started
+//! with `--peer 10.0.0.2:9999`.
+
+use std::fmt::Display;
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use serde_json::{self, Value};
+
+use admin::AdminAddr;
+use app::App;
+use delegate;
+use manager::{self, EvictionPolicy, MemoryBudget};
+use replica::Replica;
+use store;
+
+/// Names of all settings
+pub const SETTINGS: &'static [&'static str] = &[
+    "id", "api", "peer", "monitor", "admin", "headless", "data_dir", "store", "store_threads",
+    "zone_threads", "cluster", "memory", "eviction", "delegation", "tombstone_horizon"
+];
+
+/// Environment variables and the settings they set
+const ENV: &'static [(&'static str, &'static str)] = &[
+    ("STORE", "store"),
+    ("CLUSTER", "cluster"),
+    ("MEMORY", "memory"),
+    ("EVICTION", "eviction"),
+    ("DELEGATION", "delegation"),
+    ("TOMBSTONE_HORIZON", "tombstone_horizon")
+];
+
+/// Configuration of a node
+#[derive(Debug)]
+pub struct Config {
+    pub id: Option<Replica>,         // Address peers know this node by
+    pub api: Option<SocketAddr>,     // Listening address for clients, defaults to `id`
+    pub peer: Option<SocketAddr>,    // Listening address for peers, defaults to `id` port + 100
+    pub monitor: Option<SocketAddr>, // Listening address for stats, defaults to `id` port + 200
+    pub admin: Option<AdminAddr>,    // Admin socket, serves shell commands
+    pub headless: bool,              // Don't read shell commands from stdin
+    pub data_dir: Option<String>,    // Defaults to `data_<id>`, or `log_<id>` for the log store
+    pub store: String,               // Store backend, `fs` or `log`
+    pub store_threads: usize,        // Threads each for store reads and writes
+    pub zone_threads: usize,         // Threads Zones run on
+    pub cluster: Vec<(Replica, SocketAddr)>, // Peers to replicate with, and their peer addresses
+    pub memory: MemoryBudget,
+    pub eviction: EvictionPolicy,
+    pub delegation: Option<String>,  // Delegation strategy spec, see `delegate::from_spec`
+    pub tombstone_horizon: Option<u64> // In ms
+}
+
+impl Config {
+    /// Reads the configuration from command line `args` (without the program name), the file given
+    /// with `--config` and `env` variables. A single argument without an option is the `id`.
+    pub fn load<I: IntoIterator<Item=(String, String)>>(args: &[String], env: I) -> Result<Config, String> {
+        let mut config: Config = Default::default();
+        let mut options = try!(parse_args(args));
+
+        if let Some(i) = options.iter().position(|&(ref key, _)| key == "config") {
+            let (_, file) = options.remove(i);
+
+            try!(config.read_file(&file));
+        }
+
+        for (var, value) in env {
+            if let Some(&(_, key)) = ENV.iter().find(|&&(name, _)| name == var) {
+                try!(config.set(key, &value).map_err(|err| format!("{} ({})", err, var)));
+            }
+        }
+
+        for (key, value) in options {
+            try!(config.set(&key, &value));
+        }
+
+        try!(config.validate());
+
+        Ok(config)
+    }
+
+    /// Reads settings from a JSON file holding an object. Lists are allowed for the `cluster`.
+    pub fn read_file(&mut self, file: &str) -> Result<(), String> {
+        let mut buffer = String::new();
+
+        try!(File::open(file).and_then(|mut f| f.read_to_string(&mut buffer))
+            .map_err(|err| format!("{}: {}", file, err)));
+
+        let settings = match serde_json::from_str(&buffer) {
+            Ok(Value::Object(settings)) => settings,
+            Ok(_) => return Err(format!("{}: expected an object", file)),
+            Err(err) => return Err(format!("{}: {}", file, err))
+        };
+
+        for (key, value) in settings {
+            let value = match value {
+                Value::String(s) => s,
+                Value::Number(n) => n.to_string(),
+                Value::Array(ref items) if items.iter().all(|item| item.is_string()) => {
+                    items.iter().map(|item| item.as_str().unwrap()).collect::<Vec<_>>().join(" ")
+                },
+                _ => return Err(format!("{}: {}: expected a string or number", file, key))
+            };
+
+            try!(self.set(&key, &value).map_err(|err| format!("{}: {}", file, err)));
+        }
+
+        Ok(())
+    }
+
+    /// Sets a setting from its string representation.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        fn parse<T: FromStr>(value: &str) -> Result<T, String> where T::Err: Display {
+            value.parse().map_err(|err| format!("{:?}: {}", value, err))
+        }
+
+        let result = match key {
+            "id" => parse(value).map(|id| self.id = Some(id)),
+            "api" => parse(value).map(|addr| self.api = Some(addr)),
+            "peer" => parse(value).map(|addr| self.peer = Some(addr)),
+            "monitor" => parse(value).map(|addr| self.monitor = Some(addr)),
+            "admin" => value.parse().map(|addr| self.admin = Some(addr)),
+            "headless" => parse(value).map(|headless| self.headless = headless),
+            "data_dir" => {
+                self.data_dir = Some(value.into());
+                Ok(())
+            },
+            "store" => {
+                self.store = value.into();
+                Ok(())
+            },
+            "store_threads" => parse(value).map(|n| self.store_threads = n),
+            "zone_threads" => parse(value).map(|n| self.zone_threads = n),
+            "cluster" => {
+                value.split(|c| c == ' ' || c == ',')
+                    .filter(|r| ! r.is_empty())
+                    .map(parse_member)
+                    .collect::<Result<_, _>>()
+                    .map(|cluster| self.cluster = cluster)
+            },
+            "memory" => value.parse().map(|memory| self.memory = memory),
+            "eviction" => value.parse().map(|eviction| self.eviction = eviction),
+            "delegation" => delegate::from_spec(value).map(|_| self.delegation = Some(value.into())),
+            "tombstone_horizon" => parse(value).map(|ms| self.tombstone_horizon = Some(ms)),
+            _ => Err("unknown setting".into())
+        };
+
+        result.map_err(|err| format!("{}: {}", key, err))
+    }
+
+    /// Checks settings that depend on each other, and that required ones are set.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = vec![];
+
+        if self.id.is_none() {
+            errors.push("id: missing, must be the IP:port this node is known to peers by".to_string());
+        }
+
+        if self.store != "fs" && self.store != "log" {
+            errors.push(format!("store: unknown backend {:?}, must be fs or log", self.store));
+        }
+
+        if self.store_threads == 0 {
+            errors.push("store_threads: must be positive".to_string());
+        }
+
+        if self.zone_threads == 0 {
+            errors.push("zone_threads: must be positive".to_string());
+        }
+
+        if let Some(ref id) = self.id {
+            let (api, peer, monitor) = (self.api_addr(), self.peer_addr(), self.monitor_addr());
+
+            if api == peer || api == monitor || peer == monitor {
+                errors.push(format!("api, peer and monitor addresses must differ: {}, {}, {}", api, peer, monitor));
+            }
+
+            if let Some(AdminAddr::Tcp(admin)) = self.admin {
+                if ! admin.ip().is_loopback() {
+                    errors.push(format!("admin: {} is not a loopback address, admin connections are not authenticated", admin));
+                }
+
+                if admin == api || admin == peer || admin == monitor {
+                    errors.push(format!("admin: {} is already used", admin));
+                }
+            }
+
+            if self.cluster.iter().any(|&(ref replica, _)| replica == id) {
+                errors.push(format!("cluster: includes this node's own id {}", id));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// Sets up `app` according to this configuration.
+    pub fn apply(&self, app: &mut App) {
+        if let Some(ref spec) = self.delegation {
+            app.delegation = delegate::from_spec(spec).unwrap(); // checked when set
+        }
+
+        if let Some(horizon) = self.tombstone_horizon {
+            app.clock.set_tombstone_horizon(horizon);
+        }
+
+        app.eviction = self.eviction;
+        app.memory = self.memory;
+        app.store_threads = self.store_threads;
+        app.zone_threads = self.zone_threads;
+    }
+
+    /// Returns the id of this node. Panics if the configuration was not validated.
+    pub fn id(&self) -> Replica {
+        self.id.clone().expect("Missing id")
+    }
+
+    pub fn api_addr(&self) -> SocketAddr {
+        self.api.unwrap_or_else(|| self.id().api_addr())
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer.unwrap_or_else(|| self.id().peer_addr())
+    }
+
+    pub fn monitor_addr(&self) -> SocketAddr {
+        self.monitor.unwrap_or_else(|| self.id().monitor_addr())
+    }
+
+    pub fn data_dir(&self) -> String {
+        match self.data_dir {
+            Some(ref dir) => dir.clone(),
+            None => format!("{}_{}", if self.store == "log" { "log" } else { "data" }, self.id())
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            id: None,
+            api: None,
+            peer: None,
+            monitor: None,
+            admin: None,
+            headless: false,
+            data_dir: None,
+            store: "fs".into(),
+            store_threads: store::NUM_THREADS,
+            zone_threads: manager::NUM_THREADS,
+            cluster: vec![],
+            memory: Default::default(),
+            eviction: Default::default(),
+            delegation: None,
+            tombstone_horizon: None
+        }
+    }
+}
+
+/// Parses a `cluster` entry, `<id>` or `<id>@<peer address>`.
+fn parse_member(value: &str) -> Result<(Replica, SocketAddr), String> {
+    let mut parts = value.splitn(2, '@');
+    let id = parts.next().unwrap();
+
+    let replica: Replica = try!(id.parse().map_err(|err| format!("{:?}: {}", id, err)));
+
+    let peer = match parts.next() {
+        Some(addr) => try!(addr.parse().map_err(|err| format!("{:?}: {}", addr, err))),
+        None => replica.peer_addr()
+    };
+
+    Ok((replica, peer))
+}
+
+/// Splits `--key value` and `--key=value` options into settings, with dashes in keys replaced by
+/// underscores. A single argument without an option is the `id`.
+fn parse_args(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut options = vec![];
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if ! arg.starts_with("--") {
+            if options.iter().any(|&(ref key, _)| key == "id") {
+                return Err(format!("Unexpected argument: {}", arg));
+            }
+
+            options.push(("id".to_string(), arg.clone()));
+            continue;
+        }
+
+        let mut parts = arg[2..].splitn(2, '=');
+        let key = parts.next().unwrap().replace('-', "_");
+
+        let value = match parts.next() {
+            Some(value) => value.to_string(),
+            None => try!(args.next().ok_or(format!("Missing value for {}", arg))).clone()
+        };
+
+        options.push((key, value));
+    }
+
+    Ok(options)
+}
+
+#[test]
+fn test_config() {
+    use std;
+
+    let dir = "test_data/config";
+    let file = format!("{}/qumulus.json", dir);
+
+    std::fs::create_dir_all(dir).unwrap();
+
+    File::create(&file).unwrap().write_all(br#"{
+        "id": "127.0.0.1:1000",
+        "cluster": ["127.0.0.1:2000", "127.0.0.1:3000@127.0.0.1:3999"],
+        "memory": "1M,2M",
+        "store_threads": 4
+    }"#).unwrap();
+
+    let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    let env = vec![("EVICTION".to_string(), "lfu".to_string()), ("HOME".to_string(), "/moo".to_string())];
+
+    // Options override the environment, which overrides the file
+    let config = Config::load(&args(&["--config", &file, "--store-threads", "8", "--data-dir=moo"]), env).unwrap();
+
+    assert_eq!(config.id(), "127.0.0.1:1000".parse().unwrap());
+    assert_eq!(config.cluster, [
+        ("127.0.0.1:2000".parse().unwrap(), "127.0.0.1:2100".parse().unwrap()),
+        ("127.0.0.1:3000".parse().unwrap(), "127.0.0.1:3999".parse().unwrap())
+    ]);
+    assert_eq!(config.memory, MemoryBudget { soft: 1 << 20, hard: 2 << 20 });
+    assert_eq!(config.eviction, EvictionPolicy::Lfu);
+    assert_eq!(config.store_threads, 8);
+    assert_eq!(config.zone_threads, manager::NUM_THREADS);
+    assert_eq!(config.data_dir(), "moo");
+    assert_eq!(config.peer_addr(), "127.0.0.1:1100".parse().unwrap());
+
+    // The id can be given on its own
+    let config = Config::load(&args(&["127.0.0.1:1000", "--monitor", "0.0.0.0:8080"]), vec![]).unwrap();
+
+    assert_eq!(config.monitor_addr(), "0.0.0.0:8080".parse().unwrap());
+    assert_eq!(config.data_dir(), "data_127.0.0.1:1000");
+    assert!(! config.headless);
+
+    let config = Config::load(&args(&["127.0.0.1:1000", "--headless", "true", "--admin", "unix:admin.sock"]), vec![]).unwrap();
+
+    assert!(config.headless);
+    assert_eq!(config.admin, Some(AdminAddr::Unix("admin.sock".into())));
+
+    // Bad settings are rejected
+    let error = |a: &[&str]| Config::load(&args(a), vec![]).unwrap_err();
+
+    assert!(error(&[]).starts_with("id: "));
+    assert!(error(&["127.0.0.1:1000", "--moo", "1"]).starts_with("moo: "));
+    assert!(error(&["127.0.0.1:1000", "--zone-threads"]).starts_with("Missing value"));
+    assert!(error(&["127.0.0.1:1000", "--zone-threads", "many"]).starts_with("zone_threads: "));
+    assert!(error(&["127.0.0.1:1000", "--zone-threads", "0"]).starts_with("zone_threads: "));
+    assert!(error(&["127.0.0.1:1000", "--memory", "2M,1M"]).starts_with("memory: "));
+    assert!(error(&["127.0.0.1:1000", "--delegation", "moo"]).starts_with("delegation: "));
+    assert!(error(&["127.0.0.1:1000", "--store", "moo"]).starts_with("store: "));
+    assert!(error(&["127.0.0.1:1000", "--api", "127.0.0.1:1100"]).starts_with("api, peer"));
+    assert!(error(&["127.0.0.1:1000", "--admin", "10.0.0.1:1300"]).starts_with("admin: "));
+    assert!(error(&["127.0.0.1:1000", "--admin", "127.0.0.1:1200"]).starts_with("admin: "));
+    assert!(error(&["127.0.0.1:1000", "--headless", "yes"]).starts_with("headless: "));
+    assert!(error(&["127.0.0.1:1000", "--cluster", "127.0.0.1:2000@moo"]).starts_with("cluster: "));
+    assert!(error(&["127.0.0.1:1000", "--cluster", "127.0.0.1:1000@127.0.0.1:2000"]).starts_with("cluster: "));
+    assert!(error(&["--config", "test_data/config/missing.json"]).starts_with("test_data/config/missing.json: "));
+}
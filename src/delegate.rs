@@ -1,60 +1,354 @@
 //! Contains functions to help measure size / population statistics of Nodes and help decide the
 //! appropriate points in the tree to partition as Zones.
+//!
+//! Partitioning is decided by a `DelegationStrategy`, selected at startup with `from_spec`.
 
-use std::collections::BinaryHeap;
-
-use time;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::sync::Arc;
 
+use listener::Listener;
 use node::Node;
+use path::{bucket_level, Path};
+
+/// Zones smaller than this (in bytes) are folded back into their parent by default
+const UNDELEGATE_SIZE: usize = 256;
 
-/// Possibly delegate
-pub fn delegate(node: &Node) -> Option<Node> {
-    // TODO: allow other strategies
+/// Nodes with more children than this are split into buckets
+const MAX_CHILDREN: usize = 10000;
 
-    let (_, delegate_node) = check_node(node);
-    delegate_node
+/// Decides which children of a `Zone` are delegated to their own `Zone`, and when a delegated
+/// `Zone` is folded back into its parent.
+pub trait DelegationStrategy: Send + Sync {
+    /// Possibly delegate children of `zone`, marking delegations with `timestamp`
+    fn delegate(&self, zone: &ZoneInfo, timestamp: u64) -> Option<Node>;
+
+    /// Returns true if delegated `zone` should be folded back into its parent.
+    fn undelegate(&self, zone: &ZoneInfo) -> bool {
+        zone.node.total_byte_size() < UNDELEGATE_SIZE
+    }
 }
 
-fn check_node(node: &Node) -> (usize, Option<Node>) {
-    let mut delegate_node: Node = Default::default();
-    let mut total_size = node.byte_size();
+/// What a `Zone` knows about itself when checking delegations
+pub struct ZoneInfo<'a> {
+    pub path: &'a Path,
+    pub node: &'a Node,
+    pub listeners: &'a [Listener],
+    pub writes: &'a BTreeMap<String, u64>, // Writes per child since last check
+    pub elapsed: u64                       // Time since last check (ms)
+}
+
+/// Delegates the largest children until the `Zone` fits, and children with large values of their
+/// own. This is the default strategy.
+pub struct SizeStrategy {
+    pub max_zone_size: usize,
+    pub min_child_size: usize,
+    pub max_value_size: usize
+}
+
+/// Delegates children written more often than `max_writes` per second
+pub struct WriteRateStrategy {
+    pub max_writes: u64
+}
+
+/// Delegates children with more than `max_listeners` listeners
+pub struct ListenerStrategy {
+    pub max_listeners: usize
+}
+
+/// Delegates paths matching any of `rules`, where `*` matches any key
+pub struct RuleStrategy {
+    pub rules: Vec<Path>
+}
 
-    if total_size > 32768 {
-        // TODO: delegate this Node if value stored here is e.g. > 32k
+/// Creates a `DelegationStrategy` from a spec:
+/// `size[:<max zone size>:<min child size>[:<max value size>]]`, `writes:<per second>`,
+/// `listeners:<count>` or `rules:<path>[,<path>...]`. Paths are dot separated.
+pub fn from_spec(spec: &str) -> Result<Arc<DelegationStrategy>, String> {
+    let mut parts = spec.splitn(2, ':');
+    let name = parts.next().unwrap_or_default();
+    let args = parts.next();
+
+    fn parse<T: ::std::str::FromStr>(arg: Option<&str>) -> Result<T, String> {
+        arg.and_then(|a| a.parse().ok()).ok_or(format!("Bad number: {:?}", arg))
     }
-    // TODO: handle if Node has many children, e.g. > 10000
-    else {
-        // recursively check if children need to be delegated
 
-        let mut largest_children = BinaryHeap::new();
+    match (name, args) {
+        ("size", None) => Ok(Arc::new(SizeStrategy::default())),
+        ("size", Some(args)) => {
+            let mut args = args.split(':');
 
-        node.each_child(|k, child_node| {
-            let (mut child_size, child_delegations) = check_node(child_node);
+            let max_zone_size = try!(parse(args.next()));
+            let min_child_size = try!(parse(args.next()));
+
+            let max_value_size = match args.next() {
+                Some(arg) => try!(parse(Some(arg))),
+                None => SizeStrategy::default().max_value_size
+            };
+
+            Ok(Arc::new(SizeStrategy {
+                max_zone_size: max_zone_size,
+                min_child_size: min_child_size,
+                max_value_size: max_value_size
+            }))
+        },
+        ("writes", args) => Ok(Arc::new(WriteRateStrategy { max_writes: try!(parse(args)) })),
+        ("listeners", args) => Ok(Arc::new(ListenerStrategy { max_listeners: try!(parse(args)) })),
+        ("rules", Some(args)) => {
+            let rules = args.split(',').map(|r| Path::new(r.split('.').map(|s| s.into()).collect())).collect();
+
+            Ok(Arc::new(RuleStrategy { rules: rules }))
+        },
+        _ => Err(format!("Unknown delegation strategy: {}", spec))
+    }
+}
 
-            if let Some(child_delegations) = child_delegations {
-                delegate_node.add_child(k.clone(), child_delegations);
+impl Default for SizeStrategy {
+    fn default() -> SizeStrategy {
+        SizeStrategy {
+            max_zone_size: 65535,
+            min_child_size: 1024,
+            max_value_size: 32768
+        }
+    }
+}
+
+impl DelegationStrategy for SizeStrategy {
+    fn delegate(&self, zone: &ZoneInfo, timestamp: u64) -> Option<Node> {
+        // A bucket Zone that is still too wide is split into buckets of the next level
+        let level = zone.path.path.last().and_then(|p| bucket_level(p)).map_or(0, |l| l + 1);
+
+        let (_, delegate_node) = self.check_node(zone.node, timestamp, level);
+        delegate_node
+    }
+}
+
+impl SizeStrategy {
+    fn check_node(&self, node: &Node, timestamp: u64, level: u64) -> (usize, Option<Node>) {
+        let mut delegate_node: Node = Default::default();
+        let mut total_size = node.byte_size();
+
+        if node.len() > MAX_CHILDREN && node.bucket_level().is_none() {
+            // Too many children to delegate one by one, so split them into buckets
+            delegate_node = Node::buckets(level, timestamp);
+        }
+        else {
+            // recursively check if children need to be delegated
+
+            let mut largest_children = BinaryHeap::new();
+
+            node.each_child(|k, child_node| {
+                // Large values get their own Zone, so writes to siblings don't rewrite them
+                if child_node.byte_size() > self.max_value_size {
+                    delegate_node.add_child(k.clone(), Node::delegate(timestamp));
+                    return;
+                }
+
+                let (mut child_size, child_delegations) = self.check_node(child_node, timestamp, 0);
+
+                if let Some(child_delegations) = child_delegations {
+                    delegate_node.add_child(k.clone(), child_delegations);
+                }
+
+                child_size += k.len();
+                total_size += child_size;
+
+                if child_size > self.min_child_size {
+                    largest_children.push( (child_size, k.clone()) );
+                }
+            });
+
+            while total_size > self.max_zone_size {
+                if let Some( (child_size, k) ) = largest_children.pop() {
+                    delegate_node.add_child(k.clone(), Node::delegate(timestamp));
+                    total_size -= child_size;
+                }
+                else {
+                    break;
+                }
             }
+        }
+
+        let delegate_node = if delegate_node.is_noop() { None } else { Some(delegate_node) };
 
-            child_size += k.len();
-            total_size += child_size;
+        (total_size, delegate_node)
+    }
+}
 
-            if child_size > 1024 {
-                largest_children.push( (child_size, k.clone()) );
+impl DelegationStrategy for WriteRateStrategy {
+    fn delegate(&self, zone: &ZoneInfo, timestamp: u64) -> Option<Node> {
+        let elapsed = if zone.elapsed > 0 { zone.elapsed } else { 1 };
+
+        delegate_children(zone.node, timestamp, |k| {
+            zone.writes.get(k).map_or(false, |w| w * 1000 / elapsed > self.max_writes)
+        })
+    }
+
+    fn undelegate(&self, zone: &ZoneInfo) -> bool {
+        let elapsed = if zone.elapsed > 0 { zone.elapsed } else { 1 };
+        let writes: u64 = zone.writes.values().sum();
+
+        // Hot Zones are usually small, so only fold once they have cooled down
+        zone.node.total_byte_size() < UNDELEGATE_SIZE && writes * 1000 / elapsed < self.max_writes / 2
+    }
+}
+
+impl DelegationStrategy for ListenerStrategy {
+    fn delegate(&self, zone: &ZoneInfo, timestamp: u64) -> Option<Node> {
+        let mut counts = BTreeMap::new();
+
+        for listener in zone.listeners {
+            if let Some(k) = listener.path.path.first() {
+                *counts.entry(k).or_insert(0) += 1;
             }
-        });
+        }
 
-        while total_size > 65535 {
-            if let Some( (child_size, k) ) = largest_children.pop() {
-                delegate_node.add_child(k.clone(), Node::delegate(time::precise_time_ns()));
-                total_size -= child_size;
+        delegate_children(zone.node, timestamp, |k| {
+            counts.get(k).map_or(false, |&c| c > self.max_listeners)
+        })
+    }
+
+    fn undelegate(&self, zone: &ZoneInfo) -> bool {
+        zone.node.total_byte_size() < UNDELEGATE_SIZE && zone.listeners.len() < self.max_listeners / 2
+    }
+}
+
+impl DelegationStrategy for RuleStrategy {
+    fn delegate(&self, zone: &ZoneInfo, timestamp: u64) -> Option<Node> {
+        let mut path = zone.path.clone();
+
+        self.check_node(zone.node, &mut path, timestamp)
+    }
+
+    fn undelegate(&self, zone: &ZoneInfo) -> bool {
+        ! self.matches(zone.path) && zone.node.total_byte_size() < UNDELEGATE_SIZE
+    }
+}
+
+impl RuleStrategy {
+    /// Delegates descendants of `node` at `path` matching a rule, walking down nodes on the way to
+    /// deeper matches.
+    fn check_node(&self, node: &Node, path: &mut Path, timestamp: u64) -> Option<Node> {
+        let mut delegate_node: Node = Default::default();
+
+        node.each_child(|k, child_node| {
+            if child_node.is_delegated() {
+                return;
+            }
+
+            path.push(k);
+
+            if self.matches(path) {
+                delegate_node.add_child(k.clone(), Node::delegate(timestamp));
             }
-            else {
-                break;
+            else if self.leads_to_match(path) {
+                if let Some(child_delegations) = self.check_node(child_node, path, timestamp) {
+                    delegate_node.add_child(k.clone(), child_delegations);
+                }
             }
-        }
+
+            path.pop();
+        });
+
+        if delegate_node.is_noop() { None } else { Some(delegate_node) }
+    }
+
+    /// Returns true if `path` should be a `Zone`.
+    fn matches(&self, path: &Path) -> bool {
+        let path = path.without_buckets();
+
+        self.rules.iter().any(|rule| rule.len() == path.len() && Self::prefix_matches(rule, &path))
+    }
+
+    /// Returns true if a rule matches descendants of `path`.
+    fn leads_to_match(&self, path: &Path) -> bool {
+        let path = path.without_buckets();
+
+        self.rules.iter().any(|rule| rule.len() > path.len() && Self::prefix_matches(rule, &path))
+    }
+
+    fn prefix_matches(rule: &Path, path: &Path) -> bool {
+        rule.path.iter().zip(&path.path).all(|(r, p)| r == "*" || r == p)
     }
+}
+
+/// Delegates all children that are not yet delegated and satisfy `f`.
+fn delegate_children<F>(node: &Node, timestamp: u64, mut f: F) -> Option<Node> where F: FnMut(&String) -> bool {
+    let mut delegate_node: Node = Default::default();
+
+    node.each_child(|k, child_node| {
+        if ! child_node.is_delegated() && f(k) {
+            delegate_node.add_child(k.clone(), Node::delegate(timestamp));
+        }
+    });
+
+    if delegate_node.is_noop() { None } else { Some(delegate_node) }
+}
+
+#[test]
+fn test_from_spec() {
+    assert!(from_spec("size").is_ok());
+    assert!(from_spec("size:1000:10").is_ok());
+    assert!(from_spec("size:1000:10:500").is_ok());
+    assert!(from_spec("size:1000").is_err());
+    assert!(from_spec("writes:100").is_ok());
+    assert!(from_spec("writes").is_err());
+    assert!(from_spec("listeners:50").is_ok());
+    assert!(from_spec("rules:users.*,logs").is_ok());
+    assert!(from_spec("moo").is_err());
+}
+
+#[test]
+fn test_rule_strategy() {
+    use serde_json;
+
+    let data = serde_json::from_str(r#"{ "users": { "moo": { "a": 1 }, "cow": { "b": 2 } }, "logs": 3 }"#).unwrap();
+    let node = Node::expand(data, 1000);
+    let writes = BTreeMap::new();
+
+    let strategy = RuleStrategy { rules: vec![Path::new(vec!["users".into(), "*".into()])] };
+
+    let root = Path::empty();
+    let info = ZoneInfo { path: &root, node: &node, listeners: &[], writes: &writes, elapsed: 0 };
+
+    // Nested matches are delegated without delegating `users` first
+    let mut users: Node = Default::default();
+    let mut expected: Node = Default::default();
+
+    users.add_child("cow".into(), Node::delegate(2000));
+    users.add_child("moo".into(), Node::delegate(2000));
+    expected.add_child("users".into(), users);
+
+    assert_eq!(strategy.delegate(&info, 2000), Some(expected));
+
+    let users = Path::new(vec!["users".into()]);
+    let node = Node::expand(serde_json::from_str(r#"{ "moo": { "a": 1 } }"#).unwrap(), 1000);
+    let info = ZoneInfo { path: &users, node: &node, listeners: &[], writes: &writes, elapsed: 0 };
+
+    assert_eq!(strategy.delegate(&info, 2001), Some(Node::delegate(2001).prepend_path(&["moo".to_string()])));
+
+    // Zones matching a rule are kept, however small
+    let moo = Path::new(vec!["users".into(), "moo".into()]);
+    let info = ZoneInfo { path: &moo, node: &node, listeners: &[], writes: &writes, elapsed: 0 };
+
+    assert!(! strategy.undelegate(&info));
+}
+
+#[test]
+fn test_size_strategy_large_value() {
+    use serde_json;
+    use serde_json::Value as JSON;
+
+    let mut data = serde_json::Map::new();
+
+    data.insert("big".into(), JSON::String("x".repeat(40000)));
+    data.insert("small".into(), JSON::from(1));
+
+    let node = Node::expand(JSON::Object(data), 1000);
+    let writes = BTreeMap::new();
+    let root = Path::empty();
+    let info = ZoneInfo { path: &root, node: &node, listeners: &[], writes: &writes, elapsed: 0 };
 
-    let delegate_node = if delegate_node.is_noop() { None } else { Some(delegate_node) };
+    let expected = Node::delegate(2000).prepend_path(&["big".to_string()]);
 
-    (total_size, delegate_node)
+    assert_eq!(SizeStrategy::default().delegate(&info, 2000), Some(expected));
 }
@@ -0,0 +1,35 @@
+//! A distributed hierarchical data distribution thingy
+
+#![recursion_limit="128"]
+
+extern crate bincode;
+extern crate libc;
+#[macro_use] extern crate log;
+extern crate mioco;
+extern crate rand;
+extern crate serde;
+extern crate serde_json;
+#[macro_use] extern crate serde_derive;
+extern crate threadpool;
+extern crate time;
+
+pub mod admin;
+pub mod app;
+pub mod client;
+pub mod clock;
+pub mod cluster;
+pub mod command;
+pub mod config;
+pub mod delegate;
+pub mod listener;
+pub mod manager;
+pub mod monitor;
+pub mod node;
+#[macro_use] pub mod path;
+pub mod replica;
+pub mod shell;
+pub mod signal;
+pub mod server;
+pub mod store;
+pub mod value;
+pub mod zone;
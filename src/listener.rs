@@ -8,7 +8,15 @@ use serde_json::value::Value;
 use node::Update;
 use path::Path;
 
+/// Identifies a bind by the client that made it and the id of the bind command
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct BindId {
+    pub client: u64,
+    pub id: u64
+}
+
 pub struct Listener {
+    pub bind: BindId,
     pub root: Arc<Path>,
     pub path: Arc<Path>,
     pub tx: Sender<String>
@@ -16,13 +24,24 @@ pub struct Listener {
 
 /// A Relative Listeer
 pub struct RListener {
+    pub bind: BindId,
     pub path: Path,
     pub tx: Sender<String>
 }
 
+impl BindId {
+    pub fn new(client: u64, id: u64) -> BindId {
+        BindId {
+            client: client,
+            id: id
+        }
+    }
+}
+
 impl Listener {
-    pub fn new(root: Arc<Path>, path: Arc<Path>, tx: Sender<String>) -> Listener {
+    pub fn new(bind: BindId, root: Arc<Path>, path: Arc<Path>, tx: Sender<String>) -> Listener {
         Listener {
+            bind: bind,
             root: root,
             path: path,
             tx: tx
@@ -31,7 +50,7 @@ impl Listener {
 
     pub fn update(&self, update: &Update) -> Result<(), SendError<String>> {
         let req_id: Value = 0.into();
-        let root = Value::Array(self.root.path.iter().map(|s| { Value::String(s.clone()) }).collect());
+        let root = self.root.without_buckets().to_json();
         let update = update.filter(&self.path.path[..]);
 
         if update == Value::Null {
@@ -47,21 +66,36 @@ impl Listener {
     /// Computes whether listener is retained and/or delegated
     pub fn delegate(&self, d_path: &Path) -> (bool, Option<RListener>) {
         let (retain, path) = self.path.delegate(d_path);
-        let d_listener = path.map(|p| RListener::new(p, &self.tx.clone()));
+        let d_listener = path.map(|p| RListener::new(self.bind, p, &self.tx.clone()));
 
         (retain, d_listener)
     }
+
+    /// Converts a listener of the delegated Zone at `d_path` to a listener relative to the parent
+    /// Zone, used when the delegated Zone is folded back into its parent
+    pub fn reclaim(&self, d_path: &Path) -> RListener {
+        RListener::new(self.bind, (*self.path).clone(), &self.tx).prepend_path(d_path)
+    }
 }
 
 impl RListener {
-    pub fn new(path: Path, tx: &Sender<String>) -> RListener {
+    pub fn new(bind: BindId, path: Path, tx: &Sender<String>) -> RListener {
         RListener {
+            bind: bind,
             path: path,
             tx: tx.clone()
         }
     }
 
+    pub fn prepend_path(mut self, path: &Path) -> RListener {
+        let mut prefixed = path.clone();
+
+        prefixed.append(&mut self.path);
+        self.path = prefixed;
+        self
+    }
+
     pub fn to_absolute(self, path: Arc<Path>) -> Listener {
-        Listener::new(path, Arc::new(self.path), self.tx)
+        Listener::new(self.bind, path, Arc::new(self.path), self.tx)
     }
 }
@@ -1,34 +1,9 @@
-//! A distributed hierarchical data distribution thingy
+//! Qumulus node
 
-#![recursion_limit="128"]
-
-extern crate bincode;
 extern crate env_logger;
-#[macro_use] extern crate log;
-extern crate mioco;
-extern crate rand;
-extern crate serde;
-extern crate serde_json;
-#[macro_use] extern crate serde_derive;
-extern crate threadpool;
-extern crate time;
-
-pub mod app;
-pub mod client;
-pub mod cluster;
-pub mod command;
-pub mod delegate;
-pub mod listener;
-pub mod manager;
-pub mod monitor;
-pub mod node;
-#[macro_use] pub mod path;
-pub mod replica;
-pub mod shell;
-pub mod server;
-pub mod store;
-pub mod value;
-pub mod zone;
+extern crate qumulus;
+
+use qumulus::{admin, app, cluster, config, manager, monitor, path, server, shell, signal, store};
 
 fn main() {
     env_logger::init().unwrap();
@@ -37,52 +12,94 @@ fn main() {
 
     let args: Vec<_> = std::env::args().collect();
 
-    if args.len() != 2 {
-        println!("Usage: {} <ID>", &args[0]);
-        println!("Missing ID. ID must be provided as an IP:port string.");
-        println!("This is used as the listening address as well as the data directory.");
-
-        return;
-    }
+    let config = match config::Config::load(&args[1..], std::env::vars()) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Usage: {} [--config <file>] [--<setting> <value>...] [<ID>]", &args[0]);
+            println!("ID must be provided as an IP:port string, as a setting or on its own.");
+            println!("Settings: {}", config::SETTINGS.join(", "));
+            println!();
+            println!("Invalid configuration:");
+
+            for line in err.lines() {
+                println!("  {}", line);
+            }
+
+            std::process::exit(2);
+        }
+    };
 
-    let id_str = &args[1];
-    let id: replica::Replica = id_str.parse().unwrap();
+    let id = config.id();
 
     println!("  ID / address: {:?}", &id);
 
     let mut app = app::App::new(id.clone());
 
-    store::fs::FS::spawn(&mut app);
+    config.apply(&mut app);
+
+    if let Some(horizon) = config.tombstone_horizon {
+        println!("  Tombstone horizon: {}ms", horizon);
+    }
+
+    if let Some(ref spec) = config.delegation {
+        println!("  Delegation: {}", spec);
+    }
+
+    println!("  Eviction: {:?}", config.eviction);
+    println!("  Memory: {} / {} bytes", config.memory.soft, config.memory.hard);
+    println!("  Threads: {} zone, {} store", config.zone_threads, config.store_threads);
+
+    let data_dir = config.data_dir();
+
+    match &config.store[..] {
+        "fs" => store::spawn(&mut app, store::fs::FS::new(&data_dir)),
+        "log" => match store::log::Log::new(&data_dir) {
+            Ok(log) => store::spawn(&mut app, log),
+            Err(err) => {
+                println!("Could not open log store in {}: {}", data_dir, err);
+                std::process::exit(2);
+            }
+        },
+        _ => unreachable!() // checked by config
+    }
+
+    println!("  Store: {} in {}", config.store, data_dir);
     manager::Manager::spawn(&mut app);
-    cluster::Cluster::spawn(&mut app);
+    cluster::Cluster::spawn(&mut app, config.peer_addr());
 
     app.manager.load(&path::Path::empty());
 
     println!("Listening addresses:");
-    println!("  API: {}", id.api_addr());
-    println!("  Peer: {}", id.peer_addr());
-    println!("  Monitor: {}", id.monitor_addr());
+    println!("  API: {}", config.api_addr());
+    println!("  Peer: {}", config.peer_addr());
+    println!("  Monitor: {}", config.monitor_addr());
 
-    let server = server::Server::new(&app, id.api_addr());
+    let server = server::Server::new(&app, config.api_addr());
     server.listen();
 
-    let replicas: Vec<replica::Replica> = match std::env::var("CLUSTER") {
-        Ok(r) => r.split(' ').map(|r| r.parse().unwrap()).collect(),
-        Err(_) => vec![]
-    };
-
     println!("Adding replicas:");
 
-    for replica in replicas {
-        println!("  {:?}", &replica);
-        app.cluster.add(replica);
+    for &(ref replica, peer) in config.cluster.iter() {
+        println!("  {:?} at {}", replica, peer);
+        app.cluster.add(replica.clone(), peer);
     }
 
-    monitor::Monitor::spawn(&app);
+    monitor::Monitor::spawn(&app, config.monitor_addr());
 
-    let stdin = std::io::stdin();
+    if let Some(ref addr) = config.admin {
+        if let Err(err) = admin::spawn(&app, addr) {
+            println!("Could not listen on admin socket {}: {}", addr, err);
+            std::process::exit(2);
+        }
+    }
+
+    signal::watch(app.handle());
 
-    shell::start(app, stdin.lock(), std::io::stdout());
+    if ! config.headless {
+        let stdin = std::io::stdin();
+
+        shell::start(app, stdin.lock(), std::io::stdout());
+    }
 
     loop {
         std::thread::park();
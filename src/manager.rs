@@ -2,20 +2,41 @@
 
 use std::any::Any;
 use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 use mioco;
 use mioco::sync::mpsc::{channel, Receiver, Sender};
 use rand;
 
-use app::{App, AppHandle};
+use app::{App, AppHandle, Stats};
 use listener::RListener;
 use node::External;
-use path::Path;
+use path::{bucket, is_bucket, Path};
 use zone::{Zone, ZoneHandle};
 
-const MAX_LOADED_SOFT: usize = 600;
-const MAX_LOADED_HARD: usize = 800;
+/// Default number of threads Zones run on
+pub const NUM_THREADS: usize = 10;
+
+/// How often `EvictionManager` checks memory used by Zones growing
+const EVICTION_INTERVAL_MS: u64 = 1000;
+
+/// Limits on the estimated size of loaded Zone data (in bytes)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryBudget {
+    pub soft: usize, // Cold Zones are evicted above this
+    pub hard: usize  // Zones wait for memory to load above this
+}
+
+/// How `EvictionManager` picks Zones to hibernate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+    Random,
+    Lru, // Least recently used
+    Lfu  // Least frequently used, with ties broken by recency
+}
 
 /// A handle to the Manager process. This is the shareable public interface.
 #[derive(Clone)]
@@ -36,17 +57,23 @@ pub enum ManagerCall {
     Load(Path),
     ZoneLoaded(Path),
 
+    // Called by EvictionManager
+    SignalEvictionChecked(bool),
+
     // Called by Zones
     SignalDeferHibernation(ZoneHandle),
     SignalHibernated(ZoneHandle),
     SignalRequestLoad(ZoneHandle),
+    SignalRetired(ZoneHandle),
 }
 
 pub struct Manager {
     app: AppHandle,
     eviction: EvictionHandle,
+    budget: MemoryBudget,
     active: BTreeMap<Path, ZoneHandle>,
     loaded: usize,
+    evictable: bool, // Whether loaded Zones can be evicted to make room for waiting loads
     requesting_load: VecDeque<ZoneHandle>,
     rx: Receiver<(Option<Sender<Box<Any + Send>>>, ManagerCall)>
 }
@@ -64,8 +91,8 @@ impl ManagerHandle {
         self.call(ManagerCall::Load(path.clone()))
     }
 
-    /// Routes delegated data to the correct `Zone`
-    pub fn send_external(&self, prefix: &Path, external: External, replicate: bool) {
+    /// Routes delegated data to the correct `Zone`, returning its handle
+    pub fn send_external(&self, prefix: &Path, external: External, replicate: bool) -> ZoneHandle {
         let mut path = prefix.clone();
 
         // TODO: zone may be remote
@@ -78,6 +105,7 @@ impl ManagerHandle {
         let zone = self.load(&path);
 
         zone.merge(external.tree, replicate); // TODO flow control
+        zone
     }
 
     /// Routes delegated data to the correct `Zone` with a list of listeners.
@@ -121,6 +149,11 @@ impl ManagerHandle {
         self.call(ManagerCall::List)
     }
 
+    /// Called by EvictionManager after each periodic check.
+    pub fn eviction_checked(&self, evictable: bool) {
+        self.cast(ManagerCall::SignalEvictionChecked(evictable));
+    }
+
     /// Called by Zone to defer hibernation.
     pub fn zone_defer_hibernation(&self, zone: ZoneHandle) {
         self.cast(ManagerCall::SignalDeferHibernation(zone));
@@ -136,6 +169,11 @@ impl ManagerHandle {
         self.cast(ManagerCall::SignalRequestLoad(zone));
     }
 
+    /// Called by Zone to notify that its data was reclaimed by its parent.
+    pub fn zone_retired(&self, zone: ZoneHandle) {
+        self.cast(ManagerCall::SignalRetired(zone));
+    }
+
     /// Generic function to call a function on the underlying Manager through message passing.
     fn call<T: Any>(&self, call: ManagerCall) -> T {
         let (tx, rx) = channel();
@@ -169,23 +207,26 @@ impl ManagerChannel {
 impl Manager {
     pub fn spawn(app: &mut App) {
         let manager = Manager::new(app);
+        let threads = app.zone_threads;
 
         thread::spawn(move|| {
-            mioco::start_threads(10, move|| {
+            mioco::start_threads(threads, move|| {
                 manager.message_loop();
             }).unwrap();
         });
     }
 
     pub fn new(app: &mut App) -> Manager {
-        let eviction = EvictionManager::spawn();
+        let eviction = EvictionManager::spawn(app.eviction, app.memory, app.stats.clone(), app.manager.clone());
         let channel = app.channels.manager.take().expect("Receiver already taken");
 
         let manager = Manager {
             app: app.handle(),
             eviction: eviction,
+            budget: app.memory,
             active: BTreeMap::new(),
             loaded: 0,
+            evictable: true,
             requesting_load: VecDeque::new(),
             rx: channel.rx
         };
@@ -203,9 +244,11 @@ impl Manager {
                 ManagerCall::List => Box::new(self.list()),
                 ManagerCall::Load(path) => Box::new(self.load(&path)),
                 ManagerCall::ZoneLoaded(path) => Box::new(self.zone_loaded(&path)),
+                ManagerCall::SignalEvictionChecked(evictable) => Box::new(self.eviction_checked(evictable)),
                 ManagerCall::SignalDeferHibernation(zone) => Box::new(self.zone_defer_hibernation(zone)),
                 ManagerCall::SignalHibernated(zone) => Box::new(self.zone_hibernated(zone)),
                 ManagerCall::SignalRequestLoad(zone) => Box::new(self.zone_request_load(zone)),
+                ManagerCall::SignalRetired(zone) => Box::new(self.zone_retired(zone)),
             };
 
             if let Some(reply) = reply {
@@ -237,19 +280,38 @@ impl Manager {
         self.active.get(path).cloned()
     }
 
-    /// Find the 'closest' `Zone` that would be able to satisfy a call to `path`
+    /// Find the 'closest' `Zone` that would be able to satisfy a call to `path`. Keys of wide nodes
+    /// are found through the bucket `Zone`s holding them, so the returned path may contain buckets.
     pub fn find_nearest(&self, path: &Path) -> (Path, ZoneHandle) {
         // TODO: probably could be more efficient
         // TODO: use a bloom filter?
-        let mut probe = path.clone();
+        let mut probe = Path::empty();
+        let mut nearest = (probe.clone(), self.active[&probe].clone()); // crash if no root node
+
+        for part in &path.path {
+            if ! is_bucket(part) {
+                // Descend through (possibly nested) buckets holding `part`
+                for level in 0.. {
+                    probe.push(&bucket(part, level));
+
+                    match self.active.get(&probe) {
+                        Some(found) => nearest = (probe.clone(), found.clone()),
+                        None => {
+                            probe.pop();
+                            break;
+                        }
+                    }
+                }
+            }
+
+            probe.push(part);
 
-        loop {
             if let Some(found) = self.active.get(&probe) {
-                return (probe, found.clone())
+                nearest = (probe.clone(), found.clone());
             }
-
-            probe.pop(); // crash if no root node
         }
+
+        nearest
     }
 
     /// List all active zones
@@ -257,6 +319,16 @@ impl Manager {
         self.active.values().cloned().collect()
     }
 
+    /// Called by EvictionManager after each periodic check. Waiting loads go ahead once memory
+    /// dropped below the hard limit, or if no Zone can be evicted to make room.
+    pub fn eviction_checked(&mut self, evictable: bool) {
+        self.evictable = evictable;
+
+        while ! self.requesting_load.is_empty() && ! self.must_wait() {
+            self.load_requested();
+        }
+    }
+
     /// Called by Zone as a deferment response to hibernation signal.
     pub fn zone_defer_hibernation(&self, zone: ZoneHandle) {
         self.eviction.tx.send(EvictionCall::Deferred(zone)).unwrap();
@@ -268,15 +340,8 @@ impl Manager {
         self.loaded -= 1;
         self.app.stats.zones.local_loaded.decrement();
 
-        if let Some(zone) = self.requesting_load.pop_front() {
-            zone.load();
-            self.eviction.tx.send(EvictionCall::Loaded(zone)).unwrap();
-            self.loaded += 1;
-            self.app.stats.zones.local_loaded.increment();
-
-            if self.requesting_load.len() == 0 {
-                info!("Dropped below MAX_LOADED_HARD zones");
-            }
+        if ! self.requesting_load.is_empty() {
+            self.load_requested();
         }
     }
 
@@ -285,22 +350,53 @@ impl Manager {
         self.load_zone(zone);
     }
 
+    /// Called by Zone to notify that its data was reclaimed by its parent. The `Zone` is no longer
+    /// found by path and releases its loaded slot.
+    pub fn zone_retired(&mut self, zone: ZoneHandle) {
+        if self.active.remove(&zone.path()).is_some() {
+            self.app.stats.zones.local_active.decrement();
+        }
+
+        self.zone_hibernated(zone);
+    }
+
     fn load_zone(&mut self, zone: ZoneHandle) {
-        if self.loaded > MAX_LOADED_HARD {
+        if self.must_wait() {
             if self.requesting_load.len() == 0 {
-                info!("Exceeded MAX_LOADED_HARD zones");
+                info!("Exceeded hard memory limit");
             }
 
             self.requesting_load.push_back(zone);
 
         }
         else {
-            zone.load();
-            self.eviction.tx.send(EvictionCall::Loaded(zone)).unwrap();
-            self.loaded += 1;
-            self.app.stats.zones.local_loaded.increment();
+            self.load_now(zone);
+        }
+    }
+
+    /// Returns true if loads must wait for memory to be freed. Don't wait if nothing can be
+    /// evicted, the root Zone is exempted.
+    fn must_wait(&self) -> bool {
+        self.evictable && self.loaded > 1 && self.app.stats.zones.local_bytes.value() > self.budget.hard
+    }
+
+    /// Loads the Zone waiting longest for memory.
+    fn load_requested(&mut self) {
+        let zone = self.requesting_load.pop_front().unwrap();
+
+        self.load_now(zone);
+
+        if self.requesting_load.len() == 0 {
+            info!("Dropped below hard memory limit");
         }
     }
+
+    fn load_now(&mut self, zone: ZoneHandle) {
+        zone.load();
+        self.eviction.tx.send(EvictionCall::Loaded(zone)).unwrap();
+        self.loaded += 1;
+        self.app.stats.zones.local_loaded.increment();
+    }
 }
 
 #[derive(Clone)]
@@ -309,6 +405,9 @@ struct EvictionHandle {
 }
 
 struct EvictionManager {
+    policy: EvictionPolicy,
+    budget: MemoryBudget,
+    stats: Arc<Stats>,
     loaded: HashSet<ZoneHandle>,
     pending: HashSet<ZoneHandle>,
     rx: Receiver<EvictionCall>,
@@ -316,27 +415,40 @@ struct EvictionManager {
 }
 
 enum EvictionCall {
+    Check,
     Loaded(ZoneHandle),
     Unloaded(ZoneHandle),
     Deferred(ZoneHandle)
 }
 
 impl EvictionManager {
-    pub fn spawn() -> EvictionHandle {
-        let manager = EvictionManager::new();
+    pub fn spawn(policy: EvictionPolicy, budget: MemoryBudget, stats: Arc<Stats>, zones: ManagerHandle) -> EvictionHandle {
+        let manager = EvictionManager::new(policy, budget, stats);
         let handle = manager.handle();
 
         thread::spawn(move|| {
-            manager.message_loop();
+            manager.message_loop(zones);
+        });
+
+        // Loaded Zones grow as they are written to
+        let tx = handle.tx.clone();
+
+        thread::spawn(move|| {
+            while tx.send(EvictionCall::Check).is_ok() {
+                thread::sleep(Duration::from_millis(EVICTION_INTERVAL_MS));
+            }
         });
 
         handle
     }
 
-    pub fn new() -> EvictionManager {
+    pub fn new(policy: EvictionPolicy, budget: MemoryBudget, stats: Arc<Stats>) -> EvictionManager {
         let (tx, rx) = channel();
 
         EvictionManager {
+            policy: policy,
+            budget: budget,
+            stats: stats,
             loaded: HashSet::new(),
             pending: HashSet::new(),
             rx: rx,
@@ -349,11 +461,15 @@ impl EvictionManager {
         EvictionHandle { tx: self.tx.clone() }
     }
 
-    fn message_loop(mut self) {
+    /// Handles calls, reporting to `zones` after each periodic check whether Zones waiting to load
+    /// can still make room by evicting others.
+    fn message_loop(mut self, zones: ManagerHandle) {
         loop {
             let call = self.rx.recv().unwrap();
+            let check = match call { EvictionCall::Check => true, _ => false };
 
             match call {
+                EvictionCall::Check => {},
                 EvictionCall::Loaded(zone) => {
                     if zone.path().len() != 0 { // root node is exempted
                         self.loaded.insert(zone);
@@ -371,38 +487,124 @@ impl EvictionManager {
 
             // make a single pass
             self.evict();
+
+            if check {
+                zones.eviction_checked(self.evictable());
+            }
         }
     }
 
     fn evict(&mut self) {
-        let loaded = self.loaded.len();
-        let pending = self.pending.len();
-        let total = loaded + pending;
+        // Memory of Zones pending hibernation is about to be freed
+        let pending: usize = self.pending.iter().map(|zone| zone.loaded_bytes()).sum();
+        let mut used = self.stats.zones.local_bytes.value().saturating_sub(pending);
 
-        if total <= MAX_LOADED_SOFT {
+        if used <= self.budget.soft {
             return;
         }
 
-        if loaded == 0 {
-            println!("Nothing to evict");
-            return;
+        while used > self.budget.soft {
+            let zone = match self.coldest() {
+                Some(zone) => zone,
+                None => break // Nothing to evict
+            };
+
+            used = used.saturating_sub(zone.loaded_bytes());
+
+            zone.hibernate();
+            self.loaded.remove(&zone);
+            self.pending.insert(zone);
         }
+    }
 
-        let overflow = total - MAX_LOADED_SOFT;
-        let r = rand::random::<usize>() % (MAX_LOADED_HARD - MAX_LOADED_SOFT) / 2;
+    /// Returns true if any loaded Zone can be evicted.
+    fn evictable(&self) -> bool {
+        self.loaded.iter().any(|zone| zone.loaded_bytes() > 0)
+    }
 
-        if r > overflow {
-            return;
+    /// Picks the loaded Zone to evict next according to the policy. Zones still loading don't free
+    /// any memory, so are skipped.
+    fn coldest(&self) -> Option<ZoneHandle> {
+        let mut candidates = self.loaded.iter().filter(|zone| zone.loaded_bytes() > 0);
+
+        let zone = match self.policy {
+            EvictionPolicy::Random => {
+                let candidates: Vec<_> = candidates.collect();
+
+                if candidates.is_empty() {
+                    return None;
+                }
+
+                Some(candidates[rand::random::<usize>() % candidates.len()])
+            },
+            EvictionPolicy::Lru => candidates.min_by_key(|zone| zone.last_access()),
+            EvictionPolicy::Lfu => candidates.min_by_key(|zone| (zone.accesses(), zone.last_access()))
+        };
+
+        zone.cloned()
+    }
+}
+
+impl Default for MemoryBudget {
+    fn default() -> MemoryBudget {
+        MemoryBudget {
+            soft: 64 << 20,
+            hard: 96 << 20
         }
+    }
+}
+
+/// Parses `<soft>,<hard>`, with sizes in bytes or with a K, M or G suffix, e.g. `64M,96M`.
+impl FromStr for MemoryBudget {
+    type Err = String;
 
-        let i = rand::random::<u64>() % loaded as u64;
-        let zone = self.loaded.iter().nth(i as usize).unwrap().clone();
+    fn from_str(s: &str) -> Result<MemoryBudget, String> {
+        let limits = try!(s.split(',').map(parse_bytes).collect::<Result<Vec<_>, _>>());
 
-        zone.hibernate();
-        self.loaded.remove(&zone);
-        self.pending.insert(zone);
+        if limits.len() != 2 {
+            return Err(format!("Expected <soft>,<hard> limits: {}", s));
+        }
+
+        if limits[0] == 0 || limits[0] > limits[1] {
+            return Err(format!("Soft limit must be positive and at most the hard limit: {}", s));
+        }
 
-        // TODO improve this cache eviction algorithm
+        Ok(MemoryBudget { soft: limits[0], hard: limits[1] })
+    }
+}
+
+/// Parses a size in bytes, with an optional K, M or G suffix.
+fn parse_bytes(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+
+    let (digits, shift) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 30),
+        _ => (s, 0)
+    };
+
+    let n: usize = try!(digits.parse().map_err(|_| format!("Invalid size: {}", s)));
+
+    n.checked_mul(1 << shift).ok_or(format!("Size too large: {}", s))
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> EvictionPolicy {
+        EvictionPolicy::Lru
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EvictionPolicy, String> {
+        match s {
+            "random" => Ok(EvictionPolicy::Random),
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            _ => Err(format!("Unknown eviction policy: {}", s))
+        }
     }
 }
 
@@ -436,6 +638,77 @@ fn test_find_nearest() {
     assert_eq!(manager.find_nearest(&moo).0, moo);
     assert_eq!(manager.find_nearest(&moo_cow).0, moo_cow);
     assert_eq!(manager.find_nearest(&moo_cow_cow).0, moo_cow);
+
+    // Keys of a wide node are found through their bucket
+    let mut moo_bucket = moo.clone();
+    moo_bucket.push(&bucket("pig", 0));
+
+    let mut moo_pig = moo.clone();
+    moo_pig.push(&"pig".to_string());
+
+    manager.load(&moo_bucket);
+    assert_eq!(manager.find_nearest(&moo_pig).0, moo_bucket);
+    assert_eq!(manager.find_nearest(&moo_cow).0, moo_cow);
+
+    let mut moo_bucket_pig = moo_bucket.clone();
+    moo_bucket_pig.push(&"pig".to_string());
+
+    manager.load(&moo_bucket_pig);
+    assert_eq!(manager.find_nearest(&moo_pig).0, moo_bucket_pig);
+    assert_eq!(manager.find_nearest(&moo_bucket_pig).0, moo_bucket_pig);
+}
+
+#[test]
+fn test_eviction() {
+    let zones: Vec<_> = ["moo", "cow", "pig"].iter()
+        .map(|name| ZoneHandle::test_handle(Arc::new(Path::new(vec![name.to_string()]))))
+        .collect();
+
+    // moo is used most, but cow most recently
+    for _ in 0..3 {
+        zones[0].merge(Default::default(), false);
+    }
+
+    zones[2].merge(Default::default(), false);
+    zones[1].merge(Default::default(), false);
+
+    for zone in zones.iter() {
+        zone.set_loaded_bytes(100);
+    }
+
+    let coldest = |policy| {
+        let mut manager = EvictionManager::new(policy, Default::default(), Default::default());
+
+        manager.loaded.extend(zones.iter().cloned());
+        manager.coldest().unwrap()
+    };
+
+    assert!(coldest(EvictionPolicy::Lru) == zones[0]);
+    assert!(coldest(EvictionPolicy::Lfu) == zones[2]);
+    assert!(zones.contains(&coldest(EvictionPolicy::Random)));
+
+    assert_eq!("lfu".parse(), Ok(EvictionPolicy::Lfu));
+    assert!("moo".parse::<EvictionPolicy>().is_err());
+
+    // Coldest Zones are evicted down to the soft limit
+    let stats: Arc<Stats> = Default::default();
+    let mut manager = EvictionManager::new(EvictionPolicy::Lru, MemoryBudget { soft: 150, hard: 250 }, stats.clone());
+
+    stats.zones.local_bytes.set(300);
+    manager.loaded.extend(zones.iter().cloned());
+    manager.evict();
+    assert_eq!(manager.pending.len(), 2);
+    assert!(manager.loaded.contains(&zones[1]));
+
+    // Until they hibernate, memory of pending Zones is counted as freed
+    manager.evict();
+    assert_eq!(manager.loaded.len(), 1);
+
+    assert_eq!("64M,96M".parse(), Ok(MemoryBudget { soft: 64 << 20, hard: 96 << 20 }));
+    assert_eq!("512,1k".parse(), Ok(MemoryBudget { soft: 512, hard: 1024 }));
+    assert!("2K,1K".parse::<MemoryBudget>().is_err());
+    assert!("64M".parse::<MemoryBudget>().is_err());
+    assert!("moo,cow".parse::<MemoryBudget>().is_err());
 }
 
 #[test]
@@ -451,4 +724,19 @@ fn test_load() {
     let zone = app.manager.load(&root);
 
     assert!(zone.state().is_idle());
+
+    // Loads wait above the hard limit while Zones can be evicted
+    let mut app = app::App::new("127.0.0.1:1000".parse().unwrap());
+    let mut manager = Manager::new(&mut app);
+
+    manager.loaded = 2;
+    app.stats.zones.local_bytes.set(manager.budget.hard + 1);
+    manager.zone_request_load(ZoneHandle::test_handle(Arc::new(Path::new(vec!["moo".to_string()]))));
+
+    manager.eviction_checked(true);
+    assert_eq!(manager.requesting_load.len(), 1);
+
+    manager.eviction_checked(false);
+    assert_eq!(manager.requesting_load.len(), 0);
+    assert_eq!(manager.loaded, 3);
 }
@@ -5,11 +5,10 @@ use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::thread::Builder;
 
 use app::{App, AppHandle};
-use replica::Replica;
 
 pub struct Monitor {
     app: AppHandle,
-    id: Replica
+    addr: SocketAddr
 }
 
 pub struct Server {
@@ -18,16 +17,16 @@ pub struct Server {
 }
 
 impl Monitor {
-    pub fn new(app: &App) -> Monitor {
+    pub fn new(app: &App, addr: SocketAddr) -> Monitor {
         Monitor {
             app: app.handle(),
-            id: app.id.clone()
+            addr: addr
         }
     }
 
-    /// Start the Monitor "process".
-    pub fn spawn(app: &App) {
-        let mut monitor = Monitor::new(app);
+    /// Start the Monitor "process", listening on `addr`.
+    pub fn spawn(app: &App, addr: SocketAddr) {
+        let mut monitor = Monitor::new(app, addr);
 
         thread("Monitor").spawn(move || {
             monitor.run();
@@ -35,7 +34,7 @@ impl Monitor {
     }
 
     pub fn run(&mut self) {
-        let server = Server::new(&self.id.monitor_addr(), self.app.clone());
+        let server = Server::new(&self.addr, self.app.clone());
 
         server.accept_loop();
     }
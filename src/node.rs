@@ -6,16 +6,22 @@
 //! For each 'node' in the tree, two timestamps are tracked as meta information. These timestamps
 //! are used to for consistent conflict resolution.
 //!
-//! Deleted data leave meta information as tombstones which are occasionally cleared [TODO].
+//! Deleted data leave meta information as tombstones. Tombstones are cleared by `Node::gc` once
+//! every replica has seen the deletion, see `Clock::tombstone_horizon`.
+//!
+//! Children of very wide nodes are held in buckets (see `path::bucket`), each delegated to its own
+//! Zone. Buckets are transparent: reads, merges and updates pass through them by key.
 
 use std::collections::BTreeMap;
 use std::collections::btree_map::Entry;
+use std::cmp::{self, Ordering};
 use std::mem;
+use std::ops::Bound;
 
 use serde_json;
 use serde_json::Value as JSON;
 
-use path::Path;
+use path::{self, bucket, bucket_level, is_bucket, Path};
 use value::Value;
 
 /// Tracks visibility of a node
@@ -59,7 +65,11 @@ pub struct External {
     pub tree: NodeTree,
 
     /// True if this is a transition to delegated
-    pub initial: bool
+    pub initial: bool,
+
+    /// True if this is a transition back from delegated, data should be reclaimed from the
+    /// delegated Zone
+    pub reclaim: bool
 }
 
 #[derive(Debug, Default)]
@@ -135,6 +145,15 @@ impl Node {
         }
     }
 
+    /// Creates a `Node` with `value` set at given `timestamp`.
+    pub fn new(value: Value, timestamp: u64) -> Node {
+        Node {
+            vis: Vis::update(timestamp),
+            value: value,
+            ..Default::default()
+        }
+    }
+
     /// Expands JSON data to a `Node` representation creating each node at given `timestamp`.
     pub fn expand(data: JSON, timestamp: u64) -> Node {
         let vis = Vis::update(timestamp);
@@ -142,7 +161,7 @@ impl Node {
         match data {
             JSON::Null => Node { vis: vis, value: Value::Null, ..Default::default() },
             JSON::Bool(v) => Node { vis: vis, value: Value::Bool(v), ..Default::default() },
-            JSON::Number(v) => Node { vis: vis, value: Value::F64(v.as_f64().unwrap()), ..Default::default() },
+            JSON::Number(v) => Node { vis: vis, value: Value::from(v), ..Default::default() },
             JSON::String(s) => Node { vis: vis, value: Value::from(s), ..Default::default() },
             JSON::Object(obj) => {
                 let keys = obj.into_iter().map(|(k, v)|
@@ -203,6 +222,78 @@ impl Node {
         }
     }
 
+    /// Creates delegations that split children of a wide node into buckets at `level`. Buckets
+    /// are permanently visible, so they don't affect visibility of the children they hold.
+    pub fn buckets(level: u64, timestamp: u64) -> Node {
+        let mut node: Node = Default::default();
+
+        for i in 0..path::BUCKETS {
+            node.add_child(format!("*{}-{}", level, i), Node {
+                vis: Vis::permanent(),
+                delegated: timestamp | 1,
+                ..Default::default()
+            });
+        }
+
+        node
+    }
+
+    /// Returns true if this node is a bucket holding some children of a wide node.
+    pub fn is_bucket(&self) -> bool {
+        self.vis.updated == u64::max_value()
+    }
+
+    /// Returns the level of the buckets holding this node's children, if it is a wide node.
+    pub fn bucket_level(&self) -> Option<u64> {
+        self.keys.as_ref().and_then(|keys| {
+            // Bucket keys start with '*', which sorts before '+'
+            keys.range::<str, _>((Bound::Included("*"), Bound::Excluded("+"))).filter_map(|(k, _)| bucket_level(k)).next()
+        })
+    }
+
+    /// Moves children that are not buckets into their bucket at `level`. New buckets get `vis`.
+    fn rebucket(&mut self, level: u64, vis: Vis) {
+        if let Some(ref mut keys) = self.keys {
+            let moved: Vec<String> = keys.keys().filter(|k| ! is_bucket(k)).cloned().collect();
+
+            for k in moved {
+                let child = keys.remove(&k).unwrap();
+                let bucket_node = keys.entry(bucket(&k, level)).or_insert_with(|| Node { vis: vis, ..Default::default() });
+
+                bucket_node.add_child(k, child);
+            }
+        }
+    }
+
+    /// Returns true if this node's data is held by a delegated Zone.
+    pub fn is_delegated(&self) -> bool {
+        self.delegated & 1 == 1
+    }
+
+    /// Returns paths of delegated nodes, relative to this node. Delegated nodes hold no data here,
+    /// so their children are not visited.
+    pub fn delegations(&self) -> Vec<Path> {
+        fn visit(node: &Node, path: &mut Path, delegations: &mut Vec<Path>) {
+            node.each_child(|k, child_node| {
+                path.push(k);
+
+                if child_node.is_delegated() {
+                    delegations.push(path.clone());
+                }
+                else {
+                    visit(child_node, path, delegations);
+                }
+
+                path.pop();
+            });
+        }
+
+        let mut delegations = vec![];
+
+        visit(self, &mut Path::empty(), &mut delegations);
+        delegations
+    }
+
     /// Moves out all data that should be external and returns it.
     pub fn delegated(&mut self) -> Node {
         Node {
@@ -232,6 +323,11 @@ impl Node {
         *self == Default::default()
     }
 
+    /// Returns the value stored at this node.
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
     /// Returns number of child nodes.
     pub fn len(&self) -> usize {
         match self.keys {
@@ -249,12 +345,86 @@ impl Node {
         }
     }
 
+    /// Returns the newest timestamp in this node including children.
+    pub fn timestamp(&self) -> u64 {
+        // Buckets are marked as permanently visible, which is not a timestamp
+        let updated = if self.is_bucket() { 0 } else { self.vis.updated };
+        let mut timestamp = *[updated, self.vis.deleted, self.delegated].iter().max().unwrap();
+
+        self.each_child(|_, child_node| {
+            timestamp = timestamp.max(child_node.timestamp());
+        });
+
+        timestamp
+    }
+
+    /// Returns the number of nodes including children.
+    pub fn count(&self) -> usize {
+        let mut count = 1;
+
+        self.each_child(|_, child_node| {
+            count += child_node.count();
+        });
+
+        count
+    }
+
+    /// Clears tombstones. Removes children that can never be visible again, and whose deletions
+    /// are older than `horizon`. `deleted` is the effective deletion timestamp of this node.
+    ///
+    /// Returns the number of nodes removed.
+    pub fn gc(&mut self, deleted: u64, horizon: u64) -> usize {
+        let mut removed = 0;
+
+        if let Some(ref mut keys) = self.keys {
+            let mut dead = vec![];
+
+            for (k, child) in keys.iter_mut() {
+                let child_deleted = cmp::max(deleted, child.vis.deleted);
+
+                if child_deleted > 0 && child_deleted < horizon && child.is_dead(deleted, horizon) {
+                    dead.push(k.clone());
+                }
+                else {
+                    removed += child.gc(child_deleted, horizon);
+                }
+            }
+
+            for k in dead {
+                removed += keys.remove(&k).map_or(0, |child| child.count());
+            }
+        }
+
+        if self.len() == 0 {
+            self.keys = None;
+        }
+
+        removed
+    }
+
+    /// Returns true if this node and its children are hidden by deletions older than `horizon`,
+    /// and were last updated before those deletions, so that no newer update of an ancestor can
+    /// make them visible.
+    fn is_dead(&self, deleted: u64, horizon: u64) -> bool {
+        let deleted = cmp::max(deleted, self.vis.deleted);
+
+        if self.delegated > 0 || self.vis.deleted >= horizon || self.vis.updated > deleted {
+            return false;
+        }
+
+        match self.keys {
+            None => true,
+            Some(ref keys) => keys.values().all(|child| child.is_dead(deleted, horizon))
+        }
+    }
+
     /// Returns the estimated byte size of storing this node's value.
     pub fn byte_size(&self) -> usize {
         match self.value {
             Value::Bool(_) => 1,
             Value::I64(_) | Value::U64(_) | Value::F64(_) => 8,
             Value::String(ref s) => s.len(),
+            Value::Counter(ref c) => 8 + 16 * c.len(),
             Value::Null => 1
         }
     }
@@ -270,6 +440,27 @@ impl Node {
         total_size
     }
 
+    /// Returns the estimated byte size of `diff` that merging it into this node does not add, i.e.
+    /// keys already present, values it overwrites or deletes, and values it leaves in place.
+    pub fn overwritten_byte_size(&self, diff: &Node) -> usize {
+        let mut size = if cmp::max(diff.vis.updated, diff.vis.deleted) > self.vis.updated {
+            self.byte_size()
+        }
+        else {
+            diff.byte_size()
+        };
+
+        if let (Some(ref keys), Some(ref diff_keys)) = (self.keys.as_ref(), diff.keys.as_ref()) {
+            for (k, diff_child) in diff_keys.iter() {
+                if let Some(child) = keys.get(k) {
+                    size += k.len() + child.overwritten_byte_size(diff_child);
+                }
+            }
+        }
+
+        size
+    }
+
     /// Adds a child Node with given key.
     pub fn add_child(&mut self, k: String, child: Node) {
         match self.keys {
@@ -305,20 +496,22 @@ impl Node {
     /// * [in/out]
     ///   * `diff` - Set of changes to be applied. Modified to retain only actual changes.
     /// * [out]
-    ///   * `updates` - a nested map of `Update`s to be sent to listeners, and
-    ///   * `externals` - a Vec of External changes to be applied to other zones.
+    ///   * `updates` - a nested map of `Update`s to be sent to listeners,
+    ///   * `externals` - a Vec of External changes to be applied to other zones, and
+    ///   * `conflicts` - the number of different values with the same timestamp resolved.
     pub fn merge(&mut self,
                  diff: &mut Node,
                  vis_old: Vis,
                  vis_new: Vis
-                ) -> (Option<Update>, Vec<External>) {
+                ) -> (Option<Update>, Vec<External>, usize) {
         let mut externals: Vec<External> = vec![];
+        let mut conflicts = 0;
 
         let mut stack = Path::empty();
 
-        let update = merge(&mut stack, self, diff, vis_old, vis_new, &mut externals);
+        let update = merge(&mut stack, self, diff, vis_old, vis_new, &mut externals, &mut conflicts);
 
-        (update, externals)
+        (update, externals, conflicts)
     }
 
     /// Read data from node
@@ -345,14 +538,14 @@ impl Node {
 
 impl NodeTree {
     /// Merge two trees, including visibilitiy through ancestors.
-    pub fn merge(&mut self, diff: &mut NodeTree) -> (Option<Update>, Vec<External>) {
-        let (update, externals) = {
+    pub fn merge(&mut self, diff: &mut NodeTree) -> (Option<Update>, Vec<External>, usize) {
+        let (update, externals, conflicts) = {
             diff.vis.merge(&self.vis); // 'new' vis cannot contain older data than current vis
             self.node.merge(&mut diff.node, self.vis, diff.vis)
         };
 
         self.vis = diff.vis;
-        (update, externals)
+        (update, externals, conflicts)
     }
 
     /// Read data from node
@@ -361,6 +554,49 @@ impl NodeTree {
     pub fn read(&self, path: &Path) -> (Option<Update>, Vec<DelegatedMatch>) {
         self.node.read(self.vis, path)
     }
+
+    /// Clears tombstones older than `horizon`, see `Node::gc`.
+    pub fn gc(&mut self, horizon: u64) -> usize {
+        let deleted = cmp::max(self.vis.deleted, self.node.vis.deleted);
+
+        self.node.gc(deleted, horizon)
+    }
+
+    /// Returns the value and `updated` timestamp of the node at `path`, which must not contain
+    /// wildcards. Missing nodes have a `Null` value and a timestamp of 0. Invisible nodes have a
+    /// `Null` value.
+    pub fn get(&self, path: &Path) -> (Value, u64) {
+        let mut vis = self.vis;
+        let mut node = &self.node;
+
+        for part in &path.path {
+            vis.descend(&node.vis);
+
+            // Pass through bucket holding `part`
+            if let Some(level) = node.bucket_level() {
+                if ! is_bucket(part) {
+                    node = match node.keys.as_ref().and_then(|keys| keys.get(&bucket(part, level))) {
+                        Some(child) => child,
+                        None => return (Value::Null, 0)
+                    };
+
+                    vis.descend(&node.vis);
+                }
+            }
+
+            node = match node.keys.as_ref().and_then(|keys| keys.get(part)) {
+                Some(child) => child,
+                None => return (Value::Null, 0)
+            };
+        }
+
+        vis.descend(&node.vis);
+
+        match vis.is_visible() {
+            true => (node.value.clone(), node.vis.updated),
+            false => (Value::Null, node.vis.updated)
+        }
+    }
 }
 
 impl Update {
@@ -376,7 +612,8 @@ impl Update {
             Some(Value::I64(v)) => v.into(),
             Some(Value::U64(v)) => v.into(),
             Some(Value::F64(v)) => v.into(),
-            Some(Value::String(ref s)) => JSON::String(String::from(&**s))
+            Some(Value::String(ref s)) => JSON::String(String::from(&**s)),
+            Some(Value::Counter(ref c)) => c.value().into()
         };
 
         let keys = match self.keys {
@@ -409,7 +646,8 @@ impl Update {
                 Some(Value::I64(v)) => v.into(),
                 Some(Value::U64(v)) => v.into(),
                 Some(Value::F64(v)) => v.into(),
-                Some(Value::String(ref s)) => JSON::String(String::from(&**s))
+                Some(Value::String(ref s)) => JSON::String(String::from(&**s)),
+                Some(Value::Counter(ref c)) => c.value().into()
             };
 
             return JSON::Array(vec![JSON::Null, changed, value])
@@ -468,7 +706,32 @@ impl Update {
         return JSON::Null;
     }
 
+    /// Returns the part of this update at `path`, if any.
+    pub fn descend(self, path: &[String]) -> Option<Update> {
+        let mut update = self;
+
+        for p in path {
+            update = match update.keys.and_then(|mut keys| keys.remove(p)) {
+                Some(child) => child,
+                None => return None
+            };
+        }
+
+        Some(update)
+    }
+
     fn add_child(&mut self, k: &String, child_update: Option<Update>) {
+        // Buckets are invisible to clients, so lift their children
+        if is_bucket(k) {
+            if let Some(Update { keys: Some(keys), .. }) = child_update {
+                for (k, child_update) in keys {
+                    self.add_child(&k, Some(child_update));
+                }
+            }
+
+            return;
+        }
+
         if let Some(child_update) = child_update {
             if self.keys.is_none() {
                 self.keys = Some(BTreeMap::new())
@@ -502,7 +765,8 @@ fn merge(
     diff: &mut Node,
     mut vis_old: Vis, // Old visibility of parent node
     mut vis_new: Vis, // New visibility of parent node
-    externals: &mut Vec<External>)
+    externals: &mut Vec<External>,
+    conflicts: &mut usize)
 -> Option<Update> {
     // "Previous" effective visibility of this node
     vis_old.descend(&node.vis);
@@ -522,7 +786,32 @@ fn merge(
 
     // Merge value at node
 
-    if diff.vis.updated > node.vis.updated {
+    // Counters are combined whatever their timestamps, e.g. when replicas create the same counter
+    // concurrently. A counter created after a deletion the other one predates replaces it instead.
+    let deleted = cmp::max(vis_old.deleted, cmp::max(vis_new.deleted, diff.vis.deleted));
+    let origin = cmp::min(node.vis.updated, diff.vis.updated);
+
+    let counter_changed = match (&mut node.value, &diff.value) {
+        (&mut Value::Counter(ref mut counter), &Value::Counter(ref diff_counter)) if origin > deleted => {
+            Some(counter.merge(diff_counter))
+        },
+        _ => None
+    };
+
+    if let Some(changed) = counter_changed {
+        value_changed = changed;
+
+        if diff.vis.updated > node.vis.updated {
+            node.vis.updated = diff.vis.updated;
+            propagate = Some(Default::default());
+        }
+        else if ! changed {
+            // nothing new, throw away
+            diff.vis.updated = 0;
+            diff.value = Value::Null;
+        }
+    }
+    else if diff.vis.updated > node.vis.updated {
         // timestamp newer, use updated value
         if node.value != diff.value {
             node.value = diff.value.clone();
@@ -540,9 +829,23 @@ fn merge(
         diff.value = Value::Null;
     }
     else { // same timesstamp
-        if diff.value != node.value {
-            // TODO: This isn't so good
-            println!("Value conflict: {:?} - {:?} -> {:?} t+{:?}", stack, node.value, diff.value, diff.vis.updated);
+        let mut diff_wins = false;
+
+        if node.value != diff.value {
+            // Every replica must pick the same value regardless of merge order
+            debug!("Value conflict: {:?} - {:?} -> {:?} t+{:?}", stack, node.value, diff.value, diff.vis.updated);
+            *conflicts += 1;
+            diff_wins = diff.value.tiebreak(&node.value) == Ordering::Greater;
+        }
+
+        if diff_wins {
+            node.value = diff.value.clone();
+            value_changed = true;
+        }
+        else if diff.value != node.value {
+            // losing value, throw away
+            diff.vis.updated = 0;
+            diff.value = Value::Null;
         }
     }
 
@@ -597,6 +900,12 @@ fn merge(
         }
     }
 
+    // Children of wide nodes are held in buckets
+    if let Some(level) = node.bucket_level().or_else(|| diff.bucket_level()) {
+        node.rebucket(level, Vis::permanent());
+        diff.rebucket(level, Default::default());
+    }
+
     // Propagate uncloaks / deletes
     if let Some(mut p_node) = propagate {
         if let Some(ref mut node_keys) = node.keys {
@@ -605,7 +914,7 @@ fn merge(
                 stack.push(k);
 
                 // TODO: p_node is mutable and will get corrupted by child nodes
-                let child_diff = merge(stack, node_child, &mut p_node, vis_old, vis_new, externals);
+                let child_diff = merge(stack, node_child, &mut p_node, vis_old, vis_new, externals, conflicts);
 
                 stack.pop();
 
@@ -631,7 +940,7 @@ fn merge(
             match entry {
                 Entry::Occupied(mut entry) => {
                     // Existing node exists, so recursively merge
-                    let child_update = merge(stack, entry.get_mut(), diff_child, vis_old, vis_new, externals);
+                    let child_update = merge(stack, entry.get_mut(), diff_child, vis_old, vis_new, externals, conflicts);
                     update.add_child(k, child_update);
 
                     // TODO: remove from diff_keys if noop
@@ -640,7 +949,7 @@ fn merge(
                     // No existing node, merge to empty node
                     let mut node_child: Node = Default::default();
 
-                    let child_update = merge(stack, &mut node_child, diff_child, vis_old, vis_new, externals);
+                    let child_update = merge(stack, &mut node_child, diff_child, vis_old, vis_new, externals, conflicts);
 
                     if ! node_child.is_noop() {
                         // If there are actual changes, keep node child
@@ -660,12 +969,16 @@ fn merge(
     // True if this node is transitioning to a delegated state
     let mut initial_delegation = false;
 
+    // True if this node is transitioning back from a delegated state
+    let mut undelegation = false;
+
     // Merge delegation (external) status of node
     if diff.delegated > 0 && diff.delegated > node.delegated {
         if stack.len() > 0 && (diff.delegated ^ node.delegated) & 1 == 1 {
             // delegation status changed
             update.delegated = Some(diff.delegated & 1 == 1);
             initial_delegation = diff.delegated & 1 == 1;
+            undelegation = ! initial_delegation;
         }
 
         node.delegated = diff.delegated;
@@ -675,9 +988,8 @@ fn merge(
     }
 
     // Handle delegated data
-    if stack.len() > 0 && node.delegated & 1 > 0 && (node.keys.is_some() || node.value != Value::Null || initial_delegation) {
+    if stack.len() > 0 && node.delegated & 1 > 0 && (node.keys.is_some() || node.value != Value::Null || ! node.vis.is_noop() || initial_delegation) {
         // TODO: add externals if effective vis changes
-        // TODO: handle un-delegation
 
         let external = External {
             path: stack.clone(),
@@ -685,7 +997,8 @@ fn merge(
                 node: node.delegated(),
                 vis: vis_new
             },
-            initial: initial_delegation
+            initial: initial_delegation,
+            reclaim: false
         };
 
         externals.push(external);
@@ -702,6 +1015,15 @@ fn merge(
         }
     }
 
+    // Data is still held by the formerly delegated Zone, so it needs to be reclaimed
+    if undelegation {
+        externals.push(External {
+            path: stack.clone(),
+            reclaim: true,
+            ..Default::default()
+        });
+    }
+
     // TODO: throw node / diff / update away if empty
 
     return match update.is_noop() {
@@ -740,7 +1062,7 @@ fn read(stack: &mut Path,
     let mut update: Update = Default::default();
 
     // Set true to fetch value at this node
-    let mut read_self_value = stack.len() >= path.len();
+    let mut read_self_value = pos >= path.len();
 
     if pos < path.len() {
         // Match / get child / self values
@@ -752,7 +1074,9 @@ fn read(stack: &mut Path,
                 for (k, node_child) in node_keys.iter() {
                     stack.push(k);
 
-                    let child_update = read(stack, node_child, vis, &path, pos + 1, externals);
+                    // Buckets hold keys of this level, so don't advance path position
+                    let next = if is_bucket(k) { pos } else { pos + 1 };
+                    let child_update = read(stack, node_child, vis, &path, next, externals);
 
                     stack.pop();
 
@@ -764,9 +1088,14 @@ fn read(stack: &mut Path,
                 for (k, node_child) in node_keys.iter() {
                     stack.push(k);
 
-                    // convert part to "*#"
-                    let path = Path::new(vec!["*#".into()]);
-                    let child_update = read(stack, node_child, vis, &path, 0, externals);
+                    let child_update = if is_bucket(k) {
+                        read(stack, node_child, vis, &path, pos, externals)
+                    }
+                    else {
+                        // convert part to "*#"
+                        let path = Path::new(vec!["*#".into()]);
+                        read(stack, node_child, vis, &path, 0, externals)
+                    };
 
                     stack.pop();
 
@@ -788,6 +1117,20 @@ fn read(stack: &mut Path,
                     update.add_child(k, child_update);
                 }
             }
+            else if let (Some(level), false) = (node.bucket_level(), is_bucket(part)) {
+                // Match one, through the bucket holding it
+                let k = bucket(part, level);
+
+                if let Some(node_child) = node_keys.get(&k) {
+                    stack.push(&k);
+
+                    let child_update = read(stack, node_child, vis, &path, pos, externals);
+
+                    stack.pop();
+
+                    update.add_child(&k, child_update);
+                }
+            }
             else {
                 // Match one
                 match node_keys.get(part) {
@@ -814,7 +1157,8 @@ fn read(stack: &mut Path,
         }
     }
 
-    if read_self_value {
+    // Buckets have no value of their own
+    if read_self_value && ! node.is_bucket() {
         // Get value at this node
         if vis.is_visible() {
             update.changed = true;
@@ -844,7 +1188,7 @@ fn test_expand() {
         keys: Some(map! {
             "moo".to_string() => Node {
                 vis: Vis::new(1000, 0),
-                value: Value::F64(42.0),
+                value: Value::I64(42),
                 keys: None,
                 delegated: 0
             }
@@ -855,6 +1199,39 @@ fn test_expand() {
     assert_eq!(node, expected);
 }
 
+#[test]
+fn test_expand_numbers() {
+    use bincode;
+
+    let data: JSON = serde_json::from_str(r#"
+        {
+            "i": -9007199254740993,
+            "u": 18446744073709551615,
+            "f": 0.5
+        }
+    "#).unwrap();
+
+    let node = Node::expand(data.clone(), 1000);
+
+    assert_eq!(node.keys.as_ref().unwrap()["i"].value, Value::I64(-9007199254740993));
+    assert_eq!(node.keys.as_ref().unwrap()["u"].value, Value::U64(18446744073709551615));
+    assert_eq!(node.keys.as_ref().unwrap()["f"].value, Value::F64(0.5));
+
+    // Survives storage / replication
+    let serialized = bincode::serialize(&node, bincode::Infinite).unwrap();
+    let deserialized: Node = bincode::deserialize(&serialized).unwrap();
+
+    assert_eq!(deserialized, node);
+
+    // Survives update format
+    let (update, _) = deserialized.read(Vis::permanent(), &Path::new(vec!["*".into()]));
+    let json = update.unwrap().to_json();
+
+    assert_eq!(json[0]["i"][2], data["i"]);
+    assert_eq!(json[0]["u"][2], data["u"]);
+    assert_eq!(json[0]["f"][2], data["f"]);
+}
+
 #[test]
 fn test_merge() {
     let mut node = NodeTree {
@@ -904,6 +1281,128 @@ fn test_merge() {
     println!("update: {:#?}", update);
 }
 
+#[test]
+fn test_get() {
+    let data: JSON = serde_json::from_str(r#"{ "moo": { "cow": 42 } }"#).unwrap();
+
+    let mut tree = Node::expand(data, 1000).noop_vis();
+    tree.vis = Vis::permanent();
+
+    let moo_cow = Path::new(vec!["moo".into(), "cow".into()]);
+    let moo_moo = Path::new(vec!["moo".into(), "moo".into()]);
+
+    assert_eq!(tree.get(&moo_cow), (Value::I64(42), 1000));
+    assert_eq!(tree.get(&moo_moo), (Value::Null, 0));
+
+    let mut kill = Node::delete(2000).prepend_path(&["moo".to_string()]).noop_vis();
+    tree.merge(&mut kill);
+
+    assert_eq!(tree.get(&moo_cow), (Value::Null, 1000));
+}
+
+#[test]
+fn test_merge_counter() {
+    use value::Counter;
+
+    let mut a = Counter::default();
+    let mut b = Counter::default();
+
+    a.add("a", 2);
+    b.add("b", 3);
+
+    let root = NodeTree { node: Node::new(Value::Null, 1), vis: Vis::permanent() };
+
+    // Created concurrently by two replicas
+    let diff_a = Node::new(Value::Counter(a), 1000).prepend_path(&["moo".to_string()]);
+    let diff_b = Node::new(Value::Counter(b), 2000).prepend_path(&["moo".to_string()]);
+
+    let mut ab = root.clone();
+    ab.merge(&mut diff_a.clone().noop_vis());
+    ab.merge(&mut diff_b.clone().noop_vis());
+
+    let mut ba = root.clone();
+    ba.merge(&mut diff_b.noop_vis());
+    ba.merge(&mut diff_a.clone().noop_vis());
+
+    assert_eq!(ab, ba);
+
+    match ab.get(&Path::new(vec!["moo".into()])).0 {
+        Value::Counter(c) => assert_eq!(c.value(), 5),
+        v => panic!("Expected counter, got {:?}", v)
+    }
+
+    // A counter created after a deletion doesn't pick up totals from before it
+    let mut c = Counter::default();
+
+    c.add("a", 1);
+
+    let mut recreated = Node::new(Value::Null, 4000);
+
+    recreated.add_child("moo".into(), Node::new(Value::Counter(c), 4000));
+
+    ab.merge(&mut Node::delete(3000).prepend_path(&["moo".to_string()]).noop_vis());
+    ab.merge(&mut recreated.noop_vis());
+    ab.merge(&mut diff_a.noop_vis());
+
+    match ab.get(&Path::new(vec!["moo".into()])).0 {
+        Value::Counter(c) => assert_eq!(c.value(), 1),
+        v => panic!("Expected counter, got {:?}", v)
+    }
+}
+
+#[test]
+fn test_merge_conflict() {
+    let root = NodeTree { node: Node::new(Value::Null, 1), vis: Vis::permanent() };
+
+    let diff_a = Node::new(Value::from("a".to_string()), 1000).prepend_path(&["moo".to_string()]);
+    let diff_b = Node::new(Value::I64(42), 1000).prepend_path(&["moo".to_string()]);
+
+    let mut ab = root.clone();
+    ab.merge(&mut diff_a.clone().noop_vis());
+    let (_, _, conflicts) = ab.merge(&mut diff_b.clone().noop_vis());
+    assert_eq!(conflicts, 1);
+
+    let mut ba = root.clone();
+    ba.merge(&mut diff_b.noop_vis());
+    let (_, _, conflicts) = ba.merge(&mut diff_a.noop_vis());
+    assert_eq!(conflicts, 1);
+
+    assert_eq!(ab, ba);
+}
+
+#[test]
+fn test_gc() {
+    let data: JSON = serde_json::from_str(r#"{ "moo": { "cow": 42 }, "cow": 42 }"#).unwrap();
+
+    let mut tree = Node::expand(data, 1000).noop_vis();
+    tree.vis = Vis::permanent();
+
+    let mut kill = Node::delete(2000).prepend_path(&["moo".to_string()]).noop_vis();
+    tree.merge(&mut kill);
+
+    let before = tree.clone();
+
+    // Deletion not old enough
+    assert_eq!(tree.gc(2000), 0);
+    assert_eq!(tree, before);
+
+    // Rewritten after deletion, so could be visible again if "moo" is rewritten
+    let mut rewrite = Node::new(Value::I64(43), 3000).prepend_path(&["moo".to_string(), "cow".to_string()]).noop_vis();
+    tree.merge(&mut rewrite);
+    assert_eq!(tree.gc(2001), 0);
+
+    let mut kill = Node::delete(4000).prepend_path(&["moo".to_string()]).noop_vis();
+    tree.merge(&mut kill);
+
+    let (visible, _) = tree.read(&Path::new(vec!["**".into()]));
+
+    assert_eq!(tree.gc(4001), 2);
+    assert_eq!(tree.node.len(), 1);
+
+    // Clearing tombstones doesn't change visible data
+    assert_eq!(tree.read(&Path::new(vec!["**".into()])).0, visible);
+}
+
 #[test]
 fn test_merge_noop() {
     let mut tree = NodeTree {
@@ -913,8 +1412,123 @@ fn test_merge_noop() {
 
     let mut noop: NodeTree = Default::default();
 
-    let ( update, externals ) = tree.merge(&mut noop);
+    let ( update, externals, conflicts ) = tree.merge(&mut noop);
 
     assert_eq!(update, None);
     assert_eq!(externals.len(), 0);
+    assert_eq!(conflicts, 0);
+}
+
+#[test]
+fn test_overwritten_byte_size() {
+    let tree = Node::expand(serde_json::from_str(r#"{ "moo": "abc", "cow": 1 }"#).unwrap(), 1000);
+
+    // Keys are kept, older values replaced
+    let diff = Node::expand(serde_json::from_str(r#"{ "moo": "abcdef", "pig": 2 }"#).unwrap(), 2000);
+
+    assert_eq!(tree.overwritten_byte_size(&diff), 1 + "moo".len() + 3);
+
+    // Outdated values are left in place
+    let diff = Node::expand(serde_json::from_str(r#"{ "cow": 2 }"#).unwrap(), 500);
+
+    assert_eq!(tree.overwritten_byte_size(&diff), 1 + "cow".len() + 8);
+}
+
+#[test]
+fn test_merge_undelegate() {
+    let data: JSON = serde_json::from_str(r#"{ "moo": { "cow": 42 } }"#).unwrap();
+
+    let mut tree = Node::expand(data, 1000).noop_vis();
+    tree.vis = Vis::permanent();
+
+    let moo = ["moo".to_string()];
+
+    let mut delegate = Node::delegate(2000).prepend_path(&moo).noop_vis();
+    let (_, mut externals, _) = tree.merge(&mut delegate);
+
+    assert_eq!(externals.len(), 1);
+    assert!(externals[0].initial);
+
+    let delegated = externals.pop().unwrap().tree.node;
+
+    let mut undelegate = Node::undelegate(3000).prepend_path(&moo).noop_vis();
+    let (_, externals, _) = tree.merge(&mut undelegate);
+
+    assert_eq!(externals.len(), 1);
+    assert!(externals[0].reclaim);
+    assert_eq!(externals[0].path, Path::new(moo.to_vec()));
+
+    // Reclaimed data stays with this tree
+    let (_, externals, _) = tree.merge(&mut delegated.prepend_path(&moo).noop_vis());
+
+    assert_eq!(externals.len(), 0);
+    assert_eq!(tree.get(&Path::new(vec!["moo".into(), "cow".into()])), (Value::I64(42), 1000));
+}
+
+#[test]
+fn test_merge_buckets() {
+    let data: JSON = serde_json::from_str(r#"{ "users": { "moo": 1, "cow": 2 } }"#).unwrap();
+
+    let mut tree = Node::expand(data, 1000).noop_vis();
+    tree.vis = Vis::permanent();
+
+    let users = vec!["users".to_string()];
+    let moo_bucket = Path::new(vec!["users".into(), bucket("moo", 0)]);
+
+    let buckets = Node::buckets(0, 2000).prepend_path(&users);
+
+    // Replicas observe the delegation time, not the permanent visibility of buckets
+    assert_eq!(buckets.timestamp(), 2000 | 1);
+
+    let (_, externals, _) = tree.merge(&mut buckets.noop_vis());
+
+    assert_eq!(externals.len() as u64, path::BUCKETS);
+
+    // Existing children move to their bucket
+    let external = externals.into_iter().find(|e| e.path == moo_bucket).unwrap();
+
+    assert!(external.initial);
+    assert_eq!(external.tree.get(&Path::new(vec!["moo".into()])), (Value::I64(1), 1000));
+
+    // Reads are routed through buckets
+    let (_, delegated) = tree.read(&Path::new(vec!["users".into(), "moo".into()]));
+
+    assert_eq!(delegated.len(), 1);
+    assert_eq!(delegated[0].path, moo_bucket);
+    assert_eq!(delegated[0].match_spec, Path::new(vec!["moo".into()]));
+
+    let (_, delegated) = tree.read(&Path::new(vec!["users".into(), "*".into()]));
+
+    assert_eq!(delegated.len() as u64, path::BUCKETS);
+    assert_eq!(delegated[0].match_spec, Path::new(vec!["*".into()]));
+
+    // New children are sent to their bucket
+    let data: JSON = serde_json::from_str(r#"{ "users": { "pig": 3 } }"#).unwrap();
+    let (_, externals, _) = tree.merge(&mut Node::expand(data, 3000).noop_vis());
+
+    assert_eq!(externals.len(), 1);
+    assert_eq!(externals[0].path, Path::new(vec!["users".into(), bucket("pig", 0)]));
+
+    // Bucket has no value of its own
+    let (update, _) = external.tree.read(&Path::new(vec!["*#".into()]));
+    let json = update.unwrap().to_json();
+
+    assert_eq!(json[0]["moo"][2], 1);
+    assert_eq!(json[1], JSON::Null);
+}
+
+#[test]
+fn test_delegations() {
+    let data: JSON = serde_json::from_str(r#"{ "users": { "moo": { "a": 1 }, "cow": 2 }, "logs": 3 }"#).unwrap();
+    let mut node = Node::expand(data, 1000);
+
+    let mut delegate = Node::delegate(2000).prepend_path(&["users".to_string(), "moo".to_string()]);
+
+    delegate.add_child("logs".into(), Node::delegate(2000));
+    node.merge(&mut delegate, Vis::permanent(), Vis::permanent());
+
+    assert_eq!(node.delegations(), [
+        Path::new(vec!["logs".into()]),
+        Path::new(vec!["users".into(), "moo".into()])
+    ]);
 }
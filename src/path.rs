@@ -2,6 +2,9 @@
 
 use serde_json::Value;
 
+/// Number of buckets the children of a wide node are split into
+pub const BUCKETS: u64 = 64;
+
 #[derive(Clone, Debug, Default, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub struct Path {
     pub path: Vec<String>
@@ -62,11 +65,39 @@ impl Path {
         Path::new(prefix.cloned().collect())
     }
 
+    /// Returns the same path without buckets, i.e. the path as seen by clients.
+    pub fn without_buckets(&self) -> Path {
+        Path::new(self.path.iter().filter(|p| ! is_bucket(p)).cloned().collect())
+    }
+
     pub fn delegate(&self, d_path: &Path) -> (bool, Option<Path>) {
-        let mut iter = self.path.iter();
+        let mut iter = self.path.iter().peekable();
         let mut retain = false;
 
         for d in &d_path.path {
+            // Buckets hold keys of the same level, so they don't consume a part
+            if let Some(level) = bucket_level(d) {
+                match iter.peek().map(|p| &***p) {
+                    None => {
+                        return (true, None);
+                    },
+                    Some("*") => {
+                        retain = true;
+                        continue;
+                    },
+                    Some("**") | Some("*#") => {
+                        // Bucket itself has no value, so only match descendants
+                        return (true, Some(path!(%)));
+                    },
+                    Some(p) if bucket(p, level) == *d => {
+                        continue;
+                    },
+                    _ => {
+                        return (true, None);
+                    }
+                }
+            }
+
             let p = iter.next();
 
             match p {
@@ -110,6 +141,41 @@ impl Path {
     }
 }
 
+/// Returns the bucket holding child `key` of a wide node, for buckets at `level`. Buckets of
+/// nested wide nodes are at level 0, buckets splitting a bucket are one level deeper.
+///
+/// Bucket keys look like `*<level>-<n>`, so they never clash with a client's keys.
+pub fn bucket(key: &str, level: u64) -> String {
+    // FNV-1a, as bucketing must be stable across replicas and restarts
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for b in level.to_string().bytes().chain(Some(b'-')).chain(key.bytes()) {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    format!("*{}-{}", level, hash % BUCKETS)
+}
+
+/// Returns true if `part` is a bucket key.
+pub fn is_bucket(part: &str) -> bool {
+    bucket_level(part).is_some()
+}
+
+/// Returns the level of bucket key `part`, if it is one.
+pub fn bucket_level(part: &str) -> Option<u64> {
+    if ! part.starts_with('*') {
+        return None;
+    }
+
+    let mut parts = part[1..].splitn(2, '-');
+
+    match (parts.next().and_then(|l| l.parse().ok()), parts.next().and_then(|n| n.parse::<u64>().ok())) {
+        (Some(level), Some(_)) => Some(level),
+        _ => None
+    }
+}
+
 #[test]
 fn test_macro() {
     assert_eq!(path(vec!["root"]), path!(root));
@@ -216,3 +282,54 @@ fn test_delegate_match() {
         (retain, d_listener)
     }
 }
+
+#[test]
+fn test_bucket() {
+    let b = bucket("moo", 0);
+
+    assert!(is_bucket(&b));
+    assert_eq!(bucket_level(&b), Some(0));
+    assert_eq!(bucket("moo", 0), b);
+    assert_eq!(bucket_level(&bucket("moo", 1)), Some(1));
+
+    assert!(! is_bucket("*"));
+    assert!(! is_bucket("**"));
+    assert!(! is_bucket("*#"));
+    assert!(! is_bucket("moo"));
+
+    let mut p = path!(root);
+
+    p.push(&b);
+    p.push(&"moo".to_string());
+    assert_eq!(p.without_buckets(), path!(root.moo));
+}
+
+#[test]
+fn test_delegate_bucket() {
+    let mut d_path = path!(root);
+
+    d_path.push(&bucket("moo", 0));
+
+    // Keys in the bucket are delegated, relative to the bucket
+    let (r, p) = path!(root.moo.cow).delegate(&d_path);
+    assert!(!r);
+    assert_eq!(p.unwrap(), path!(moo.cow));
+
+    let (r, p) = path!(root.*.cow).delegate(&d_path);
+    assert!(r);
+    assert_eq!(p.unwrap(), path!(*.cow));
+
+    let (r, p) = path!(root.%).delegate(&d_path);
+    assert!(r);
+    assert_eq!(p.unwrap(), path!(%));
+
+    let (r, p) = path!(root).delegate(&d_path);
+    assert!(r);
+    assert!(p.is_none());
+
+    // Keys in other buckets are not
+    let other = (0..).map(|i| format!("moo{}", i)).find(|k| bucket(k, 0) != bucket("moo", 0)).unwrap();
+    let (r, p) = Path::new(vec!["root".into(), other]).delegate(&d_path);
+    assert!(r);
+    assert!(p.is_none());
+}
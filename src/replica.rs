@@ -7,7 +7,7 @@ use std::str::FromStr;
 /// Represents a Replica.
 ///
 /// Replicas are identified by an IP/port combination
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct Replica {
     addr: SocketAddr
 }
@@ -39,6 +39,9 @@ fn accept_loop(app: AppHandle, listener: TcpListener) {
         let stream = listener.accept();
 
         match stream {
+            Ok(_) if app.is_stopping() => {
+                // shutting down, connection is closed when dropped
+            },
             Ok(stream) => {
                 // connection succeeded
                 println!("Connection from: {}", stream.peer_addr().unwrap());
@@ -1,18 +1,30 @@
 use std::io::prelude::*;
-use std::process;
 
 use app::{App, AppHandle};
 use path::Path;
 
 struct Shell<W> {
     app: AppHandle,
-    writer: W
+    writer: W,
+    session: bool // Commands come from an admin connection, which `exit` and `quit` close
 }
 
 pub fn start<R: BufRead, W: Write>(app: App, reader: R, writer: W) {
     let mut s = Shell {
         app: app.handle(),
-        writer: writer
+        writer: writer,
+        session: false
+    };
+
+    s.command_loop(reader);
+}
+
+/// Runs commands of an admin connection until it closes.
+pub fn serve<R: BufRead, W: Write>(app: AppHandle, reader: R, writer: W) {
+    let mut s = Shell {
+        app: app,
+        writer: writer,
+        session: true
     };
 
     s.command_loop(reader);
@@ -34,9 +46,11 @@ impl<W: Write> Shell<W> {
                     Some("cluster.sync") => self.sync(),
                     Some("cluster.sync_all") => self.sync_all(),
                     Some("store.dump") => self.store_dump(line.next().unwrap_or_default()),
+                    Some("store.list") => self.store_list(),
                     Some("stats") => self.stats(),
                     Some("zone.dump") => self.zone_dump(line.next().unwrap_or_default()),
                     Some("zone.sync") => self.zone_sync(line.next().unwrap_or_default()),
+                    Some("exit") | Some("quit") if self.session => return,
                     Some("exit") | Some("quit") | Some("shutdown") => self.shutdown(),
                     Some("") => (),
                     _ => writeln!(self.writer, "Bad command").unwrap()
@@ -66,10 +80,8 @@ impl<W: Write> Shell<W> {
     }
 
     fn shutdown(&mut self) {
-        writeln!(self.writer, "Shutting down...").unwrap();
-
-        // TODO: exit is not clean, destructors not called, files/sockets not flushed
-        process::exit(0);
+        self.writer.flush().unwrap();
+        self.app.shutdown();
     }
 
     fn stats(&mut self) {
@@ -100,6 +112,28 @@ impl<W: Write> Shell<W> {
         }.unwrap();
     }
 
+    fn store_list(&mut self) {
+        use time;
+
+        let zones = self.app.store.inventory();
+        let len = zones.len();
+        let total: u64 = zones.iter().map(|z| z.size).sum();
+
+        writeln!(self.writer, "Stored Zones:").unwrap();
+
+        for z in zones {
+            let path = z.path.path.join(".");
+            let written = match z.written {
+                0 => String::from("never saved"),
+                ms => time::at_utc(time::Timespec::new((ms / 1000) as i64, 0)).rfc3339().to_string()
+            };
+
+            writeln!(self.writer, "{:>10} {:>20} {:?}", z.size, written, path).unwrap();
+        }
+
+        writeln!(self.writer, "Total: {} stored zones, {} bytes", len, total).unwrap();
+    }
+
     fn zone_dump(&mut self, path: &str) {
         let path = match path {
             "" => Path::new(vec![]),
@@ -0,0 +1,43 @@
+//! Shuts down gracefully on SIGTERM and SIGINT
+//!
+//! Only the first signal is handled, a second one kills the process if shutdown hangs.
+
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+use app::AppHandle;
+
+/// Set by the signal handler
+static RECEIVED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn handler(_: libc::c_int) {
+    RECEIVED.store(true, Ordering::SeqCst);
+
+    // signal() is safe to call here
+    unsafe {
+        libc::signal(libc::SIGTERM, libc::SIG_DFL);
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+/// Installs handlers for SIGTERM and SIGINT that shut down `app`.
+pub fn watch(app: AppHandle) {
+    unsafe {
+        let handler = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+
+    // Little is safe to do in a signal handler, so shut down from a thread instead
+    thread::spawn(move|| {
+        while ! RECEIVED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        app.shutdown();
+    });
+}
@@ -0,0 +1,295 @@
+//! On-disk format of zone data files
+//!
+//! Files start with a header holding a magic number, the format version, the payload length and a
+//! CRC-32 checksum of the payload, so torn or corrupted files are detected before deserializing.
+//!
+//! Files written before the header was introduced are read as version 0. Older versions are
+//! upgraded at load time, and written back in the current version when the Zone is next saved.
+//!
+//! Records appended to logs, the manifest and log store segments are framed with their length, a
+//! checksum of the length and a checksum of the payload. Only an incomplete record at the end of a
+//! log is expected after a crash, anything else that does not check out is corruption.
+
+use std::io::Cursor;
+
+use bincode;
+
+use super::StoreError;
+use zone::ZoneData;
+
+/// Marks the start of a zone file
+const MAGIC: &'static [u8; 4] = b"QMZN";
+
+/// Version of the format written
+pub const VERSION: u32 = 1;
+
+/// Magic, version, payload length and checksum
+const HEADER_SIZE: usize = 20;
+
+/// Payload length, its checksum and the payload checksum
+pub const RECORD_HEADER_SIZE: usize = 12;
+
+/// Adds a header to serialized `ZoneData`.
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(HEADER_SIZE + payload.len());
+
+    encoded.extend_from_slice(MAGIC);
+    encoded.extend_from_slice(&le_bytes(VERSION as u64, 4));
+    encoded.extend_from_slice(&le_bytes(payload.len() as u64, 8));
+    encoded.extend_from_slice(&le_bytes(crc32(payload) as u64, 4));
+    encoded.extend_from_slice(payload);
+    encoded
+}
+
+/// Verifies the header of a zone file and returns its version and payload. Files without a header
+/// are version 0.
+pub fn decode(buffer: &[u8]) -> Result<(u32, &[u8]), StoreError> {
+    if buffer.len() < MAGIC.len() || &buffer[..MAGIC.len()] != MAGIC {
+        return Ok((0, buffer));
+    }
+
+    if buffer.len() < HEADER_SIZE {
+        return Err(StoreError::ReadError("Incomplete header".into()));
+    }
+
+    let version = from_le_bytes(&buffer[4..8]) as u32;
+    let len = from_le_bytes(&buffer[8..16]);
+    let checksum = from_le_bytes(&buffer[16..20]) as u32;
+    let payload = &buffer[HEADER_SIZE..];
+
+    if version > VERSION {
+        return Err(StoreError::ReadError(format!("Unsupported format version {}", version).into()));
+    }
+
+    if payload.len() as u64 != len {
+        return Err(StoreError::ReadError(format!("Expected {} bytes, found {}", len, payload.len()).into()));
+    }
+
+    if crc32(payload) != checksum {
+        return Err(StoreError::ReadError("Checksum mismatch".into()));
+    }
+
+    Ok((version, payload))
+}
+
+/// Verifies and deserializes a zone file, upgrading it from older versions.
+pub fn read(buffer: &[u8]) -> Result<ZoneData, StoreError> {
+    let (version, payload) = try!(decode(buffer));
+
+    match version {
+        // Version 1 only added the header
+        0 | 1 => {
+            let mut cursor = Cursor::new(payload);
+
+            let data = try!(bincode::deserialize_from(&mut cursor, bincode::Infinite)
+                .map_err(|err| StoreError::ReadError(Box::new(err))));
+
+            // Trailing bytes mean the payload is not what it claims to be
+            if cursor.position() != payload.len() as u64 {
+                return Err(StoreError::ReadError("Trailing bytes after data".into()));
+            }
+
+            Ok(data)
+        },
+        _ => unreachable!()
+    }
+}
+
+/// Frames a serialized log record.
+pub fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let len = le_bytes(payload.len() as u64, 4);
+    let mut encoded = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+
+    encoded.extend_from_slice(&len);
+    encoded.extend_from_slice(&le_bytes(crc32(&len) as u64, 4));
+    encoded.extend_from_slice(&le_bytes(crc32(payload) as u64, 4));
+    encoded.extend_from_slice(payload);
+    encoded
+}
+
+/// Verifies the records of a log, returning the offset and payload of each, and the length of
+/// the complete ones. Damage that no intact record follows is taken for an incomplete write at the
+/// end and left out, other damage is an error.
+pub fn decode_records(buffer: &[u8]) -> Result<(Vec<(u64, &[u8])>, u64), StoreError> {
+    let mut records = vec![];
+    let mut offset = 0;
+
+    while offset < buffer.len() {
+        let rest = &buffer[offset..];
+
+        if rest.len() < RECORD_HEADER_SIZE {
+            break;
+        }
+
+        if crc32(&rest[0..4]) != from_le_bytes(&rest[4..8]) as u32 {
+            // The header of the last record may not have been written completely
+            if !intact_record_from(buffer, offset + 1) {
+                break;
+            }
+
+            return Err(StoreError::ReadError(format!("Bad record length at {}", offset).into()));
+        }
+
+        let len = from_le_bytes(&rest[0..4]) as usize;
+
+        if rest.len() < RECORD_HEADER_SIZE + len {
+            break;
+        }
+
+        let payload = &rest[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len];
+
+        if crc32(payload) != from_le_bytes(&rest[8..12]) as u32 {
+            // Nor its payload
+            if !intact_record_from(buffer, offset + RECORD_HEADER_SIZE + len) {
+                break;
+            }
+
+            return Err(StoreError::ReadError(format!("Checksum mismatch in record at {}", offset).into()));
+        }
+
+        records.push((offset as u64, payload));
+        offset += RECORD_HEADER_SIZE + len;
+    }
+
+    Ok((records, offset as u64))
+}
+
+/// Whether an intact record starts anywhere in `buffer` from `start` on.
+fn intact_record_from(buffer: &[u8], start: usize) -> bool {
+    (start..buffer.len()).any(|offset| {
+        let rest = &buffer[offset..];
+
+        if rest.len() < RECORD_HEADER_SIZE || crc32(&rest[0..4]) != from_le_bytes(&rest[4..8]) as u32 {
+            return false;
+        }
+
+        let len = from_le_bytes(&rest[0..4]) as usize;
+
+        rest.len() >= RECORD_HEADER_SIZE + len &&
+            crc32(&rest[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len]) == from_le_bytes(&rest[8..12]) as u32
+    })
+}
+
+/// Verifies a single complete record, returning its payload.
+pub fn decode_record(buffer: &[u8]) -> Result<&[u8], StoreError> {
+    match try!(decode_records(buffer)) {
+        (ref records, len) if records.len() == 1 && len == buffer.len() as u64 => Ok(records[0].1),
+        _ => Err(StoreError::ReadError("Incomplete record".into()))
+    }
+}
+
+/// CRC-32 (IEEE) of `bytes`.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+fn le_bytes(n: u64, len: usize) -> Vec<u8> {
+    (0..len).map(|i| (n >> (8 * i)) as u8).collect()
+}
+
+fn from_le_bytes(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |n, &b| (n << 8) | b as u64)
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+}
+
+#[test]
+fn test_format() {
+    use node::{Node, NodeTree, Vis};
+    use path::Path;
+    use serde_json::Value as JSON;
+
+    let data = ZoneData::new(
+        Path::new(vec!["moo".into()]),
+        NodeTree {
+            vis: Vis::update(1000),
+            node: Node::expand(JSON::String(String::from("moo")), 1000)
+        }
+    );
+
+    let serialized = bincode::serialize(&data, bincode::Infinite).unwrap();
+    let encoded = encode(&serialized);
+
+    assert_eq!(decode(&encoded).unwrap(), (VERSION, &serialized[..]));
+    assert_eq!(read(&encoded).unwrap(), data);
+
+    // Headerless files are migrated
+    assert_eq!(read(&serialized).unwrap(), data);
+
+    // Torn and corrupted files are rejected
+    assert!(read(&encoded[..encoded.len() - 1]).is_err());
+    assert!(read(&encoded[..10]).is_err());
+
+    let mut corrupted = encoded.clone();
+    let last = corrupted.len() - 1;
+
+    corrupted[last] ^= 1;
+    assert!(read(&corrupted).is_err());
+
+    // Newer versions are not read
+    let mut newer = encoded.clone();
+
+    newer[4] = VERSION as u8 + 1;
+    assert!(read(&newer).is_err());
+}
+
+#[test]
+fn test_records() {
+    let mut log = encode_record(b"moo");
+
+    log.extend(encode_record(b""));
+    log.extend(encode_record(b"cow"));
+
+    let (records, len) = decode_records(&log).unwrap();
+
+    assert_eq!(records, [(0, &b"moo"[..]), (15, &b""[..]), (27, &b"cow"[..])]);
+    assert_eq!(len, log.len() as u64);
+    assert_eq!(decode_record(&log[27..]).unwrap(), b"cow");
+
+    // An incomplete record at the end is left out
+    for torn in 28..log.len() {
+        assert_eq!(decode_records(&log[..torn]).unwrap().1, 27);
+    }
+
+    let mut torn = log.clone();
+    let last = torn.len() - 1;
+
+    torn[last] ^= 1;
+    assert_eq!(decode_records(&torn).unwrap().1, 27);
+
+    // So is a tail of zeros or garbage, as left by a crash while the file was extended
+    let mut torn = log.clone();
+
+    torn.extend(vec![0; 64]);
+    assert_eq!(decode_records(&torn).unwrap().1, log.len() as u64);
+
+    let mut torn = log.clone();
+
+    torn.extend((0..64u32).map(|i| (i * 37 + 11) as u8));
+    assert_eq!(decode_records(&torn).unwrap().1, log.len() as u64);
+
+    // Damage before the end is not
+    let mut corrupted = log.clone();
+
+    corrupted[RECORD_HEADER_SIZE] ^= 1;
+    assert!(decode_records(&corrupted).is_err());
+
+    let mut corrupted = log.clone();
+
+    corrupted[0] ^= 1;
+    assert!(decode_records(&corrupted).is_err());
+}
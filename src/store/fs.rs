@@ -1,223 +1,338 @@
 //! A simple filesystem based zone store. For test use only.
+//!
+//! Each Zone has a data file, and a log file next to it holding deltas since the last save.
+//!
+//! File names are derived from truncated, hashed Zone paths, so a manifest maps them back to Zone
+//! paths. Saves, deletes and first appends of a Zone append a record to the manifest, which is
+//! rewritten once mostly superseded. On startup, it is reconciled with the data files found and
+//! rewritten. Zones only stored as logs are listed from the manifest with a size of 0.
+//!
+//! Data files are written in the checksummed format of the `format` module, and records of logs
+//! and the manifest are framed by it.
 
 use std;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, BTreeSet};
 use std::collections::hash_map::DefaultHasher;
 use std::error::Error;
-use std::fs::{DirBuilder, File};
+use std::fs::{DirBuilder, File, OpenOptions};
 use std::hash::{Hash, Hasher};
 use std::io::ErrorKind;
 use std::io::prelude::*;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{Receiver, Sender};
-use std::thread;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
 
 use bincode;
-use threadpool::ThreadPool;
 
 use super::*;
-use app::{App, AppHandle};
+use clock;
+use node::NodeTree;
 use path::Path;
-use zone::{ZoneData, ZoneHandle};
+use zone::ZoneData;
 
-const NUM_THREADS: usize = 50;
+/// Extension of the write-ahead log of a Zone
+pub const LOG: &'static str = "wal";
 
-pub struct FS {
-    app: AppHandle,
+/// Extension of the write-ahead log being covered by a snapshot write in progress
+pub const SAVING_LOG: &'static str = "wal.saving";
 
-    dir: std::path::PathBuf,
-    rx: Receiver<StoreCall>,
+/// File name of the manifest
+pub const MANIFEST: &'static str = "manifest";
 
-    read_pool: ThreadPool,
-    write_pool: ThreadPool,
+/// Manifest is rewritten once it holds this many more records than Zones
+const MANIFEST_SLACK: usize = 1000;
 
-    write_queue: Arc<Mutex<VecDeque<ZoneHandle>>>
+pub struct FS {
+    dir: std::path::PathBuf,
+    manifest: Mutex<Manifest>
 }
 
-impl FS {
-    /// Start the Store "process".
-    pub fn spawn(app: &mut App) {
-        // TODO: take serializer as parameter?
-        let dir = format!("data_{}", app.id);
-        let channel = app.channels.store.take().expect("Receiver already taken");
-        let store = FS::new(app.handle(), &dir, channel);
-
-        thread::spawn(move|| {
-            store.message_loop();
-        });
-    }
+/// Stored Zones by file name, and the number of records in the manifest file
+struct Manifest {
+    zones: BTreeMap<String, StoredZone>,
+    records: usize
+}
 
-    pub fn new(app: AppHandle, dir: &str, channel: StoreChannel) -> FS {
+impl FS {
+    pub fn new(dir: &str) -> FS {
         let dir = std::path::PathBuf::from(dir);
 
         if ! dir.is_dir() {
             DirBuilder::new().recursive(true).create(&dir).unwrap();
         }
 
+        // Zones only stored as logs are lost with a damaged manifest, the others are found again
+        let mut zones = match blocking_read_manifest(&dir) {
+            Ok(manifest) => manifest.unwrap_or_default(),
+            Err(err) => {
+                error!("Could not read manifest, rebuilding it: {}", err);
+                BTreeMap::new()
+            }
+        };
+
+        // Saves and deletes may have been cut short before they were recorded
+        blocking_reconcile(&dir, &mut zones).expect("Could not reconcile manifest");
+        blocking_write_manifest(&dir, &zones).expect("Could not write manifest");
+
         FS {
-            app: app,
             dir: dir,
-            rx: channel.rx,
-            read_pool: ThreadPool::new(NUM_THREADS),
-            write_pool: ThreadPool::new(NUM_THREADS),
-            write_queue: Arc::new(Mutex::new(VecDeque::new()))
+            manifest: Mutex::new(Manifest { records: zones.len(), zones: zones })
         }
     }
 
-    fn message_loop(self) {
-        loop {
-            let call = self.rx.recv().unwrap();
+    /// Updates the manifest entry of the Zone with data file `name`, removing it if `zone` is None.
+    fn record(&self, name: String, zone: Option<StoredZone>) -> Result<(), StoreError> {
+        let mut manifest = self.manifest.lock().unwrap();
 
-            match call {
-                StoreCall::List(reply) => self.list(reply),
-                StoreCall::Load(zone, path) => self.load(zone, path),
-                StoreCall::LoadData(path, tx) => self.load_data(path, tx),
-                StoreCall::RequestWrite(zone) => self.request_write(zone),
-                StoreCall::Write(zone, path, data) => self.write(zone, path, data)
-            }
+        match zone {
+            Some(ref zone) => manifest.zones.insert(name.clone(), zone.clone()),
+            None => manifest.zones.remove(&name)
+        };
+
+        if manifest.records > 2 * manifest.zones.len() + MANIFEST_SLACK {
+            try!(blocking_write_manifest(&self.dir, &manifest.zones));
+            manifest.records = manifest.zones.len();
         }
+        else {
+            try!(blocking_append_manifest(&self.dir, &(name, zone)));
+            manifest.records += 1;
+        }
+
+        Ok(())
     }
 
-    /// Lists all Zone Paths stored locally
-    pub fn list(&self, tx: Sender<Path>) {
-        let entries = match std::fs::read_dir(&self.dir) {
-            Err(err) => {
-                error!("Error listing directory.");
-                error!("  {:?}", err);
-                return;
-            },
-            Ok(entries) => entries
-        };
+    /// Returns the path of the data file of a `Zone`.
+    fn zonepath(&self, path: &Path) -> std::path::PathBuf {
+        let mut filepath = self.dir.clone();
 
-        for entry in entries {
-            let entry = match entry {
-                Err(err) => {
-                    error!("Error reading entry.");
-                    error!("  {:?}", err);
-                    return;
-                },
-                Ok(entry) => entry
-            };
+        filepath.push(zonefilename(path));
+        filepath
+    }
+}
 
+impl Store for FS {
+    fn delete(&self, path: &Path) -> Result<(), StoreError> {
+        let filepath = self.zonepath(path);
+        let filepaths = [filepath.with_extension(LOG), filepath.with_extension(SAVING_LOG), filepath];
 
-            match blocking_read(&entry.path()) {
-                Err(err) => {
-                    error!("Error loading {:?}: {}", entry, err.description());
-                    error!("  {:?}", err);
-                },
-                Ok(node) => {
-                    tx.send(node.path).unwrap();
+        for filepath in filepaths.iter() {
+            if let Err(err) = std::fs::remove_file(&filepath) {
+                if err.kind() != ErrorKind::NotFound {
+                    error!("  Error deleting {}: {}", filepath.display(), err);
+                    return Err(StoreError::WriteError(Box::new(err)));
                 }
             }
         }
+
+        // Files are gone, so a crash before this is reconciled at startup
+        self.record(zonefilename(path), None)
     }
 
-    /// Loads data for a `Zone` asynchronously, notifying its handle when done.
-    pub fn load(&self, zone: ZoneHandle, path: Path) {
-        let mut filepath = self.dir.clone();
+    fn list(&self) -> Result<Vec<StoredZone>, StoreError> {
+        Ok(self.manifest.lock().unwrap().zones.values().cloned().collect())
+    }
 
-        self.app.stats.store.reads_pending.increment();
+    fn load(&self, path: &Path) -> Result<ZoneData, StoreError> {
+        blocking_load(&self.zonepath(path))
+    }
 
-        let stats = self.app.stats.clone();
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), StoreError> {
+        let encoded = format::encode(data);
 
-        self.read_pool.execute(move|| {
-            debug!("Loading: {:?}", path);
+        try!(blocking_write(&self.zonepath(path), &encoded));
 
-            let filename = zonefilename(&path);
+        // Data file is in place, so a crash before this is reconciled at startup
+        self.record(zonefilename(path), Some(StoredZone {
+            path: path.clone(),
+            size: encoded.len() as u64,
+            written: clock::wall_millis()
+        }))
+    }
 
-            filepath.push(filename);
+    fn append(&self, path: &Path, diff: &[u8]) -> Result<(), StoreError> {
+        let name = zonefilename(path);
 
-            debug!("reading {}", filepath.display());
+        // Zones are listed before their first save, as logs can't be mapped back to their path
+        let listed = self.manifest.lock().unwrap().zones.contains_key(&name);
 
-            match blocking_read(&*filepath) {
-                Err(err) => {
-                    error!("Error loading {:?} - {}: {}", path, filepath.display(), err.description());
-                    error!("{:?}", err);
-                    stats.store.reads_errors.increment();
-                    // TODO: set Zone to error state
-                    //zone.set_error(err);
-                },
-                Ok(node) => zone.loaded(node)
-            };
+        if ! listed {
+            try!(self.record(name, Some(StoredZone { path: path.clone(), size: 0, written: 0 })));
+        }
 
-            stats.store.reads_pending.decrement();
-            stats.store.reads.increment();
-        });
+        blocking_append(&self.zonepath(path).with_extension(LOG), &format::encode_record(diff))
     }
 
-    /// Asynchronously load and send `ZoneData` for `Path` to channel.
-    pub fn load_data(&self, path: Path, tx: Sender<Option<ZoneData>>) {
-        let mut filepath = self.dir.clone();
+    /// Cuts off an incomplete diff at the end of each log, e.g. from a crash during an append, so
+    /// later appends can be read.
+    fn recover(&self, path: &Path) -> Result<(), StoreError> {
+        let filepath = self.zonepath(path);
 
-        self.read_pool.execute(move|| {
-            debug!("Loading: {:?}", path);
+        for logpath in [filepath.with_extension(SAVING_LOG), filepath.with_extension(LOG)].iter() {
+            try!(blocking_read_log(logpath, true));
+        }
 
-            let filename = zonefilename(&path);
+        Ok(())
+    }
+
+    fn rotate_log(&self, path: &Path) -> Result<(), StoreError> {
+        let filepath = self.zonepath(path);
 
-            filepath.push(filename);
+        blocking_rotate(&filepath.with_extension(LOG), &filepath.with_extension(SAVING_LOG))
+    }
 
-            debug!("reading {}", filepath.display());
+    fn sync_log(&self, path: &Path) -> Result<(), StoreError> {
+        let filepath = self.zonepath(path);
 
-            tx.send(blocking_read(&*filepath).ok()).is_ok(); // ignore if caller goes away
-        });
+        // diffs may still sit in a rotated log, and the rotation or log creation in the directory
+        for logpath in [filepath.with_extension(SAVING_LOG), filepath.with_extension(LOG)].iter() {
+            try!(blocking_sync(logpath));
+        }
+
+        blocking_sync_dir(filepath.parent().unwrap())
     }
 
-    /// Request for notification to write data.
-    pub fn request_write(&self, zone: ZoneHandle) {
-        if self.write_pool.active_count() >= NUM_THREADS {
-            // No write slots available, save for later
-            self.write_queue.lock().unwrap().push_back(zone);
+    fn truncate_log(&self, path: &Path) -> Result<(), StoreError> {
+        if let Err(err) = std::fs::remove_file(&self.zonepath(path).with_extension(SAVING_LOG)) {
+            if err.kind() != ErrorKind::NotFound {
+                return Err(StoreError::WriteError(Box::new(err)));
+            }
         }
-        else {
-            zone.save();
+
+        Ok(())
+    }
+}
+
+/// Reads data for a Zone and replays its write-ahead logs on top.
+pub fn blocking_load(filepath: &std::path::Path) -> Result<ZoneData, StoreError> {
+    let mut data = try!(blocking_read(filepath));
+
+    for logpath in [filepath.with_extension(SAVING_LOG), filepath.with_extension(LOG)].iter() {
+        for mut diff in try!(blocking_read_log(logpath, false)) {
+            // Delegated data was passed on to other Zones when first merged
+            data.tree.merge(&mut diff);
         }
     }
 
-    /// Write data for a `Zone` asynchronously, notifying its handle when done.
-    pub fn write(&self, zone: ZoneHandle, path: Path, data: Vec<u8>) {
-        let path = path.clone();
-        let mut filepath = self.dir.clone();
+    Ok(data)
+}
+
+/// Reads diffs from a log. With `repair`, an incomplete diff at the end is cut off. Damage
+/// elsewhere is an error, so later diffs are never cut off.
+pub fn blocking_read_log(logpath: &std::path::Path, repair: bool) -> Result<Vec<NodeTree>, StoreError> {
+    debug!("blocking_read_log: {:?}", logpath);
+
+    let mut buffer = Vec::new();
 
-        let pending = self.write_queue.clone();
+    match File::open(logpath) {
+        Err(err) => {
+            if err.kind() == ErrorKind::NotFound {
+                return Ok(vec![]);
+            }
 
-        self.app.stats.store.writes_pending.increment();
+            return Err(StoreError::ReadError(Box::new(err)));
+        },
+        Ok(mut file) => {
+            if let Err(err) = file.read_to_end(&mut buffer) {
+                return Err(StoreError::ReadError(Box::new(err)));
+            }
+        }
+    }
 
-        let stats = self.app.stats.clone();
+    let (diffs, len) = try!(parse_log(&buffer).map_err(|err| {
+        error!("Damaged log {}: {}", logpath.display(), err);
+        err
+    }));
 
-        self.write_pool.execute(move|| {
-            debug!("Writing: {:?}", path);
+    if len < buffer.len() as u64 {
+        warn!("Incomplete diff in {} at {}", logpath.display(), len);
 
-            let filename = zonefilename(&path);
+        if repair {
+            let truncated = OpenOptions::new().write(true).open(logpath)
+                .and_then(|file| file.set_len(len));
 
-            filepath.push(filename);
+            if let Err(err) = truncated {
+                return Err(StoreError::WriteError(Box::new(err)));
+            }
+        }
+    }
 
-            debug!("writing {}", filepath.display());
+    Ok(diffs)
+}
 
-            match blocking_write(&*filepath, data) {
-                Err(err) => {
-                    error!("Error writing {:?} - {}: {}", path, filepath.display(), err.description());
-                    error!("{:?}", err);
-                    stats.store.writes_errors.increment();
-                    // TODO set Zone to error state
-                    //zone.set_error(err);
-                },
-                Ok(_) => zone.saved()
-            };
+/// Parses diffs from the contents of a log. Returns the diffs and the length of the complete ones.
+pub fn parse_log(buffer: &[u8]) -> Result<(Vec<NodeTree>, u64), StoreError> {
+    let (records, len) = try!(format::decode_records(buffer));
+    let mut diffs = vec![];
 
-            stats.store.writes_pending.decrement();
-            stats.store.writes.increment();
+    for (_, payload) in records {
+        diffs.push(try!(bincode::deserialize(payload).map_err(|err| StoreError::ReadError(Box::new(err)))));
+    }
 
-            let mut pending = pending.lock().unwrap();
+    Ok((diffs, len))
+}
+
+fn blocking_append(logpath: &std::path::Path, serialized: &[u8]) -> Result<(), StoreError> {
+    let mut file = match OpenOptions::new().create(true).append(true).open(logpath) {
+        Err(err) => return Err(StoreError::WriteError(Box::new(err))),
+        Ok(file) => file
+    };
 
-            // "Wake" any zones waiting to write
-            if let Some(zone) = pending.pop_front() {
-                zone.save();
+    if let Err(err) = file.write_all(serialized) {
+        return Err(StoreError::WriteError(Box::new(err)));
+    }
+
+    Ok(())
+}
+
+fn blocking_sync(logpath: &std::path::Path) -> Result<(), StoreError> {
+    let file = match File::open(logpath) {
+        Err(err) => {
+            if err.kind() == ErrorKind::NotFound {
+                return Ok(()); // nothing logged
             }
-        });
+
+            return Err(StoreError::WriteError(Box::new(err)));
+        },
+        Ok(file) => file
+    };
+
+    if let Err(err) = file.sync_data() {
+        return Err(StoreError::WriteError(Box::new(err)));
     }
+
+    Ok(())
 }
 
-fn blocking_read(filepath: &std::path::Path) -> Result<ZoneData, StoreError> {
+/// Moves the log at `logpath` to `savingpath`. Diffs already at `savingpath` are kept, as their
+/// snapshot write must have failed.
+fn blocking_rotate(logpath: &std::path::Path, savingpath: &std::path::Path) -> Result<(), StoreError> {
+    if ! logpath.exists() {
+        return Ok(());
+    }
+
+    if ! savingpath.exists() {
+        if let Err(err) = std::fs::rename(logpath, savingpath) {
+            return Err(StoreError::WriteError(Box::new(err)));
+        }
+
+        return Ok(());
+    }
+
+    let mut buffer = Vec::new();
+
+    if let Err(err) = File::open(logpath).and_then(|mut file| file.read_to_end(&mut buffer)) {
+        return Err(StoreError::ReadError(Box::new(err)));
+    }
+
+    try!(blocking_append(savingpath, &buffer));
+
+    if let Err(err) = std::fs::remove_file(logpath) {
+        return Err(StoreError::WriteError(Box::new(err)));
+    }
+
+    Ok(())
+}
+
+pub fn blocking_read(filepath: &std::path::Path) -> Result<ZoneData, StoreError> {
     debug!("blocking_read: {:?}", filepath);
 
     let mut file = match File::open(filepath) {
@@ -246,16 +361,14 @@ fn blocking_read(filepath: &std::path::Path) -> Result<ZoneData, StoreError> {
         return Err(StoreError::ReadError(Box::new(err)));
     }
 
-    match bincode::deserialize(&buffer) {
-        Err(err) => {
-            error!("err {}:", err.description());
-            Err(StoreError::ReadError(Box::new(err)))
-        },
-        Ok(data) => Ok(data)
-    }
+    format::read(&buffer).map_err(|err| {
+        error!("  Error loading {}: {}", filepath.display(), err);
+        err
+    })
 }
 
-fn blocking_write(filepath: &std::path::Path, serialized: Vec<u8>) -> Result<(), StoreError> {
+/// Replaces the file at `filepath` durably, through a temporary file.
+fn blocking_write(filepath: &std::path::Path, serialized: &[u8]) -> Result<(), StoreError> {
     debug!("blocking_write: {:?}", filepath);
 
     let tmp_path = filepath.with_extension("tmp");
@@ -272,7 +385,8 @@ fn blocking_write(filepath: &std::path::Path, serialized: Vec<u8>) -> Result<(),
         Ok(file) => file,
     };
 
-    if let Err(err) = file.write_all(&serialized) {
+    // Logs covered by the data are discarded once written, so it must be durable first
+    if let Err(err) = file.write_all(serialized).and_then(|_| file.sync_all()) {
         return Err(StoreError::WriteError(Box::new(err)));
     }
 
@@ -280,10 +394,167 @@ fn blocking_write(filepath: &std::path::Path, serialized: Vec<u8>) -> Result<(),
         return Err(StoreError::WriteError(Box::new(err)));
     }
 
+    blocking_sync_dir(filepath.parent().unwrap())
+}
+
+/// Makes renames and new files in `dir` durable.
+fn blocking_sync_dir(dir: &std::path::Path) -> Result<(), StoreError> {
+    File::open(dir).and_then(|dir| dir.sync_all()).map_err(|err| StoreError::WriteError(Box::new(err)))
+}
+
+/// Reads the manifest in `dir`, if any. An incomplete record at the end is ignored.
+pub fn blocking_read_manifest(dir: &std::path::Path) -> Result<Option<BTreeMap<String, StoredZone>>, StoreError> {
+    let mut buffer = Vec::new();
+
+    match File::open(dir.join(MANIFEST)) {
+        Err(err) => {
+            if err.kind() == ErrorKind::NotFound {
+                return Ok(None);
+            }
+
+            return Err(StoreError::ReadError(Box::new(err)));
+        },
+        Ok(mut file) => {
+            if let Err(err) = file.read_to_end(&mut buffer) {
+                return Err(StoreError::ReadError(Box::new(err)));
+            }
+        }
+    }
+
+    let mut manifest = BTreeMap::new();
+    let (records, len) = try!(format::decode_records(&buffer));
+
+    if len < buffer.len() as u64 {
+        warn!("Incomplete manifest record at {}", len);
+    }
+
+    for (_, payload) in records {
+        let record: (String, Option<StoredZone>) = try!(bincode::deserialize(payload)
+            .map_err(|err| StoreError::ReadError(Box::new(err))));
+
+        match record {
+            (name, Some(zone)) => manifest.insert(name, zone),
+            (name, None) => manifest.remove(&name)
+        };
+    }
+
+    Ok(Some(manifest))
+}
+
+/// Replaces the manifest in `dir` with one record per Zone.
+pub fn blocking_write_manifest(dir: &std::path::Path, manifest: &BTreeMap<String, StoredZone>) -> Result<(), StoreError> {
+    let mut serialized = vec![];
+
+    for (name, zone) in manifest {
+        let record = try!(bincode::serialize(&(name, Some(zone)), bincode::Infinite)
+            .map_err(|err| StoreError::WriteError(Box::new(err))));
+
+        serialized.extend(format::encode_record(&record));
+    }
+
+    blocking_write(&dir.join(MANIFEST), &serialized)
+}
+
+/// Appends a record to the manifest in `dir`.
+fn blocking_append_manifest(dir: &std::path::Path, record: &(String, Option<StoredZone>)) -> Result<(), StoreError> {
+    let serialized = try!(bincode::serialize(record, bincode::Infinite)
+        .map_err(|err| StoreError::WriteError(Box::new(err))));
+
+    blocking_append(&dir.join(MANIFEST), &format::encode_record(&serialized))
+}
+
+/// Updates `manifest` to match the files in `dir`. Data files missing from it are read, and Zones
+/// without data or logs are removed. Zones only stored as logs are kept with a size of 0.
+pub fn blocking_reconcile(dir: &std::path::Path, manifest: &mut BTreeMap<String, StoredZone>) -> Result<(), StoreError> {
+    let entries = match std::fs::read_dir(dir) {
+        Err(err) => return Err(StoreError::ReadError(Box::new(err))),
+        Ok(entries) => entries
+    };
+
+    let mut data_files = BTreeMap::new();
+    let mut logged = BTreeSet::new();
+
+    for entry in entries {
+        let entry = match entry {
+            Err(err) => return Err(StoreError::ReadError(Box::new(err))),
+            Ok(entry) => entry
+        };
+
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if name.ends_with(&format!(".{}", LOG)) || name.ends_with(&format!(".{}", SAVING_LOG)) {
+            logged.insert(name.splitn(2, '.').next().unwrap().to_string());
+            continue;
+        }
+
+        // Skip temporary files and the manifest itself
+        if name.contains('.') || name == MANIFEST {
+            continue;
+        }
+
+        let metadata = match entry.metadata() {
+            Err(err) => return Err(StoreError::ReadError(Box::new(err))),
+            Ok(metadata) => metadata
+        };
+
+        if ! metadata.is_dir() {
+            data_files.insert(name, metadata);
+        }
+    }
+
+    // Zones that lost their data file may still have logs
+    let names: Vec<String> = manifest.keys().cloned().collect();
+
+    for name in names {
+        if ! data_files.contains_key(&name) {
+            if logged.contains(&name) {
+                let zone = manifest.get_mut(&name).unwrap();
+
+                zone.size = 0;
+                zone.written = 0;
+            }
+            else {
+                manifest.remove(&name);
+            }
+        }
+    }
+
+    for (name, metadata) in data_files {
+        // Sizes differ if the last save was not recorded
+        if manifest.get(&name).map_or(false, |zone| zone.size == metadata.len()) {
+            continue;
+        }
+
+        let written = metadata.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000);
+
+        match blocking_read(&dir.join(&name)) {
+            Err(err) => {
+                error!("Error loading {}: {}", name, err);
+                error!("  {:?}", err);
+            },
+            Ok(data) => {
+                manifest.insert(name, StoredZone {
+                    path: data.path,
+                    size: metadata.len(),
+                    written: written
+                });
+            }
+        }
+    }
+
+    for name in logged {
+        if ! manifest.contains_key(&name) {
+            warn!("Zone {} is only stored as logs, and not in the manifest", name);
+        }
+    }
+
     Ok(())
 }
 
-fn zonefilename(path: &Path) -> String {
+/// Returns the name of the data file of a Zone. Its logs have the same name with an extension.
+pub fn zonefilename(path: &Path) -> String {
     let zonename = path.path.join(".");
     let mut filename = String::from("r");
 
@@ -331,7 +602,7 @@ fn test_read_write() {
     let limit = bincode::Infinite;
     let serialized = bincode::serialize(&data, limit).unwrap();
 
-    blocking_write(&file, serialized).unwrap();
+    blocking_write(&file, &serialized).unwrap();
 
     assert_eq!(blocking_read(&file).unwrap(), data);
 
@@ -349,7 +620,7 @@ fn test_read_write() {
     let limit = bincode::Infinite;
     let serialized = bincode::serialize(&expected, limit).unwrap();
 
-    blocking_write(&file, serialized).unwrap();
+    blocking_write(&file, &serialized).unwrap();
 
     let verify = blocking_read(&file).unwrap();
 
@@ -364,12 +635,7 @@ fn test_list() {
         std::fs::remove_dir_all(dir).unwrap();
     }
 
-    let chan = StoreChannel::new();
-
-    let app = App::new("127.0.0.1:42".parse().unwrap());
-    let store = FS::new(app.handle(), "127.0.0.1:42", chan);
-
-    let noop_zone = ZoneHandle::test_handle(Arc::new(path![]));
+    let store = FS::new("test_data/list");
     let limit = bincode::Infinite;
 
     for i in 0..3 {
@@ -378,22 +644,112 @@ fn test_list() {
 
         let serialized = bincode::serialize(&zone_data, limit).unwrap();
 
-        store.write(noop_zone.clone(), path, serialized);
+        store.write(&path, &serialized).unwrap();
     }
 
-    std::thread::sleep(std::time::Duration::from_millis(200));
+    // Zones only stored as logs are listed too
+    store.append(&path![moo], &[]).unwrap();
 
-    let (tx, rx) = channel();
+    let paths = |store: &FS| {
+        let mut paths: Vec<Path> = store.list().unwrap().into_iter().map(|z| z.path).collect();
 
-    store.list(tx);
-
-    let mut paths: Vec<Path> = rx.iter().collect();
-
-    paths.sort();
+        paths.sort();
+        paths
+    };
 
-    assert_eq!(paths, [
+    assert_eq!(paths(&store), [
         Path::new(vec!["0".into()]),
         Path::new(vec!["1".into()]),
         Path::new(vec!["2".into()]),
+        path![moo]
     ]);
+
+    // Manifest is persisted
+    store.delete(&Path::new(vec!["1".into()])).unwrap();
+
+    let expected = [Path::new(vec!["0".into()]), Path::new(vec!["2".into()]), path![moo]];
+
+    assert_eq!(paths(&FS::new("test_data/list")), expected);
+
+    // Saves not recorded in the manifest are found at startup
+    let zone_data = ZoneData::new(path![cow], Default::default());
+
+    blocking_write(&store.zonepath(&path![cow]), &format::encode(&bincode::serialize(&zone_data, limit).unwrap())).unwrap();
+
+    let reconciled = FS::new("test_data/list");
+
+    assert_eq!(paths(&reconciled), [Path::new(vec!["0".into()]), Path::new(vec!["2".into()]), path![cow], path![moo]]);
+    assert!(reconciled.list().unwrap().iter().all(|z| (z.size > 0 && z.written > 0) || z.path == path![moo]));
+
+    // Without a manifest, only Zones with data files are found
+    std::fs::remove_file("test_data/list/manifest").unwrap();
+
+    assert_eq!(paths(&FS::new("test_data/list")), [Path::new(vec!["0".into()]), Path::new(vec!["2".into()]), path![cow]]);
+}
+
+#[test]
+fn test_log() {
+    use node::{Node, Vis};
+    use serde_json;
+
+    let dir = std::path::PathBuf::from("test_data/log");
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    DirBuilder::new().recursive(true).create(&dir).unwrap();
+
+    let mut file = dir.clone();
+
+    file.push("test_log");
+
+    let logpath = file.with_extension(LOG);
+    let savingpath = file.with_extension(SAVING_LOG);
+
+    let append = |key: &str, json: &str, ts: u64| {
+        let diff = Node::expand_from(&[key.to_string()], serde_json::from_str(json).unwrap(), ts).noop_vis();
+        let serialized = bincode::serialize(&diff, bincode::Infinite).unwrap();
+
+        blocking_append(&logpath, &format::encode_record(&serialized)).unwrap();
+    };
+
+    let root = Node::expand(serde_json::from_str("{}").unwrap(), 1).noop_vis();
+
+    blocking_append(&logpath, &format::encode_record(&bincode::serialize(&root, bincode::Infinite).unwrap())).unwrap();
+    append("moo", "1", 1000);
+    blocking_rotate(&logpath, &savingpath).unwrap();
+
+    // Diffs of a failed snapshot write are kept
+    append("cow", "2", 2000);
+    blocking_rotate(&logpath, &savingpath).unwrap();
+
+    append("moo", "3", 3000);
+
+    let mut data = blocking_load(&file).unwrap();
+
+    data.tree.vis = Vis::permanent();
+
+    assert_eq!(data.tree.get(&path!(moo)), (::value::Value::I64(3), 3000));
+    assert_eq!(data.tree.get(&path!(cow)), (::value::Value::I64(2), 2000));
+
+    // Incomplete diff at the end is cut off
+    let len = std::fs::metadata(&logpath).unwrap().len();
+
+    blocking_append(&logpath, &[1, 2, 3]).unwrap();
+    assert_eq!(blocking_read_log(&logpath, true).unwrap().len(), 1);
+    assert_eq!(std::fs::metadata(&logpath).unwrap().len(), len);
+
+    // Damage before the last diff is an error, later diffs are not cut off
+    append("cow", "4", 4000);
+
+    let len = std::fs::metadata(&logpath).unwrap().len();
+    let mut buffer = vec![];
+
+    File::open(&logpath).unwrap().read_to_end(&mut buffer).unwrap();
+    buffer[format::RECORD_HEADER_SIZE] ^= 0xff;
+    File::create(&logpath).unwrap().write_all(&buffer).unwrap();
+
+    assert!(blocking_read_log(&logpath, true).is_err());
+    assert_eq!(std::fs::metadata(&logpath).unwrap().len(), len);
 }
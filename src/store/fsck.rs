@@ -0,0 +1,363 @@
+//! Offline checks and repairs of `FS` data directories
+//!
+//! `check` validates every data file and log in a directory, and checks that delegations in each
+//! Zone match the Zone files found. `repair` fixes what it can: torn logs are truncated, broken or
+//! orphaned files are moved to a quarantine directory, and the manifest is rebuilt.
+//!
+//! Nodes must not be running on a directory being checked. Directories of the log-structured store
+//! are not supported.
+
+use std;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{DirBuilder, File};
+use std::io::prelude::*;
+
+use super::StoreError;
+use super::fs::{self, LOG, MANIFEST, SAVING_LOG};
+use node::NodeTree;
+use path::{is_bucket, Path};
+
+/// Directory within a data directory that files are moved to
+pub const QUARANTINE: &'static str = "quarantine";
+
+/// A problem found in a data directory
+#[derive(Debug, PartialEq)]
+pub enum Issue {
+    Corrupt(String, String),   // Data file that cannot be read, with the error
+    Misplaced(String, Path),   // Data file not named after its Zone
+    TornLog(String),           // Log with an incomplete diff at the end
+    Temporary(String),         // Leftover of an interrupted write
+    Orphaned(String, Path),    // Zone that no parent delegates to
+    Missing(Path),             // Delegated Zone without data or logs
+    Manifest                   // Manifest does not match data files
+}
+
+/// Outcome of a check
+#[derive(Debug, Default)]
+pub struct Report {
+    pub zones: usize,
+    pub logs: usize,
+    pub issues: Vec<Issue>
+}
+
+/// Checks the data directory `dir`.
+pub fn check(dir: &str) -> Result<Report, StoreError> {
+    let dir = std::path::Path::new(dir);
+    let mut report: Report = Default::default();
+
+    let mut data_files = vec![];
+    let mut log_files = vec![];
+
+    for name in try!(file_names(dir)) {
+        if name == MANIFEST {
+            continue;
+        }
+
+        if name.ends_with(".seg") {
+            return Err(StoreError::ReadError(format!("{} holds log store segments, not FS data files", dir.display()).into()));
+        }
+
+        if name.ends_with(".tmp") {
+            report.issues.push(Issue::Temporary(name));
+        }
+        else if name.ends_with(&format!(".{}", LOG)) || name.ends_with(&format!(".{}", SAVING_LOG)) {
+            log_files.push(name);
+        }
+        else if ! name.contains('.') {
+            data_files.push(name);
+        }
+    }
+
+    // Zones are known by name even if only their logs are stored, e.g. before their first save
+    let mut stored: BTreeSet<String> = data_files.iter().cloned().collect();
+    let mut logged = BTreeSet::new();
+
+    for name in log_files {
+        let mut buffer = vec![];
+
+        try!(File::open(dir.join(&name)).and_then(|mut file| file.read_to_end(&mut buffer))
+            .map_err(|err| StoreError::ReadError(Box::new(err))));
+
+        match fs::parse_log(&buffer) {
+            Err(err) => report.issues.push(Issue::Corrupt(name.clone(), err.to_string())),
+            Ok((_, len)) => if len < buffer.len() as u64 {
+                report.issues.push(Issue::TornLog(name.clone()));
+            }
+        }
+
+        let zone = name.splitn(2, '.').next().unwrap().to_string();
+
+        stored.insert(zone.clone());
+        logged.insert(zone);
+        report.logs += 1;
+    }
+
+    let mut zones: BTreeMap<Path, NodeTree> = BTreeMap::new();
+    let mut valid = BTreeSet::new();
+
+    // Zones whose data can't be read, they may delegate to any child
+    let mut unreadable = BTreeSet::new();
+
+    for name in data_files {
+        let filepath = dir.join(&name);
+
+        let data = match fs::blocking_read(&filepath) {
+            Err(err) => {
+                unreadable.insert(name.clone());
+                report.issues.push(Issue::Corrupt(name, err.to_string()));
+                continue;
+            },
+            Ok(data) => data
+        };
+
+        // Its Zone isn't missing, repair moves the file into place
+        if fs::zonefilename(&data.path) != name {
+            stored.insert(fs::zonefilename(&data.path));
+            unreadable.insert(fs::zonefilename(&data.path));
+            report.issues.push(Issue::Misplaced(name, data.path));
+            continue;
+        }
+
+        // Delegations made since the last save are only in the logs
+        match fs::blocking_load(&filepath) {
+            Err(err) => {
+                unreadable.insert(name.clone());
+                report.issues.push(Issue::Corrupt(name, err.to_string()));
+            },
+            Ok(data) => {
+                logged.remove(&name);
+                valid.insert(name);
+                zones.insert(data.path, data.tree);
+            }
+        }
+    }
+
+    report.zones = zones.len() + logged.len();
+
+    for (path, tree) in zones.iter() {
+        // Delegated Zones without data or logs lost their data. Buckets are delegated up front,
+        // so empty buckets are expected.
+        for delegation in tree.node.delegations() {
+            let mut child = path.clone();
+
+            child.append(&mut delegation.clone());
+
+            if ! is_bucket(child.path.last().unwrap()) && ! stored.contains(&fs::zonefilename(&child)) {
+                report.issues.push(Issue::Missing(child));
+            }
+        }
+
+        if path.len() > 0 && ! delegated(path, &zones, &logged, &unreadable) {
+            report.issues.push(Issue::Orphaned(fs::zonefilename(path), path.clone()));
+        }
+    }
+
+    let manifest: Option<BTreeSet<String>> = match fs::blocking_read_manifest(dir) {
+        Err(_) => None,
+        Ok(manifest) => manifest.map(|m| m.keys().cloned().collect())
+    };
+
+    // Zones only stored as logs may be listed too
+    let matches = manifest.map_or(false, |manifest| {
+        valid.is_subset(&manifest) && manifest.iter().all(|name| valid.contains(name) || logged.contains(name))
+    });
+
+    if ! matches {
+        report.issues.push(Issue::Manifest);
+    }
+
+    Ok(report)
+}
+
+/// Repairs issues found by `check` in the data directory `dir`. Returns issues that could not be
+/// repaired.
+pub fn repair(dir: &str, issues: Vec<Issue>) -> Result<Vec<Issue>, StoreError> {
+    let dir = std::path::Path::new(dir);
+    let mut unrepaired = vec![];
+
+    for issue in issues {
+        match issue {
+            Issue::Corrupt(ref name, _) |
+            Issue::Orphaned(ref name, _) => try!(quarantine_zone(dir, name)),
+            Issue::Misplaced(ref name, ref path) => {
+                let filename = fs::zonefilename(path);
+
+                if dir.join(&filename).exists() {
+                    try!(quarantine_zone(dir, name));
+                }
+                else {
+                    try!(std::fs::rename(dir.join(name), dir.join(&filename))
+                        .map_err(|err| StoreError::WriteError(Box::new(err))));
+                }
+            },
+            Issue::TornLog(ref name) => {
+                try!(fs::blocking_read_log(&dir.join(name), true));
+            },
+            Issue::Temporary(ref name) => try!(quarantine(dir, name)),
+            Issue::Missing(_) => unrepaired.push(issue),
+            Issue::Manifest => {}
+        }
+    }
+
+    // Repairs may have changed the data files, so always reconcile the manifest. Zones only
+    // stored as logs can only be listed from the existing manifest.
+    let mut manifest = fs::blocking_read_manifest(dir).ok().and_then(|manifest| manifest).unwrap_or_default();
+
+    try!(fs::blocking_reconcile(dir, &mut manifest));
+    try!(fs::blocking_write_manifest(dir, &manifest));
+
+    Ok(unrepaired)
+}
+
+/// Returns true if a parent Zone delegates to the Zone at `path`. Parents that are only stored as
+/// logs or whose data can't be read can't be checked, so are assumed to.
+fn delegated(path: &Path, zones: &BTreeMap<Path, NodeTree>, logged: &BTreeSet<String>, unreadable: &BTreeSet<String>) -> bool {
+    (0..path.len()).any(|n| {
+        let mut parent = path.clone();
+
+        parent.truncate(n);
+
+        match zones.get(&parent) {
+            Some(tree) => tree.node.delegations().contains(&path.slice(n)),
+            None => {
+                let name = fs::zonefilename(&parent);
+
+                logged.contains(&name) || unreadable.contains(&name)
+            }
+        }
+    })
+}
+
+/// Moves the data file of a Zone and its logs into quarantine.
+fn quarantine_zone(dir: &std::path::Path, name: &str) -> Result<(), StoreError> {
+    for name in [name.to_string(), format!("{}.{}", name, LOG), format!("{}.{}", name, SAVING_LOG)].iter() {
+        if dir.join(name).exists() {
+            try!(quarantine(dir, name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves a file into quarantine.
+fn quarantine(dir: &std::path::Path, name: &str) -> Result<(), StoreError> {
+    let quarantine = dir.join(QUARANTINE);
+
+    if ! quarantine.is_dir() {
+        try!(DirBuilder::new().create(&quarantine).map_err(|err| StoreError::WriteError(Box::new(err))));
+    }
+
+    std::fs::rename(dir.join(name), quarantine.join(name)).map_err(|err| StoreError::WriteError(Box::new(err)))
+}
+
+/// Lists names of files in `dir`.
+fn file_names(dir: &std::path::Path) -> Result<Vec<String>, StoreError> {
+    let mut names = vec![];
+
+    for entry in try!(std::fs::read_dir(dir).map_err(|err| StoreError::ReadError(Box::new(err)))) {
+        let entry = try!(entry.map_err(|err| StoreError::ReadError(Box::new(err))));
+
+        if entry.path().is_file() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+
+    names.sort();
+
+    Ok(names)
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Issue::Corrupt(ref name, ref err) => write!(f, "{}: corrupt data ({})", name, err),
+            Issue::Misplaced(ref name, ref path) => write!(f, "{}: holds data of {:?}", name, path.path.join(".")),
+            Issue::TornLog(ref name) => write!(f, "{}: incomplete diff at end of log", name),
+            Issue::Temporary(ref name) => write!(f, "{}: leftover of an interrupted write", name),
+            Issue::Orphaned(ref name, ref path) => write!(f, "{}: zone {:?} is not delegated by any parent", name, path.path.join(".")),
+            Issue::Missing(ref path) => write!(f, "zone {:?} is delegated but has no data", path.path.join(".")),
+            Issue::Manifest => write!(f, "{}: does not match data files", MANIFEST)
+        }
+    }
+}
+
+#[test]
+fn test_fsck() {
+    use std::fs::OpenOptions;
+
+    use bincode;
+    use node::Node;
+    use serde_json;
+    use store::Store;
+    use store::fs::FS;
+    use zone::ZoneData;
+
+    let dir = "test_data/fsck";
+
+    if std::path::Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    let store = FS::new(dir);
+    let limit = bincode::Infinite;
+
+    let write = |path: Path, json: &str| {
+        let data = ZoneData::new(path.clone(), Node::expand(serde_json::from_str(json).unwrap(), 1000).noop_vis());
+
+        store.write(&path, &bincode::serialize(&data, limit).unwrap()).unwrap();
+    };
+
+    // Root delegates "moo", "cow" and "sheep", "cow" is only logged, "sheep" delegates "lamb"
+    let mut root = Node::expand(serde_json::from_str(r#"{ "pig": 1 }"#).unwrap(), 1000);
+    let mut sheep = Node::expand(serde_json::from_str("{}").unwrap(), 1000);
+
+    root.add_child("moo".into(), Node::delegate(2000));
+    root.add_child("cow".into(), Node::delegate(2000));
+    root.add_child("sheep".into(), Node::delegate(2000));
+    sheep.add_child("lamb".into(), Node::delegate(2000));
+
+    store.write(&path!(), &bincode::serialize(&ZoneData::new(path!(), root.noop_vis()), limit).unwrap()).unwrap();
+    write(path!(moo), r#"{ "a": 1 }"#);
+    write(path!(sheep.lamb), r#"{ "c": 3 }"#);
+    store.write(&path!(sheep), &bincode::serialize(&ZoneData::new(path!(sheep), sheep.noop_vis()), limit).unwrap()).unwrap();
+    store.append(&path!(cow), &bincode::serialize(&Node::expand_from(&["b".to_string()], serde_json::from_str("2").unwrap(), 1000).noop_vis(), limit).unwrap()).unwrap();
+
+    let report = check(dir).unwrap();
+
+    assert_eq!(report.zones, 5);
+    assert_eq!(report.issues, []);
+
+    // Break things
+    write(path!(dog), "{}");
+    OpenOptions::new().create(true).append(true).open(format!("{}/{}.{}", dir, fs::zonefilename(&path!(moo)), LOG)).unwrap()
+        .write_all(&[1, 2, 3]).unwrap();
+    File::create(format!("{}/{}.tmp", dir, fs::zonefilename(&path!(moo)))).unwrap();
+    std::fs::remove_file(format!("{}/{}.{}", dir, fs::zonefilename(&path!(cow)), LOG)).unwrap();
+    File::create(format!("{}/rcorrupt_0", dir)).unwrap().write_all(b"moo").unwrap();
+    std::fs::remove_file(format!("{}/{}", dir, MANIFEST)).unwrap();
+
+    // Children of a parent that can't be read aren't orphaned
+    std::fs::rename(format!("{}/{}", dir, fs::zonefilename(&path!(sheep))), format!("{}/rmisplaced_0", dir)).unwrap();
+
+    let issues = check(dir).unwrap().issues;
+
+    assert!(issues.contains(&Issue::Orphaned(fs::zonefilename(&path!(dog)), path!(dog))));
+    assert!(issues.contains(&Issue::TornLog(format!("{}.{}", fs::zonefilename(&path!(moo)), LOG))));
+    assert!(issues.contains(&Issue::Temporary(format!("{}.tmp", fs::zonefilename(&path!(moo))))));
+    assert!(issues.contains(&Issue::Missing(path!(cow))));
+    assert!(issues.iter().any(|i| match *i { Issue::Corrupt(ref name, _) => name == "rcorrupt_0", _ => false }));
+    assert!(issues.contains(&Issue::Misplaced("rmisplaced_0".into(), path!(sheep))));
+    assert!(issues.contains(&Issue::Manifest));
+    assert_eq!(issues.len(), 7);
+
+    // Only lost data can't be repaired
+    assert_eq!(repair(dir, issues).unwrap(), [Issue::Missing(path!(cow))]);
+    assert_eq!(check(dir).unwrap().issues, [Issue::Missing(path!(cow))]);
+    assert!(std::path::Path::new(&format!("{}/{}/rcorrupt_0", dir, QUARANTINE)).exists());
+
+    // Log store directories are rejected
+    File::create(format!("{}/{:016}.seg", dir, 0)).unwrap();
+
+    assert!(check(dir).is_err());
+}
@@ -0,0 +1,618 @@
+//! A log-structured zone store.
+//!
+//! Zone data and logged diffs are appended as records to segment files, keeping the number of
+//! files low regardless of the number of Zones. An in-memory index, rebuilt from the segments on
+//! startup, maps each Zone `Path` to the location of its records. Records are framed and
+//! checksummed by the `format` module, so only an incomplete record at the end is cut off.
+//!
+//! Closed segments that are mostly superseded are compacted in the background: live records are
+//! copied to the active segment and the old segment is removed. Copied diffs may be replayed more
+//! than once after a restart, which is harmless as merges are idempotent. Deletions are copied
+//! while older segments still hold records they hide, unless their Zone was written again since,
+//! in which case the segment is kept until those older segments are gone.
+
+use std;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::SeekFrom;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use bincode;
+
+use super::*;
+use clock;
+use node::NodeTree;
+use path::Path;
+use zone::ZoneData;
+
+/// Active segment is closed once larger than this (in bytes)
+const MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Interval between compaction checks (in seconds)
+const COMPACT_INTERVAL: u64 = 60;
+
+pub struct Log {
+    state: Arc<Mutex<State>>
+}
+
+struct State {
+    dir: std::path::PathBuf,
+    index: BTreeMap<Path, Entry>,
+    segments: Vec<u64>,     // Segment ids, oldest first. The last one is active.
+    active: File,
+    active_size: u64,
+    max_segment_size: u64
+}
+
+/// Locations of the records of a Zone
+#[derive(Default)]
+struct Entry {
+    data: Option<Location>,
+    written: u64,         // Wall clock time of the last write (ms)
+    diffs: Vec<Location>, // Logged diffs
+    saving: Vec<Location> // Logged diffs set aside for a write in progress
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u64
+}
+
+#[derive(Deserialize, Serialize)]
+enum Record {
+    Data(Path, u64, ZoneData), // Written at wall clock time (ms)
+    Diff(Path, NodeTree),
+    Rotate(Path),
+    Truncate(Path),
+    Delete(Path)
+}
+
+impl Log {
+    pub fn new(dir: &str) -> Result<Log, StoreError> {
+        let state = try!(State::open(dir, MAX_SEGMENT_SIZE));
+        let state = Arc::new(Mutex::new(state));
+        let compacting = Arc::downgrade(&state);
+
+        thread::spawn(move|| {
+            loop {
+                thread::sleep(Duration::from_secs(COMPACT_INTERVAL));
+
+                // Stop once the store is gone
+                let state = match compacting.upgrade() {
+                    Some(state) => state,
+                    None => return
+                };
+
+                let compacted = compact(&state);
+
+                if let Err(err) = compacted {
+                    error!("Error compacting log store: {}", err);
+                }
+            }
+        });
+
+        Ok(Log { state: state })
+    }
+}
+
+impl Store for Log {
+    fn delete(&self, path: &Path) -> Result<(), StoreError> {
+        try!(self.state.lock().unwrap().append(&Record::Delete(path.clone())));
+
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<StoredZone>, StoreError> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.index.iter().map(|(path, entry)| {
+            StoredZone {
+                path: path.clone(),
+                size: entry.data.map_or(0, |location| location.len),
+                written: entry.written
+            }
+        }).collect())
+    }
+
+    fn load(&self, path: &Path) -> Result<ZoneData, StoreError> {
+        // Segments are read without holding the lock
+        loop {
+            let (dir, locations) = {
+                let state = self.state.lock().unwrap();
+
+                (state.dir.clone(), state.locations(path))
+            };
+
+            match load(&dir, locations.0, &locations.1) {
+                Ok(data) => return Ok(data),
+                Err(err) => {
+                    // Retry if compaction moved the records meanwhile
+                    if self.state.lock().unwrap().locations(path) == locations {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), StoreError> {
+        let data = try!(bincode::deserialize(data).map_err(|err| StoreError::OtherError(Box::new(err))));
+        let mut state = self.state.lock().unwrap();
+
+        try!(state.append(&Record::Data(path.clone(), clock::wall_millis(), data)));
+
+        // Logged diffs are truncated once this returns
+        state.sync()
+    }
+
+    fn append(&self, path: &Path, diff: &[u8]) -> Result<(), StoreError> {
+        let diff = try!(bincode::deserialize(diff).map_err(|err| StoreError::OtherError(Box::new(err))));
+
+        try!(self.state.lock().unwrap().append(&Record::Diff(path.clone(), diff)));
+
+        Ok(())
+    }
+
+    fn rotate_log(&self, path: &Path) -> Result<(), StoreError> {
+        try!(self.state.lock().unwrap().append(&Record::Rotate(path.clone())));
+
+        Ok(())
+    }
+
+    fn sync_log(&self, _: &Path) -> Result<(), StoreError> {
+        self.state.lock().unwrap().sync()
+    }
+
+    fn truncate_log(&self, path: &Path) -> Result<(), StoreError> {
+        try!(self.state.lock().unwrap().append(&Record::Truncate(path.clone())));
+
+        Ok(())
+    }
+}
+
+impl State {
+    /// Opens segments in `dir` and rebuilds the index.
+    fn open(dir: &str, max_segment_size: u64) -> Result<State, StoreError> {
+        let dir = std::path::PathBuf::from(dir);
+
+        if ! dir.is_dir() {
+            try!(DirBuilder::new().recursive(true).create(&dir).map_err(|err| StoreError::WriteError(Box::new(err))));
+        }
+
+        let mut segments = vec![];
+
+        for entry in try!(std::fs::read_dir(&dir).map_err(|err| StoreError::ReadError(Box::new(err)))) {
+            let entry = try!(entry.map_err(|err| StoreError::ReadError(Box::new(err))));
+            let entry = entry.path();
+
+            if entry.extension().map_or(false, |e| e == "seg") {
+                if let Some(id) = entry.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                    segments.push(id);
+                }
+            }
+        }
+
+        segments.sort();
+
+        if segments.is_empty() {
+            segments.push(0);
+        }
+
+        let active_id = *segments.last().unwrap();
+        let active_path = segment_path(&dir, active_id);
+
+        let active = try!(OpenOptions::new().create(true).append(true).open(&active_path)
+            .map_err(|err| StoreError::WriteError(Box::new(err))));
+
+        let mut state = State {
+            dir: dir,
+            index: BTreeMap::new(),
+            segments: segments.clone(),
+            active: active,
+            active_size: 0,
+            max_segment_size: max_segment_size
+        };
+
+        for &segment in &segments {
+            let mut end = 0;
+
+            for (record, location) in try!(records(&state.dir, segment)) {
+                state.apply(&record, location);
+                end = location.offset + location.len;
+            }
+
+            if segment == active_id {
+                // Cut off an incomplete record, e.g. from a crash during an append
+                try!(state.active.set_len(end).map_err(|err| StoreError::WriteError(Box::new(err))));
+                state.active_size = end;
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Updates the index with a record written at `location`.
+    fn apply(&mut self, record: &Record, location: Location) {
+        match *record {
+            Record::Data(ref path, written, _) => {
+                let entry = self.index.entry(path.clone()).or_insert_with(Default::default);
+
+                entry.data = Some(location);
+                entry.written = written;
+            },
+            Record::Diff(ref path, _) => {
+                self.index.entry(path.clone()).or_insert_with(Default::default).diffs.push(location);
+            },
+            Record::Rotate(ref path) => {
+                if let Some(entry) = self.index.get_mut(path) {
+                    let mut diffs = std::mem::replace(&mut entry.diffs, vec![]);
+
+                    entry.saving.append(&mut diffs);
+                }
+            },
+            Record::Truncate(ref path) => {
+                if let Some(entry) = self.index.get_mut(path) {
+                    entry.saving.clear();
+                }
+            },
+            Record::Delete(ref path) => {
+                self.index.remove(path);
+            }
+        }
+    }
+
+    /// Appends a record to the active segment and updates the index.
+    fn append(&mut self, record: &Record) -> Result<Location, StoreError> {
+        let serialized = try!(bincode::serialize(record, bincode::Infinite)
+            .map_err(|err| StoreError::WriteError(Box::new(err))));
+
+        let location = try!(self.write(&serialized));
+
+        self.apply(record, location);
+
+        Ok(location)
+    }
+
+    /// Writes a serialized record to the active segment, closing it first if full.
+    fn write(&mut self, serialized: &[u8]) -> Result<Location, StoreError> {
+        let serialized = &format::encode_record(serialized)[..];
+
+        if self.active_size > 0 && self.active_size + serialized.len() as u64 > self.max_segment_size {
+            try!(self.sync());
+
+            let id = self.segments.last().unwrap() + 1;
+
+            self.active = try!(OpenOptions::new().create(true).append(true).open(segment_path(&self.dir, id))
+                .map_err(|err| StoreError::WriteError(Box::new(err))));
+            self.active_size = 0;
+            self.segments.push(id);
+        }
+
+        try!(self.active.write_all(serialized).map_err(|err| StoreError::WriteError(Box::new(err))));
+
+        let location = Location {
+            segment: *self.segments.last().unwrap(),
+            offset: self.active_size,
+            len: serialized.len() as u64
+        };
+
+        self.active_size += location.len;
+
+        Ok(location)
+    }
+
+    fn sync(&self) -> Result<(), StoreError> {
+        self.active.sync_data().map_err(|err| StoreError::WriteError(Box::new(err)))
+    }
+
+    /// Returns the location of the data of a Zone and of its logged diffs, in order.
+    fn locations(&self, path: &Path) -> (Option<Location>, Vec<Location>) {
+        match self.index.get(path) {
+            Some(entry) => (entry.data, entry.saving.iter().chain(entry.diffs.iter()).cloned().collect()),
+            None => (None, vec![])
+        }
+    }
+
+    /// Returns the size of live records per segment.
+    fn live(&self) -> BTreeMap<u64, u64> {
+        let mut live = BTreeMap::new();
+
+        for entry in self.index.values() {
+            for location in entry.data.iter().chain(entry.diffs.iter()).chain(entry.saving.iter()) {
+                *live.entry(location.segment).or_insert(0) += location.len;
+            }
+        }
+
+        live
+    }
+
+    /// Returns true if the index points to the record of `path` at `location`.
+    fn is_live(&self, path: &Path, location: Location) -> bool {
+        self.index.get(path).map_or(false, |e| e.data == Some(location) || e.diffs.contains(&location) || e.saving.contains(&location))
+    }
+
+    /// Points the index entry of `path` at `from` to `to`.
+    fn relocate(&mut self, path: &Path, from: Location, to: Location) {
+        if let Some(entry) = self.index.get_mut(path) {
+            if entry.data == Some(from) {
+                entry.data = Some(to);
+            }
+
+            for location in entry.diffs.iter_mut().chain(entry.saving.iter_mut()) {
+                if *location == from {
+                    *location = to;
+                }
+            }
+        }
+    }
+}
+
+impl Record {
+    fn path(&self) -> &Path {
+        match *self {
+            Record::Data(ref path, _, _) |
+            Record::Diff(ref path, _) |
+            Record::Rotate(ref path) |
+            Record::Truncate(ref path) |
+            Record::Delete(ref path) => path
+        }
+    }
+}
+
+/// Compacts closed segments where less than half of the data is still live. Segments are read
+/// without holding the lock, which is only taken to copy live records.
+fn compact(state: &Mutex<State>) -> Result<(), StoreError> {
+    let (dir, mut closed, live) = {
+        let state = state.lock().unwrap();
+
+        (state.dir.clone(), state.segments[..state.segments.len() - 1].to_vec(), state.live())
+    };
+
+    for segment in closed.clone() {
+        let size = try!(std::fs::metadata(segment_path(&dir, segment))
+            .map_err(|err| StoreError::ReadError(Box::new(err)))).len();
+
+        if live.get(&segment).cloned().unwrap_or(0) * 2 < size {
+            let older: Vec<u64> = closed.iter().cloned().filter(|&s| s < segment).collect();
+
+            if try!(compact_segment(state, &dir, segment, &older)) {
+                closed.retain(|&s| s != segment);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies live records of a closed segment to the active segment, then removes it. Returns false
+/// if the segment is kept, because a deletion in it must stay in place.
+fn compact_segment(state: &Mutex<State>, dir: &std::path::Path, segment: u64, older: &[u64]) -> Result<bool, StoreError> {
+    debug!("Compacting segment {}", segment);
+
+    let copied = try!(records(dir, segment));
+
+    // Deletions are only needed while `older` segments hold records they hide
+    let deleted: BTreeSet<&Path> = copied.iter().filter_map(|&(ref record, _)| match *record {
+        Record::Delete(ref path) => Some(path),
+        _ => None
+    }).collect();
+
+    let mut hidden = BTreeSet::new();
+
+    if ! deleted.is_empty() {
+        for &older in older {
+            for (record, _) in try!(records(dir, older)) {
+                if deleted.contains(record.path()) {
+                    hidden.insert(record.path().clone());
+                }
+            }
+        }
+    }
+
+    {
+        let mut state = state.lock().unwrap();
+
+        // Copied to the end, a deletion would also hide records of its Zone written since
+        if hidden.iter().any(|path| state.index.contains_key(path)) {
+            debug!("Keeping segment {} until older segments are compacted", segment);
+            return Ok(false);
+        }
+
+        for &(ref record, location) in &copied {
+            let live = match *record {
+                Record::Delete(ref path) => hidden.contains(path),
+                Record::Rotate(_) | Record::Truncate(_) => false,
+                _ => state.is_live(record.path(), location)
+            };
+
+            if live {
+                let serialized = try!(bincode::serialize(record, bincode::Infinite)
+                    .map_err(|err| StoreError::WriteError(Box::new(err))));
+
+                let relocated = try!(state.write(&serialized));
+
+                state.relocate(record.path(), location, relocated);
+            }
+        }
+
+        try!(state.sync());
+
+        state.segments.retain(|&s| s != segment);
+    }
+
+    try!(std::fs::remove_file(segment_path(dir, segment)).map_err(|err| StoreError::WriteError(Box::new(err))));
+
+    Ok(true)
+}
+
+/// Reads the record at `location`.
+fn read(dir: &std::path::Path, location: Location) -> Result<Record, StoreError> {
+    let mut buffer = vec![0; location.len as usize];
+
+    try!(File::open(segment_path(dir, location.segment))
+        .and_then(|mut file| file.seek(SeekFrom::Start(location.offset)).map(|_| file))
+        .and_then(|mut file| file.read_exact(&mut buffer))
+        .map_err(|err| StoreError::ReadError(Box::new(err))));
+
+    let payload = try!(format::decode_record(&buffer));
+
+    bincode::deserialize(payload).map_err(|err| StoreError::ReadError(Box::new(err)))
+}
+
+/// Reads data of a Zone at `data` and replays its logged `diffs` on top.
+fn load(dir: &std::path::Path, data: Option<Location>, diffs: &[Location]) -> Result<ZoneData, StoreError> {
+    let mut loaded = match data {
+        Some(location) => match try!(read(dir, location)) {
+            Record::Data(_, _, data) => data,
+            _ => return Err(StoreError::OtherError("Index does not point to data".into()))
+        },
+        None => Default::default()
+    };
+
+    for &location in diffs {
+        if let Record::Diff(_, mut diff) = try!(read(dir, location)) {
+            // Delegated data was passed on to other Zones when first merged
+            loaded.tree.merge(&mut diff);
+        }
+    }
+
+    Ok(loaded)
+}
+
+/// Reads all complete records of a segment. Damaged records are an error.
+fn records(dir: &std::path::Path, segment: u64) -> Result<Vec<(Record, Location)>, StoreError> {
+    let mut buffer = Vec::new();
+
+    try!(File::open(segment_path(dir, segment))
+        .and_then(|mut file| file.read_to_end(&mut buffer))
+        .map_err(|err| StoreError::ReadError(Box::new(err))));
+
+    let (payloads, len) = try!(format::decode_records(&buffer).map_err(|err| {
+        error!("Damaged segment {}: {}", segment, err);
+        err
+    }));
+
+    if len < buffer.len() as u64 {
+        warn!("Incomplete record in segment {} at {}", segment, len);
+    }
+
+    let mut records = vec![];
+
+    for (offset, payload) in payloads {
+        let record = try!(bincode::deserialize(payload).map_err(|err| StoreError::ReadError(Box::new(err))));
+        let location = Location {
+            segment: segment,
+            offset: offset,
+            len: (format::RECORD_HEADER_SIZE + payload.len()) as u64
+        };
+
+        records.push((record, location));
+    }
+
+    Ok(records)
+}
+
+fn segment_path(dir: &std::path::Path, segment: u64) -> std::path::PathBuf {
+    let mut filepath = dir.to_path_buf();
+
+    filepath.push(format!("{:016}.seg", segment));
+    filepath
+}
+
+#[test]
+fn test_log_store() {
+    use node::{Node, Vis};
+    use serde_json;
+
+    let dir = "test_data/log_store";
+
+    if std::path::Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    let store = Log::new(dir).unwrap();
+    let limit = bincode::Infinite;
+
+    let data = ZoneData::new(path![moo], Node::expand(serde_json::from_str(r#"{ "a": 1 }"#).unwrap(), 1000).noop_vis());
+    let diff = Node::expand_from(&["b".to_string()], serde_json::from_str("2").unwrap(), 2000).noop_vis();
+
+    store.write(&path![moo], &bincode::serialize(&data, limit).unwrap()).unwrap();
+    store.rotate_log(&path![moo]).unwrap();
+    store.append(&path![moo], &bincode::serialize(&diff, limit).unwrap()).unwrap();
+    store.write(&path![cow], &bincode::serialize(&data, limit).unwrap()).unwrap();
+    store.delete(&path![cow]).unwrap();
+
+    let check = |store: &Log| {
+        let mut loaded = store.load(&path![moo]).unwrap();
+
+        loaded.tree.vis = Vis::permanent();
+
+        assert_eq!(loaded.tree.get(&path![a]), (::value::Value::I64(1), 1000));
+        assert_eq!(loaded.tree.get(&path![b]), (::value::Value::I64(2), 2000));
+        let zones = store.list().unwrap();
+
+        assert_eq!(zones.len(), 1);
+        assert_eq!(zones[0].path, path![moo]);
+        assert!(zones[0].size > 0 && zones[0].written > 0);
+    };
+
+    check(&store);
+
+    // Index is rebuilt from segments
+    drop(store);
+
+    let store = Log::new(dir).unwrap();
+
+    check(&store);
+
+    // Each record gets its own segment, so all closed segments are compacted
+    store.state.lock().unwrap().max_segment_size = 1;
+    store.write(&path![moo], &bincode::serialize(&data, limit).unwrap()).unwrap();
+    compact(&store.state).unwrap();
+
+    assert_eq!(store.state.lock().unwrap().segments.len(), 2);
+
+    check(&store);
+
+    drop(store);
+
+    check(&Log::new(dir).unwrap());
+
+    // Deletion of a recreated Zone stays in place while an older segment holds the Zone
+    let store = Log::new(dir).unwrap();
+    let recreated = ZoneData::new(path![cow], Node::expand(serde_json::from_str(r#"{ "c": 3 }"#).unwrap(), 3000).noop_vis());
+    let last_segment = |store: &Log| *store.state.lock().unwrap().segments.last().unwrap();
+
+    store.state.lock().unwrap().max_segment_size = 1;
+    store.write(&path![cow], &bincode::serialize(&data, limit).unwrap()).unwrap();
+
+    let written = last_segment(&store);
+
+    store.delete(&path![cow]).unwrap();
+
+    let deleted = last_segment(&store);
+
+    store.write(&path![cow], &bincode::serialize(&recreated, limit).unwrap()).unwrap();
+    store.write(&path![moo], &bincode::serialize(&data, limit).unwrap()).unwrap();
+
+    let dir_path = std::path::Path::new(dir);
+
+    assert_eq!(compact_segment(&store.state, dir_path, deleted, &[written]).unwrap(), false);
+    assert!(store.state.lock().unwrap().segments.contains(&deleted));
+
+    compact(&store.state).unwrap();
+    assert!(! store.state.lock().unwrap().segments.contains(&deleted));
+
+    drop(store);
+
+    let store = Log::new(dir).unwrap();
+    let mut loaded = store.load(&path![cow]).unwrap();
+
+    loaded.tree.vis = Vis::permanent();
+    assert_eq!(loaded.tree.get(&path![c]), (::value::Value::I64(3), 3000));
+}
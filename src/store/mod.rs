@@ -4,19 +4,93 @@
 //!
 //! Zones can load data or request to save data. When requesting to save data, `Store` will notify
 //! the Zone when it is not busy, at which point the Zone can send its latest copy of its data.
+//!
+//! Backends implement the `Store` trait and are run as the Store process by `spawn`, which takes
+//! care of threading and of queueing write requests.
+//!
+//! Merged diffs are appended as deltas to a log of the Zone, so Zones only need to save their full
+//! data once in a while. Calls reply once their diffs are appended, durable calls once the log is
+//! synced too. Logs are synced on a thread of their own, so other calls don't wait behind syncs,
+//! and syncs requested meanwhile are batched. Logged diffs are replayed on load and truncated once
+//! the Zone has been saved.
+//!
+//! Backends keep an inventory of stored Zones, so listing them does not need to read their data.
 
+pub mod format;
 pub mod fs;
+pub mod fsck;
+pub mod log;
 pub mod null;
 
+use std::collections::{BTreeMap, VecDeque};
 use std::error::Error;
 use std::fmt;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
 
 use bincode;
+use mioco;
+use threadpool::ThreadPool;
 
+use app::{App, AppHandle, Stats};
+use node::NodeTree;
 use path::Path;
 use zone::{ZoneData, ZoneHandle};
 
+/// Default number of threads each for reads and writes
+pub const NUM_THREADS: usize = 50;
+
+/// A zone persistence backend. Calls block, and are made from many threads at once.
+pub trait Store: Send + Sync {
+    /// Deletes data for a zone path.
+    fn delete(&self, path: &Path) -> Result<(), StoreError>;
+
+    /// Lists zones stored, without reading their data.
+    fn list(&self) -> Result<Vec<StoredZone>, StoreError>;
+
+    /// Reads data for a zone path. Zones without data load empty data.
+    fn load(&self, path: &Path) -> Result<ZoneData, StoreError>;
+
+    /// Saves serialized data for a zone path.
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), StoreError>;
+
+    /// Appends a serialized diff to the log of a zone. Changes since the last save are only
+    /// persisted here.
+    fn append(&self, path: &Path, diff: &[u8]) -> Result<(), StoreError>;
+
+    /// Sets logged diffs of a zone aside before its data is saved.
+    fn rotate_log(&self, path: &Path) -> Result<(), StoreError>;
+
+    /// Discards diffs set aside by `rotate_log`, once the data covering them is saved.
+    fn truncate_log(&self, path: &Path) -> Result<(), StoreError>;
+
+    /// Prepares data of a zone that is about to be loaded, e.g. repairs its log after a crash.
+    fn recover(&self, _path: &Path) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Waits for logged diffs of a zone to be durable.
+    fn sync_log(&self, _path: &Path) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// The Store "process", running calls against a `Store` backend.
+struct Process<S> {
+    app: AppHandle,
+    store: Arc<S>,
+    rx: Receiver<StoreCall>,
+
+    read_pool: ThreadPool,
+    write_pool: ThreadPool,
+
+    write_queue: Arc<Mutex<VecDeque<ZoneHandle>>>,
+
+    sync_tx: Sender<(Path, mioco::sync::mpsc::Sender<Result<(), String>>)>,
+    failed: Arc<Mutex<BTreeMap<Path, String>>> // Zones whose diffs could not be logged since the last sync
+}
+
 /// A handle to the Store process. This is the shareable public interface.
 #[derive(Clone)]
 pub struct StoreHandle {
@@ -29,12 +103,25 @@ pub struct StoreChannel {
     tx: Sender<StoreCall>
 }
 
+/// A zone as stored by a backend
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct StoredZone {
+    pub path: Path,
+    pub size: u64,   // Size of saved data (bytes)
+    pub written: u64 // Wall clock time of the last save (ms), 0 if never saved
+}
+
 /// Used for dispatching calls via message passing.
 pub enum StoreCall {
-    List(Sender<Path>),
+    Append(Path, Vec<u8>, mioco::sync::mpsc::Sender<Result<(), String>>),
+    Delete(Path),
+    Flush(Sender<()>),
+    List(Sender<StoredZone>),
     Load(ZoneHandle, Path),
     LoadData(Path, Sender<Option<ZoneData>>),
     RequestWrite(ZoneHandle),
+    SyncLog(Path, mioco::sync::mpsc::Sender<Result<(), String>>),
+    TruncateLog(Path),
     Write(ZoneHandle, Path, Vec<u8>)
 }
 
@@ -46,6 +133,241 @@ pub enum StoreError {
     WriteError(Box<Error>)
 }
 
+/// Start the Store "process" with `store` as backend.
+pub fn spawn<S: Store + 'static>(app: &mut App, store: S) {
+    let channel = app.channels.store.take().expect("Receiver already taken");
+    let process = Process::new(app.handle(), store, channel, app.store_threads);
+
+    thread::spawn(move|| {
+        process.message_loop();
+    });
+}
+
+impl<S: Store + 'static> Process<S> {
+    fn new(app: AppHandle, store: S, store_channel: StoreChannel, threads: usize) -> Process<S> {
+        let store = Arc::new(store);
+        let failed = Arc::new(Mutex::new(BTreeMap::new()));
+        let (sync_tx, sync_rx) = channel();
+
+        {
+            let (store, stats, failed) = (store.clone(), app.stats.clone(), failed.clone());
+
+            thread::spawn(move|| {
+                sync_loop(store, stats, failed, sync_rx);
+            });
+        }
+
+        Process {
+            app: app,
+            store: store,
+            rx: store_channel.rx,
+            read_pool: ThreadPool::new(threads),
+            write_pool: ThreadPool::new(threads),
+            write_queue: Arc::new(Mutex::new(VecDeque::new())),
+            sync_tx: sync_tx,
+            failed: failed
+        }
+    }
+
+    fn message_loop(self) {
+        loop {
+            let call = self.rx.recv().unwrap();
+
+            match call {
+                StoreCall::Append(path, diff, tx) => self.append(path, diff, tx),
+                StoreCall::Delete(path) => self.delete(path),
+                StoreCall::Flush(tx) => self.flush(tx),
+                StoreCall::List(reply) => self.list(reply),
+                StoreCall::Load(zone, path) => self.load(zone, path),
+                StoreCall::LoadData(path, tx) => self.load_data(path, tx),
+                StoreCall::RequestWrite(zone) => self.request_write(zone),
+                StoreCall::SyncLog(path, tx) => self.sync_log(path, tx),
+                StoreCall::TruncateLog(path) => self.truncate_log(path),
+                StoreCall::Write(zone, path, data) => self.write(zone, path, data)
+            }
+        }
+    }
+
+    /// Appends a diff to the write-ahead log of a `Zone`, then notifies `tx`. Appends are made in
+    /// order by the Store process itself, so they always precede the write that covers them.
+    fn append(&self, path: Path, diff: Vec<u8>, tx: mioco::sync::mpsc::Sender<Result<(), String>>) {
+        let result = match self.store.append(&path, &diff) {
+            Err(err) => {
+                error!("Error logging {:?}: {}", path, err);
+                self.app.stats.store.log_errors.increment();
+
+                // Durable calls made since find out on their sync
+                self.failed.lock().unwrap().insert(path.clone(), err.to_string());
+                Err(err.to_string())
+            },
+            Ok(_) => {
+                self.app.stats.store.log_appends.increment();
+                Ok(())
+            }
+        };
+
+        tx.send(result).is_ok(); // ignore if caller goes away
+    }
+
+    /// Deletes data for a `Zone` asynchronously.
+    fn delete(&self, path: Path) {
+        let store = self.store.clone();
+
+        self.write_pool.execute(move|| {
+            debug!("Deleting: {:?}", path);
+
+            if let Err(err) = store.delete(&path) {
+                error!("Error deleting {:?}: {}", path, err);
+            }
+        });
+    }
+
+    /// Waits for pending writes and deletes, then notifies `tx`.
+    fn flush(&self, tx: Sender<()>) {
+        self.write_pool.join();
+
+        tx.send(()).is_ok(); // ignore if caller goes away
+    }
+
+    /// Lists all Zones stored.
+    fn list(&self, tx: Sender<StoredZone>) {
+        match self.store.list() {
+            Err(err) => error!("Error listing zones: {}", err),
+            Ok(zones) => {
+                for zone in zones {
+                    tx.send(zone).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Loads data for a `Zone` asynchronously, notifying its handle when done.
+    fn load(&self, zone: ZoneHandle, path: Path) {
+        let store = self.store.clone();
+        let stats = self.app.stats.clone();
+
+        stats.store.reads_pending.increment();
+
+        self.read_pool.execute(move|| {
+            debug!("Loading: {:?}", path);
+
+            match store.recover(&path).and_then(|_| store.load(&path)) {
+                Err(err) => {
+                    error!("Error loading {:?}: {}", path, err);
+                    error!("{:?}", err);
+                    stats.store.reads_errors.increment();
+                    zone.set_error(err.to_string());
+                },
+                Ok(data) => zone.loaded(data)
+            };
+
+            stats.store.reads_pending.decrement();
+            stats.store.reads.increment();
+        });
+    }
+
+    /// Asynchronously load and send `ZoneData` for `Path` to channel.
+    fn load_data(&self, path: Path, tx: Sender<Option<ZoneData>>) {
+        let store = self.store.clone();
+
+        self.read_pool.execute(move|| {
+            debug!("Loading: {:?}", path);
+
+            tx.send(store.load(&path).ok()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Request for notification to write data.
+    fn request_write(&self, zone: ZoneHandle) {
+        if self.write_pool.active_count() >= self.write_pool.max_count() {
+            // No write slots available, save for later
+            self.write_queue.lock().unwrap().push_back(zone);
+        }
+        else {
+            zone.save();
+        }
+    }
+
+    /// Has diffs logged for a `Zone` so far made durable, then notifies `tx`.
+    fn sync_log(&self, path: Path, tx: mioco::sync::mpsc::Sender<Result<(), String>>) {
+        self.sync_tx.send((path, tx)).unwrap();
+    }
+
+    /// Discards logged diffs covered by the last write of a `Zone`.
+    fn truncate_log(&self, path: Path) {
+        if let Err(err) = self.store.truncate_log(&path) {
+            error!("Error truncating log {:?}: {}", path, err);
+        }
+    }
+
+    /// Write data for a `Zone` asynchronously, notifying its handle when done.
+    fn write(&self, zone: ZoneHandle, path: Path, data: Vec<u8>) {
+        // Diffs logged from now on are not covered by this write, so keep them apart
+        if let Err(err) = self.store.rotate_log(&path) {
+            error!("Error rotating log {:?}: {}", path, err);
+            self.app.stats.store.log_errors.increment();
+        }
+
+        let store = self.store.clone();
+        let pending = self.write_queue.clone();
+        let stats = self.app.stats.clone();
+
+        stats.store.writes_pending.increment();
+
+        self.write_pool.execute(move|| {
+            debug!("Writing: {:?}", path);
+
+            match store.write(&path, &data) {
+                Err(err) => {
+                    error!("Error writing {:?}: {}", path, err);
+                    error!("{:?}", err);
+                    stats.store.writes_errors.increment();
+                    zone.set_error(err.to_string());
+                },
+                Ok(_) => zone.saved()
+            };
+
+            stats.store.writes_pending.decrement();
+            stats.store.writes.increment();
+
+            let mut pending = pending.lock().unwrap();
+
+            // "Wake" any zones waiting to write
+            if let Some(zone) = pending.pop_front() {
+                zone.save();
+            }
+        });
+    }
+}
+
+/// Syncs logs as requested on `rx`. Requests made during a sync are handled together, syncing the
+/// log of each `Zone` once.
+fn sync_loop<S: Store>(store: Arc<S>, stats: Arc<Stats>, failed: Arc<Mutex<BTreeMap<Path, String>>>,
+                       rx: Receiver<(Path, mioco::sync::mpsc::Sender<Result<(), String>>)>) {
+    while let Ok(first) = rx.recv() {
+        let mut batch: BTreeMap<Path, Vec<_>> = BTreeMap::new();
+
+        for (path, tx) in Some(first).into_iter().chain(rx.try_iter()) {
+            batch.entry(path).or_insert_with(Vec::new).push(tx);
+        }
+
+        for (path, txs) in batch {
+            let result = match failed.lock().unwrap().remove(&path) {
+                Some(err) => Err(err),
+                None => store.sync_log(&path).map_err(|err| {
+                    error!("Error syncing log {:?}: {}", path, err);
+                    stats.store.log_errors.increment();
+                    err.to_string()
+                })
+            };
+
+            for tx in txs {
+                tx.send(result.clone()).is_ok(); // ignore if caller goes away
+            }
+        }
+    }
+}
+
 impl StoreChannel {
     pub fn new() -> StoreChannel {
         let (tx, rx) = channel();
@@ -59,17 +381,51 @@ impl StoreChannel {
 }
 
 impl StoreHandle {
+    /// Appends a merged diff to the write-ahead log of a zone. The returned channel is notified
+    /// once appended.
+    pub fn append(&self, path: &Path, diff: &NodeTree) -> mioco::sync::mpsc::Receiver<Result<(), String>> {
+        let limit = bincode::Infinite;
+        let serialized = bincode::serialize(diff, limit).unwrap();
+        let (tx, rx) = mioco::sync::mpsc::channel();
+
+        self.tx.send(StoreCall::Append(path.clone(), serialized, tx)).unwrap();
+        rx
+    }
+
+    /// Deletes stored data for a zone path, e.g. once a `Zone` has been folded into its parent.
+    pub fn delete(&self, path: &Path) {
+        self.tx.send(StoreCall::Delete(path.clone())).unwrap();
+    }
+
+    /// Blocks until writes and deletes requested so far are done.
+    pub fn flush(&self) {
+        let (tx, rx) = channel();
+
+        self.tx.send(StoreCall::Flush(tx)).unwrap();
+
+        rx.recv().unwrap()
+    }
+
     /// Gets a list of Zone Paths stored locally
     pub fn each_zone<F>(&self, mut f: F) where F: FnMut(Path) {
         let (tx, rx) = channel();
 
         self.tx.send(StoreCall::List(tx)).unwrap();
 
-        for p in rx.iter() {
-            f(p)
+        for z in rx.iter() {
+            f(z.path)
         }
     }
 
+    /// Gets the inventory of Zones stored locally
+    pub fn inventory(&self) -> Vec<StoredZone> {
+        let (tx, rx) = channel();
+
+        self.tx.send(StoreCall::List(tx)).unwrap();
+
+        rx.iter().collect()
+    }
+
     /// Reads data for a given zone path and sends data back directly to the `Zone` asynchronously.
     pub fn load(&self, zone: &ZoneHandle, path: &Path) {
         self.tx.send(StoreCall::Load(zone.clone(), path.clone())).unwrap();
@@ -89,6 +445,20 @@ impl StoreHandle {
         self.tx.send(StoreCall::RequestWrite(zone.clone())).unwrap();
     }
 
+    /// Makes all diffs appended for a zone durable. The returned channel is notified once done,
+    /// with an error if diffs could not be logged or synced.
+    pub fn sync_log(&self, path: &Path) -> mioco::sync::mpsc::Receiver<Result<(), String>> {
+        let (tx, rx) = mioco::sync::mpsc::channel();
+
+        self.tx.send(StoreCall::SyncLog(path.clone(), tx)).unwrap();
+        rx
+    }
+
+    /// Discards logged diffs of a zone that are covered by its last saved data.
+    pub fn truncate_log(&self, path: &Path) {
+        self.tx.send(StoreCall::TruncateLog(path.clone())).unwrap();
+    }
+
     /// Saves data for a zone and notifies zone directly via its handle.
     pub fn write(&self, zone: &ZoneHandle, path: &Path, data: &ZoneData) {
         // Optimization: seralize to send over channel instead of cloning ZoneData
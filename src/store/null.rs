@@ -1,77 +1,38 @@
-//! A null store that loads emppty data and ignores writes. For test use only
-
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
+//! A null store that loads empty data and ignores writes. For test use only
 
 use super::*;
 use path::Path;
-use zone::ZoneHandle;
-
-pub struct Null {
-    rx: Receiver<StoreCall>,
-    tx: Sender<StoreCall>,
-}
+use zone::ZoneData;
 
-impl Null {
-    /// Start the Store "process".
-    pub fn spawn() -> StoreHandle {
-        let store = Null::new();
-        let handle = store.handle();
+pub struct Null;
 
-        thread::spawn(move|| {
-            store.message_loop();
-        });
-
-        handle
+impl Store for Null {
+    fn delete(&self, _: &Path) -> Result<(), StoreError> {
+        Ok(())
     }
 
-    pub fn new() -> Null {
-        let (tx, rx) = channel();
-
-        Null { tx: tx, rx: rx }
+    fn list(&self) -> Result<Vec<StoredZone>, StoreError> {
+        Ok(vec![])
     }
 
-    /// Return a handle to Store "process".
-    fn handle(&self) -> StoreHandle {
-        StoreHandle { tx: self.tx.clone() }
+    /// Will always load an empty data set.
+    fn load(&self, _: &Path) -> Result<ZoneData, StoreError> {
+        Ok(Default::default())
     }
 
-    fn message_loop(self) {
-        loop {
-            let call = self.rx.recv().unwrap();
-
-            match call {
-                StoreCall::List(reply) => self.list(reply),
-                StoreCall::Load(zone, path) => self.load(zone, &path),
-                StoreCall::LoadData(path, tx) => self.load_data(path, tx),
-                StoreCall::RequestWrite(zone) => self.request_write(zone),
-                StoreCall::Write(zone, path, data) => self.write(zone, &path, &data)
-            }
-        }
+    fn write(&self, _: &Path, _: &[u8]) -> Result<(), StoreError> {
+        Ok(())
     }
 
-    /// Lists all Zone Paths stored locally
-    pub fn list(&self, _: Sender<Path>) {
+    fn append(&self, _: &Path, _: &[u8]) -> Result<(), StoreError> {
+        Ok(())
     }
 
-    /// Loads data for a `Zone` asynchronously, notifying its handle when done. Will always load an
-    /// empty data set.
-    pub fn load(&self, zone: ZoneHandle, _: &Path) {
-        zone.loaded(Default::default());
+    fn rotate_log(&self, _: &Path) -> Result<(), StoreError> {
+        Ok(())
     }
 
-    /// Asynchronously load and send `ZoneData` for `Path` to channel.
-    pub fn load_data(&self, _path: Path, tx: Sender<Option<ZoneData>>) {
-        tx.send(Some(Default::default())).is_ok(); // ignore if caller goes away
+    fn truncate_log(&self, _: &Path) -> Result<(), StoreError> {
+        Ok(())
     }
-
-    /// Request for notification to write data. Never gonna happen.
-    pub fn request_write(&self, _: ZoneHandle) {
-    }
-
-    /// Write data for a `Zone` asynchronously, notifying its handle when done.
-    /// Not happening either.
-    pub fn write(&self, _: ZoneHandle, _: &Path, _: &Vec<u8>) {
-    }
-
 }
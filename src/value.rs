@@ -1,3 +1,8 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use serde_json::Number;
+
 /// Leaf value storable in Node
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -18,7 +23,48 @@ pub enum Value {
     F64(f64),
 
     /// Represents a JSON string
-    String(Box<str>)
+    String(Box<str>),
+
+    /// Represents a counter, appears as a JSON integer
+    Counter(Counter)
+}
+
+/// PN-counter with separate increment / decrement totals per replica.
+///
+/// Each replica only changes its own totals, so merging counters from different replicas is a
+/// matter of keeping the largest totals seen.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct Counter {
+    p: BTreeMap<String, u64>,
+    n: BTreeMap<String, u64>
+}
+
+impl Value {
+    /// Arbitrary but deterministic ordering of values, used to pick a winner between different
+    /// values written with the same timestamp.
+    pub fn tiebreak(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (&Value::Bool(a), &Value::Bool(b)) => a.cmp(&b),
+            (&Value::I64(a), &Value::I64(b)) => a.cmp(&b),
+            (&Value::U64(a), &Value::U64(b)) => a.cmp(&b),
+            (&Value::F64(a), &Value::F64(b)) => a.to_bits().cmp(&b.to_bits()),
+            (&Value::String(ref a), &Value::String(ref b)) => a.cmp(b),
+            (&Value::Counter(ref a), &Value::Counter(ref b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank())
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match *self {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::I64(_) => 2,
+            Value::U64(_) => 3,
+            Value::F64(_) => 4,
+            Value::String(_) => 5,
+            Value::Counter(_) => 6
+        }
+    }
 }
 
 impl Default for Value {
@@ -32,3 +78,84 @@ impl From<String> for Value {
         Value::String(s.into_boxed_str())
     }
 }
+
+impl From<Number> for Value {
+    /// Keeps integers exact, only falling back to `F64` for non-integers
+    fn from(n: Number) -> Self {
+        if let Some(v) = n.as_i64() {
+            Value::I64(v)
+        }
+        else if let Some(v) = n.as_u64() {
+            Value::U64(v)
+        }
+        else {
+            Value::F64(n.as_f64().unwrap_or_default())
+        }
+    }
+}
+
+impl Counter {
+    /// Adds `delta` to the totals of `replica`.
+    pub fn add(&mut self, replica: &str, delta: i64) {
+        let (totals, delta) = match delta >= 0 {
+            true => (&mut self.p, delta as u64),
+            false => (&mut self.n, (-(delta + 1)) as u64 + 1)
+        };
+
+        let total = totals.entry(replica.to_string()).or_insert(0);
+
+        *total = total.wrapping_add(delta);
+    }
+
+    /// Merges totals of another counter. Returns true if this counter changed.
+    pub fn merge(&mut self, other: &Counter) -> bool {
+        let mut changed = false;
+
+        for (totals, other_totals) in vec![(&mut self.p, &other.p), (&mut self.n, &other.n)] {
+            for (replica, other_total) in other_totals {
+                let total = totals.entry(replica.clone()).or_insert(0);
+
+                if *other_total > *total {
+                    *total = *other_total;
+                    changed = true;
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Returns the number of replicas tracked.
+    pub fn len(&self) -> usize {
+        self.p.len() + self.n.len()
+    }
+
+    /// Returns the current value of the counter.
+    pub fn value(&self) -> i64 {
+        let p = self.p.values().fold(0u64, |sum, v| sum.wrapping_add(*v));
+        let n = self.n.values().fold(0u64, |sum, v| sum.wrapping_add(*v));
+
+        p.wrapping_sub(n) as i64
+    }
+}
+
+#[test]
+fn test_counter() {
+    let mut a = Counter::default();
+    let mut b = Counter::default();
+
+    a.add("a", 5);
+    a.add("a", -2);
+    b.add("b", 10);
+    b.add("b", i64::min_value());
+
+    let mut ab = a.clone();
+    let mut ba = b.clone();
+
+    assert!(ab.merge(&b));
+    assert!(ba.merge(&a));
+    assert!(! ab.merge(&ba));
+
+    assert_eq!(ab, ba);
+    assert_eq!(ab.value(), 3 + 10 + i64::min_value());
+}
@@ -3,10 +3,20 @@
 //! The `Zone` structure represents the subtree and is owned by a single thread.
 //!
 //! `ZoneHandle` is the shareable / clonable public interface to a `Zone`.
+//!
+//! Merged diffs are persisted as deltas in the Zone's log. The full data is only saved (folding
+//! the deltas into a snapshot) once the delta chain gets long, or periodically.
+//!
+//! If its data cannot be loaded, a Zone goes into an error state, rejecting user commands until a
+//! retry succeeds. Failed saves are retried while the Zone keeps serving from memory.
 
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
 use std::hash::{Hash, Hasher};
+use std::mem;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::Duration;
 
 use mioco;
 use mioco::sync::mpsc::{channel, Receiver, Sender};
@@ -14,10 +24,30 @@ use serde_json::Value;
 
 use app::AppHandle;
 use command::{Call, Command};
-use delegate::delegate;
-use listener::{Listener, RListener};
+use clock;
+use delegate::ZoneInfo;
+use listener::{BindId, Listener, RListener};
 use node::{DelegatedMatch, Node, Update, Vis, NodeTree};
-use path::Path;
+use path::{is_bucket, Path};
+use value::{Counter, Value as NodeValue};
+
+/// Data is saved once this many deltas were logged since the last save
+const MAX_DELTAS: u64 = 1000;
+
+/// Data with deltas is saved this long (in ms) after the first delta since the last save
+const SNAPSHOT_INTERVAL_MS: u64 = 5 * 60 * 1000;
+
+/// Delay before the first retry of a failed load or save (in ms), doubled on each failure
+const RETRY_MIN_MS: u64 = 100;
+
+/// Maximum delay between retries (in ms)
+const RETRY_MAX_MS: u64 = 30 * 1000;
+
+/// Counts accesses to all Zones, orders accesses for eviction
+static ACCESSES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Access counts of Zones are halved each time all Zones were accessed this many times
+const ACCESS_HALF_LIFE: usize = 10000;
 
 /// Persistent Zone data
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
@@ -30,26 +60,45 @@ pub struct ZoneData {
 #[derive(Clone)]
 pub struct ZoneHandle {
     path: Arc<Path>,
+    usage: Arc<Usage>,
     tx: Sender<ZoneCall>
 }
 
+/// Use of a Zone and its memory, shared by its handles so `EvictionManager` can pick cold Zones
+#[derive(Default)]
+struct Usage {
+    last: AtomicUsize,  // Sequence number of the last access
+    count: AtomicUsize, // Number of accesses, decayed as of `epoch`
+    epoch: AtomicUsize, // Half-lives of `ACCESSES` passed at the last access
+    bytes: AtomicUsize  // Estimated size of loaded data
+}
+
 /// Zones communicate via message passing. This enum is a list of valid calls.
 enum ZoneCall {
     UserCommand(UserCommand),
     Dump(Sender<NodeTree>),
+    Error(String),
+    Flush(Sender<()>),
+    Handoff(ZoneHandle, Path),
     Hibernate,
     Load,
     Loaded(ZoneData),
     Merge(NodeTree, bool),
     MergeWithListeners(NodeTree, Vec<RListener>),
+    Reclaimed(Path, NodeTree, Vec<Listener>),
+    Retry,
     Save,
     Saved,
     Size(Sender<usize>),
-    State(Sender<ZoneState>)
+    Snapshot,
+    State(Sender<ZoneState>),
+    SyncLog(Sender<Result<(), String>>),
+    Undelegate(Path)
 }
 
 struct UserCommand {
     command: Command,
+    client: u64,
     reply: Sender<ZoneResult>,
     listener: Sender<String>
 }
@@ -57,7 +106,12 @@ struct UserCommand {
 #[derive(Default)]
 pub struct ZoneResult {
     pub update: Option<Update>,
-    pub delegated: Vec<DelegatedMatch>
+    pub delegated: Vec<DelegatedMatch>,
+    pub applied: Option<bool>,        // Outcome of conditional calls
+    pub acked: Option<Receiver<()>>,  // Notified once peers received changes of a durable call
+    pub appended: Vec<Receiver<Result<(), String>>>, // Notified once changes of the call are logged
+    pub synced: Vec<Receiver<Result<(), String>>>, // Notified once changes of a durable call are persisted, also by delegated Zones
+    pub error: Option<&'static str>   // Set if the call could not be handled
 }
 
 /// Tracks current state of a Zone
@@ -77,17 +131,34 @@ pub struct Zone {
     rx: Receiver<ZoneCall>,     // Zone message inbox
     queued: VecDeque<ZoneCall>, // When Zone data is not active, queue up all commands
     listeners: Vec<Listener>,   // List of binds
-    writes: u64                 // Number of writes since last fragment check
+    writes: u64,                // Number of writes since last fragment check
+    child_writes: BTreeMap<String, u64>, // Writes per child since last fragment check
+    checked: u64,               // Time of last fragment check
+    reclaimed: Vec<Path>,       // Folded Zones whose files can be deleted after the next save
+    deleting: Vec<Path>,        // Folded Zones whose files can be deleted after the current save
+    logged: bool,               // Diffs were logged since the last log sync
+    spilled: Vec<ZoneHandle>,   // Delegated Zones sent changes since the last log sync
+    deltas: u64,                // Diffs logged since the last save
+    saved: u64,                 // Time of the last save
+    acks: usize,                // Peer acks required for changes of the current call
+    acked: Option<Receiver<()>>, // Notified once peers received changes of the current call
+    appended: Option<Vec<Receiver<Result<(), String>>>>, // Notified once changes of the current call are logged
+    retries: u32,               // Consecutive failed loads or saves
+    flushing: Vec<Sender<()>>,  // Notified once data is saved
+    handing_off: Option<(ZoneHandle, Path)>, // Parent reclaiming data once the current write is done
+    hibernated: bool,           // Data was evicted, next load is a reload
+    retired: bool               // Data was reclaimed by parent, calls are forwarded
     // TODO: size: u64,
     // TODO: prefixes: Option<BTreeMap<String, Node>>
 }
 
 impl ZoneHandle {
-    pub fn dispatch(&self, command: Command, listener: &Sender<String>) -> ZoneResult {
+    pub fn dispatch(&self, command: Command, client: u64, listener: &Sender<String>) -> ZoneResult {
         let (tx, rx) = channel();
 
-        let command = UserCommand { command: command, reply: tx, listener: listener.clone() };
+        let command = UserCommand { command: command, client: client, reply: tx, listener: listener.clone() };
 
+        self.touch();
         self.tx.send(ZoneCall::UserCommand(command)).unwrap();
         rx.recv().unwrap()
     }
@@ -105,12 +176,14 @@ impl ZoneHandle {
     /// Merge data into this `Zone`. The effective parent visibility (through all ancestors) must
     /// be provided.
     pub fn merge(&self, diff: NodeTree, replicate: bool) {
+        self.touch();
         self.tx.send(ZoneCall::Merge(diff, replicate)).unwrap();
     }
 
     /// Same as `merge` except a list of listeners is also provided. The listeners expect to see
     /// changes that would bring them up to date with data in this `Zone`
     pub fn merge_with_listeners(&self, diff: NodeTree, listeners: Vec<RListener>) {
+        self.touch();
         self.tx.send(ZoneCall::MergeWithListeners(diff, listeners)).unwrap();
     }
 
@@ -118,6 +191,53 @@ impl ZoneHandle {
         (*self.path).clone()
     }
 
+    /// Ask `Zone` to fold the delegated `Zone` at relative `path` back into itself. Usually called
+    /// by the delegated `Zone` once it has shrunk.
+    pub fn undelegate(&self, path: &Path) {
+        self.tx.send(ZoneCall::Undelegate(path.clone())).unwrap();
+    }
+
+    /// Take all data and listeners from this `Zone`, which then retires. Usually called by the
+    /// parent `Zone` when reclaiming a delegation. Data is sent to `parent` as `reclaimed` at
+    /// relative `path`, once loaded and no write of it is in flight.
+    pub fn handoff(&self, parent: &ZoneHandle, path: &Path) {
+        self.tx.send(ZoneCall::Handoff(parent.clone(), path.clone())).unwrap();
+    }
+
+    /// Merge data and listeners handed off by the formerly delegated `Zone` at relative `path`.
+    pub fn reclaimed(&self, path: &Path, tree: NodeTree, listeners: Vec<Listener>) {
+        self.touch();
+        self.tx.send(ZoneCall::Reclaimed(path.clone(), tree, listeners)).unwrap();
+    }
+
+    /// Ask `Zone` to make logged diffs durable, including those of delegated `Zone`s it sent
+    /// changes to. The returned channel is notified once done, or disconnected if the data could
+    /// not be loaded.
+    pub fn sync_log(&self) -> Receiver<Result<(), String>> {
+        let (tx, rx) = channel();
+
+        self.send_sync_log(tx);
+        rx
+    }
+
+    fn send_sync_log(&self, reply: Sender<Result<(), String>>) {
+        self.tx.send(ZoneCall::SyncLog(reply)).is_ok(); // dropping `reply` fails the caller
+    }
+
+    /// Signal `Zone` that its data could not be loaded or saved. Usually called by `Store`.
+    pub fn set_error(&self, error: String) {
+        self.tx.send(ZoneCall::Error(error)).unwrap();
+    }
+
+    /// Ask `Zone` to save its data, e.g. before shutting down. Queued calls are handled first. The
+    /// returned channel is notified once data is saved.
+    pub fn flush(&self) -> Receiver<()> {
+        let (tx, rx) = channel();
+
+        self.tx.send(ZoneCall::Flush(tx)).unwrap();
+        rx
+    }
+
     /// Signal `Zone` to hibernate. Usually called by `EvictionManager`.
     pub fn hibernate(&self) {
         self.tx.send(ZoneCall::Hibernate).unwrap();
@@ -157,17 +277,50 @@ impl ZoneHandle {
         rx.recv().unwrap()
     }
 
+    /// Returns the sequence number of the last access, higher is more recent.
+    pub fn last_access(&self) -> usize {
+        self.usage.last.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of accesses, halved for every `ACCESS_HALF_LIFE` accesses to all Zones
+    /// since, so Zones that were hot long ago can be evicted.
+    pub fn accesses(&self) -> usize {
+        let epoch = ACCESSES.load(Ordering::Relaxed) / ACCESS_HALF_LIFE;
+
+        decay(self.usage.count.load(Ordering::Relaxed), self.usage.epoch.load(Ordering::Relaxed), epoch)
+    }
+
+    /// Returns the estimated size of loaded data, without calling the Zone.
+    pub fn loaded_bytes(&self) -> usize {
+        self.usage.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Sets the estimated size of loaded data and returns the previous one. Usually called by the
+    /// `Zone` itself.
+    pub fn set_loaded_bytes(&self, bytes: usize) -> usize {
+        self.usage.bytes.swap(bytes, Ordering::Relaxed)
+    }
+
+    /// Records an access for eviction.
+    fn touch(&self) {
+        let seq = ACCESSES.fetch_add(1, Ordering::Relaxed) + 1;
+        let epoch = seq / ACCESS_HALF_LIFE;
+        let count = decay(self.usage.count.load(Ordering::Relaxed), self.usage.epoch.swap(epoch, Ordering::Relaxed), epoch);
+
+        self.usage.last.store(seq, Ordering::Relaxed);
+        self.usage.count.store(count + 1, Ordering::Relaxed);
+    }
+
     /// Creates a noop ZoneHandle for testing
     #[cfg(test)]
     pub fn test_handle(path: Arc<Path>) -> ZoneHandle {
         let (tx, rx) = channel();
 
-        use std::mem;
-
         mem::forget(rx);
 
         ZoneHandle {
             path: path,
+            usage: Default::default(),
             tx: tx
         }
     }
@@ -194,6 +347,7 @@ impl ZoneState {
     const ACTIVE: u64  = 3;
     const DIRTY: u64   = 4;
     const WRITING: u64 = 5;
+    const ERROR: u64   = 6;
 
     /// Zone is idle / hibernating
     pub fn is_idle(&self) -> bool { self.state == ZoneState::IDLE }
@@ -214,12 +368,15 @@ impl ZoneState {
     /// Write pending, data will be clean when done
     pub fn is_writing(&self) -> bool { self.state == ZoneState::WRITING }
 
+    /// Data could not be loaded, user commands are rejected until a retry succeeds
+    pub fn is_error(&self) -> bool { self.state == ZoneState::ERROR }
+
     /// Data is ready, allow reads and writes
-    pub fn is_ready(&self) -> bool { self.state >= ZoneState::ACTIVE }
+    pub fn is_ready(&self) -> bool { self.state >= ZoneState::ACTIVE && self.state <= ZoneState::WRITING }
 
     /// Set to specified state
     pub fn set(&mut self, state: u64) {
-        assert!(state <= ZoneState::WRITING);
+        assert!(state <= ZoneState::ERROR);
         self.state = state;
     }
 }
@@ -241,6 +398,7 @@ impl Zone {
         let (tx, rx) = channel();
 
         let arc_path = Arc::new(path.clone());
+        let checked = app.clock.now();
 
         Zone {
             path: arc_path.clone(),
@@ -256,17 +414,45 @@ impl Zone {
             },
             state: Default::default(),
             app: app,
-            handle: ZoneHandle { path: arc_path, tx: tx },
+            handle: ZoneHandle { path: arc_path, usage: Default::default(), tx: tx },
             rx: rx,
             queued: VecDeque::new(),
             listeners: vec![],
-            writes: 0
+            writes: 0,
+            child_writes: BTreeMap::new(),
+            checked: checked,
+            reclaimed: vec![],
+            deleting: vec![],
+            logged: false,
+            spilled: vec![],
+            deltas: 0,
+            saved: checked,
+            acks: 0,
+            acked: None,
+            appended: None,
+            retries: 0,
+            flushing: vec![],
+            handing_off: None,
+            hibernated: false,
+            retired: false
         }
     }
 
     fn message_loop(mut self) {
         loop {
-            if self.state.is_ready() {
+            if self.retired {
+                // Stop once nothing can reach this Zone any more
+                let call = match self.queued.pop_front() {
+                    Some(call) => call,
+                    None => match self.rx.recv() {
+                        Ok(call) => call,
+                        Err(_) => return
+                    }
+                };
+
+                self.forward(call);
+            }
+            else if self.state.is_ready() {
                 // Handle possibly queued calls before we were ready
                 let call = self.queued.pop_front()
                     .unwrap_or_else(|| self.rx.recv().unwrap());
@@ -278,13 +464,25 @@ impl Zone {
                 let call = self.rx.recv().unwrap();
 
                 match call {
+                    ZoneCall::Error(_) |
                     ZoneCall::Load |
                     ZoneCall::Loaded(_) |
                     ZoneCall::Hibernate |
+                    ZoneCall::Retry |
                     ZoneCall::Size(_) |
+                    ZoneCall::Snapshot |
                     ZoneCall::State(_) => {
                         self.handle_call(call);
                     },
+                    ZoneCall::UserCommand(cmd) if self.state.is_error() => {
+                        self.reject(cmd);
+                    },
+                    ZoneCall::SyncLog(_) if self.state.is_error() => {
+                        // Changes could not be persisted, dropping the reply fails the caller
+                    },
+                    ZoneCall::Flush(reply) if self.state.is_idle() || self.state.is_error() => {
+                        reply.send(()).is_ok(); // no data to save
+                    },
                     _ => {
                         self.queued.push_back(call);
 
@@ -301,13 +499,47 @@ impl Zone {
     fn handle_call(&mut self, call: ZoneCall) {
         match call {
             ZoneCall::UserCommand(cmd) => {
-                let result = self.dispatch(cmd.command, cmd.listener);
+                let durable = cmd.command.durable;
+
+                self.acks = cmd.command.acks;
+                self.appended = Some(vec![]);
+
+                let mut result = self.dispatch(cmd.command, cmd.client, cmd.listener);
+
+                // Changes are replied to once logged. Those spilled into delegated Zones are
+                // logged by them, durable calls wait for that below.
+                result.acked = self.acked.take();
+                result.appended = self.appended.take().unwrap_or_default();
+                self.acks = 0;
+
+                // Changes of durable calls must be persisted before they are acknowledged,
+                // including those spilled into delegated Zones
+                if durable {
+                    result.synced = self.sync_log();
+                }
 
                 cmd.reply.send(result).unwrap(); // TODO: don't crash the Zone!
             },
             ZoneCall::Dump(reply) => {
                 reply.send(self.dump()).unwrap();
             },
+            ZoneCall::Error(err) => {
+                self.set_error(err);
+            },
+            ZoneCall::Flush(reply) => {
+                self.flush(reply);
+            },
+            ZoneCall::Handoff(parent, path) => {
+                // Stored data must not change once the parent may delete it
+                if self.state.is_writing() {
+                    self.handing_off = Some((parent, path));
+                }
+                else {
+                    let (tree, listeners) = self.handoff();
+
+                    parent.reclaimed(&path, tree, listeners);
+                }
+            },
             ZoneCall::Load => {
                 self.load();
             },
@@ -325,9 +557,15 @@ impl Zone {
                 self.merge_with_listeners(diff, listeners);
                 self.split_check();
             },
+            ZoneCall::Reclaimed(path, tree, listeners) => {
+                self.reclaimed(path, tree, listeners);
+            },
             ZoneCall::Hibernate => {
                 self.hibernate();
             },
+            ZoneCall::Retry => {
+                self.retry();
+            },
             ZoneCall::Save => {
                 self.save();
             },
@@ -337,28 +575,146 @@ impl Zone {
             ZoneCall::Size(reply) => {
                 reply.send(self.size()).unwrap();
             },
+            ZoneCall::Snapshot => {
+                self.snapshot();
+            },
             ZoneCall::State(reply) => {
                 reply.send(self.state()).unwrap();
+            },
+            ZoneCall::SyncLog(reply) => {
+                let synced = self.sync_log();
+
+                // Wait for the Store and delegated Zones without blocking this one
+                mioco::spawn(move|| {
+                    let result = synced.iter()
+                        .map(|rx| rx.recv().unwrap_or_else(|_| Err("Zone unavailable".into())))
+                        .fold(Ok(()), |result, synced| result.and(synced));
+
+                    reply.send(result).is_ok(); // ignore if caller goes away
+                });
+            },
+            ZoneCall::Undelegate(path) => {
+                self.undelegate(&path);
             }
         }
     }
 
-    pub fn dispatch(&mut self, command: Command, tx: Sender<String>) -> ZoneResult {
+    /// Passes calls made to a retired `Zone` on to the `Zone` now holding its data.
+    fn forward(&mut self, call: ZoneCall) {
+        let (prefix, zone) = self.app.manager.find_nearest(&self.path);
+        let relative = self.path.slice(prefix.len());
+
+        match call {
+            ZoneCall::UserCommand(mut cmd) => {
+                let mut path = relative.clone();
+
+                path.append(&mut cmd.command.path);
+                cmd.command.path = path;
+
+                let mut result = zone.dispatch(cmd.command, cmd.client, &cmd.listener);
+
+                // Caller expects results relative to this Zone
+                result.update = result.update.and_then(|update| update.descend(&relative.without_buckets().path));
+
+                for d in result.delegated.iter_mut() {
+                    d.path = d.path.slice(relative.len());
+                }
+
+                cmd.reply.send(result).unwrap();
+            },
+            ZoneCall::Merge(diff, replicate) => {
+                zone.merge(diff.node.prepend_path(&relative.path).noop_vis(), replicate);
+            },
+            ZoneCall::MergeWithListeners(diff, listeners) => {
+                let listeners = listeners.into_iter().map(|l| l.prepend_path(&relative)).collect();
+
+                zone.merge_with_listeners(diff.node.prepend_path(&relative.path).noop_vis(), listeners);
+            },
+            ZoneCall::Undelegate(mut path) => {
+                let mut prefixed = relative.clone();
+
+                prefixed.append(&mut path);
+                zone.undelegate(&prefixed);
+            },
+            ZoneCall::Dump(reply) => {
+                reply.send(Default::default()).unwrap();
+            },
+            ZoneCall::Handoff(parent, path) => {
+                parent.reclaimed(&path, Default::default(), vec![]);
+            },
+            ZoneCall::Reclaimed(mut path, tree, listeners) => {
+                let mut prefixed = relative.clone();
+
+                prefixed.append(&mut path);
+                zone.reclaimed(&prefixed, tree, listeners);
+            },
+            ZoneCall::Size(reply) => {
+                reply.send(0).unwrap();
+            },
+            ZoneCall::State(reply) => {
+                reply.send(self.state()).unwrap();
+            },
+            ZoneCall::Flush(reply) => {
+                reply.send(()).is_ok(); // data is saved by the Zone now holding it
+            },
+            ZoneCall::SyncLog(reply) => {
+                zone.send_sync_log(reply);
+            },
+            ZoneCall::Error(_) |
+            ZoneCall::Hibernate |
+            ZoneCall::Load |
+            ZoneCall::Loaded(_) |
+            ZoneCall::Retry |
+            ZoneCall::Save |
+            ZoneCall::Saved |
+            ZoneCall::Snapshot => {}
+        }
+    }
+
+    pub fn dispatch(&mut self, command: Command, client: u64, tx: Sender<String>) -> ZoneResult {
         match command.call {
             Call::Bind => {
-                let (update, delegated) = self.bind(&command.path, tx);
+                let bind = BindId::new(client, command.id);
+                let (update, delegated) = self.bind(&command.path, bind, tx);
+
+                ZoneResult { update: update, delegated: delegated, ..Default::default() }
+            },
+            Call::Cas => {
+                let (applied, update, delegated) = self.cas(&command.path, command.timestamp, command.params);
+
+                if applied == Some(true) {
+                    self.split_check();
+                }
+
+                ZoneResult { update: update, delegated: delegated, applied: applied, ..Default::default() }
+            },
+            Call::Incr => {
+                let (update, delegated) = self.incr(&command.path, command.timestamp, command.params.as_i64().unwrap_or_default());
+
+                if delegated.is_empty() {
+                    self.split_check();
+                }
 
-                ZoneResult { update: update, delegated: delegated }
+                ZoneResult { update: update, delegated: delegated, ..Default::default() }
             },
             Call::Kill => {
                 self.kill(&command.path, command.timestamp);
+                self.split_check();
 
                 ZoneResult { ..Default::default() }
             }
             Call::Read => {
                 let (update, delegated) = self.read(&command.path);
 
-                ZoneResult { update: update, delegated: delegated }
+                ZoneResult { update: update, delegated: delegated, ..Default::default() }
+            },
+            Call::Unbind => {
+                let delegated = match command.params.as_u64() {
+                    Some(id) => self.unbind(&command.path, BindId::new(client, id)),
+                    None => vec![]
+                };
+
+                ZoneResult { delegated: delegated, ..Default::default() }
             },
             Call::Write => {
                 self.write(&command.path, command.timestamp, command.params);
@@ -370,11 +726,83 @@ impl Zone {
     }
 
     /// Bind value(s)
-    pub fn bind(&mut self, path: &Path, tx: Sender<String>) -> (Option<Update>, Vec<DelegatedMatch>) {
+    pub fn bind(&mut self, path: &Path, bind: BindId, tx: Sender<String>) -> (Option<Update>, Vec<DelegatedMatch>) {
         // TODO verify path
         // TODO don't sub if path has been delegated completely
 
-        self.sub(path, tx);
+        self.sub(path, bind, tx);
+        self.read(path)
+    }
+
+    /// Unbind value(s). Removes listeners created by `bind` and returns delegated Zones that may
+    /// also hold the bind's listeners.
+    pub fn unbind(&mut self, path: &Path, bind: BindId) -> Vec<DelegatedMatch> {
+        self.listeners.retain(|l| l.bind != bind);
+
+        // Delegated listeners follow the same matches as the original bind
+        let (_, delegated) = self.read(path);
+
+        delegated
+    }
+
+    /// Conditionally write a value. `params` is an object containing the new `value`, and an
+    /// `expect`ed current scalar value and/or the expected `updated` timestamp of the current value.
+    ///
+    /// Returns `Some(true)` if written, otherwise `Some(false)` with the current value. If `path`
+    /// is delegated, returns `None` with the delegated match instead.
+    pub fn cas(&mut self, path: &Path, ts: u64, mut params: Value) -> (Option<bool>, Option<Update>, Vec<DelegatedMatch>) {
+        let (update, delegated) = self.read(path);
+
+        if delegated.len() > 0 {
+            return (None, None, delegated);
+        }
+
+        let (current, updated) = self.data.tree.get(path);
+
+        let mut matches = true;
+
+        if let Some(expect) = params.get("expect") {
+            matches &= Node::expand(expect.clone(), 0).value() == &current;
+        }
+
+        if let Some(expect) = params.get("updated") {
+            matches &= expect.as_u64() == Some(updated);
+        }
+
+        if ! matches {
+            return (Some(false), update, vec![]);
+        }
+
+        let value = params.get_mut("value").map_or(Value::Null, |v| mem::replace(v, Value::Null));
+
+        self.write(path, ts, value);
+
+        (Some(true), None, vec![])
+    }
+
+    /// Increment the counter at `path` by `delta`. A value that is not a counter is replaced by a
+    /// new counter.
+    ///
+    /// Returns the new value. If `path` is delegated, returns the delegated match instead.
+    pub fn incr(&mut self, path: &Path, ts: u64, delta: i64) -> (Option<Update>, Vec<DelegatedMatch>) {
+        let (_, delegated) = self.read(path);
+
+        if delegated.len() > 0 {
+            return (None, delegated);
+        }
+
+        // Counters keep their original timestamp so increments from all replicas are combined
+        let (mut counter, ts) = match self.data.tree.get(path) {
+            (NodeValue::Counter(counter), updated) => (counter, updated),
+            _ => (Counter::default(), ts)
+        };
+
+        counter.add(&self.app.id.to_string(), delta);
+
+        let diff = Node::new(NodeValue::Counter(counter), ts).prepend_path(&path.path);
+
+        self.merge(diff.noop_vis(), true);
+
         self.read(path)
     }
 
@@ -391,7 +819,12 @@ impl Zone {
     /// Merge value(s). Merge is generic and most operations are defined as a merge. Set
     /// `replicate` flag if merge was due to a user command.
     pub fn merge(&mut self, mut diff: NodeTree, replicate: bool) {
-        let (update, externals) = self.data.tree.merge(&mut diff);
+        let overwritten = self.data.tree.node.overwritten_byte_size(&diff.node);
+        let (update, externals, conflicts) = self.data.tree.merge(&mut diff);
+
+        if conflicts > 0 {
+            self.app.stats.zones.conflicts.add(conflicts);
+        }
 
         // Only notify if there are changes
         if let Some(update) = update {
@@ -399,12 +832,50 @@ impl Zone {
         }
 
         if ! diff.node.is_noop() {
+            // Estimated until the size is recounted on save
+            let size = (self.handle.loaded_bytes() + diff.node.total_byte_size()).saturating_sub(overwritten);
+
+            self.set_loaded_bytes(size);
+
+            let appended = self.app.store.append(&self.path, &diff);
+
+            if let Some(ref mut pending) = self.appended {
+                pending.push(appended);
+            }
+
+            self.logged = true;
+            self.deltas += 1;
+
+            // Fold deltas into a full save, at the latest a while after the first one
+            if self.deltas >= MAX_DELTAS {
+                self.dirty();
+            }
+            else if self.deltas == 1 {
+                let tx = self.handle.tx.clone();
+
+                mioco::spawn(move|| {
+                    mioco::sleep(Duration::from_millis(SNAPSHOT_INTERVAL_MS));
+                    tx.send(ZoneCall::Snapshot).is_ok(); // ignore if Zone went away
+                });
+            }
+
             self.writes += 1;
-            self.dirty();
+
+            let child_writes = &mut self.child_writes;
+
+            diff.node.each_child(|k, _| {
+                *child_writes.entry(k.clone()).or_insert(0) += 1;
+            });
         }
 
         if externals.len() > 0 {
             for external in externals {
+                // Node is in a transition back from being delegated
+                if external.reclaim {
+                    self.reclaim(external.path);
+                    continue;
+                }
+
                 // Check if Node is in a transition to being delegated.
                 // Existing listeners are either:
                 //   - unaffected by delegation
@@ -426,7 +897,12 @@ impl Zone {
 
                 // Data meant for delegated node
                 if x_listeners.is_empty() {
-                    self.app.manager.send_external(&self.path, external, replicate);
+                    let zone = self.app.manager.send_external(&self.path, external, replicate);
+
+                    // Changes of local calls are persisted along with this Zone's
+                    if replicate && self.spilled.iter().all(|z| z.path != zone.path) {
+                        self.spilled.push(zone);
+                    }
                 }
                 else {
                     self.app.manager.send_external_with_listeners(&self.path, external, x_listeners);
@@ -435,7 +911,16 @@ impl Zone {
         }
 
         if replicate && ! diff.node.is_noop() {
-            self.app.cluster.replicate(&self.path, diff);
+            if self.acks > 0 {
+                let (tx, rx) = channel();
+
+                self.app.cluster.replicate_acked(&self.path, diff, self.acks, tx);
+                self.acked = Some(rx);
+                self.acks = 0;
+            }
+            else {
+                self.app.cluster.replicate(&self.path, diff);
+            }
         }
     }
 
@@ -447,7 +932,7 @@ impl Zone {
     /// recursive delegation problem.
     pub fn merge_with_listeners(&mut self, diff: NodeTree, listeners: Vec<RListener>) {
         // First, bring listeners up to date
-        let (update, externals) = {
+        let (update, externals, _) = {
             // TODO: workaround merge mutating receiver and argument
             let mut tree_clone = self.data.tree.clone();
             let mut diff_clone = diff.clone();
@@ -502,6 +987,66 @@ impl Zone {
         self.listeners.append(&mut listeners);
     }
 
+    /// Fold the delegated `Zone` at relative `path` back into this `Zone`. The un-delegation is
+    /// replicated, so every replica reclaims from its own delegated `Zone`.
+    pub fn undelegate(&mut self, path: &Path) {
+        let (_, delegated) = self.read(path);
+
+        // Ignore stale requests
+        if delegated.iter().any(|d| d.path == *path) {
+            let pending = self.app.clock.issue();
+            let diff = Node::undelegate(pending.timestamp()).prepend_path(&path.path);
+
+            self.merge(diff.noop_vis(), true);
+        }
+    }
+
+    /// Ask the formerly delegated `Zone` at relative `path` to hand off its data and listeners,
+    /// without waiting for it to load.
+    fn reclaim(&mut self, path: Path) {
+        let mut zone_path = self.path();
+
+        zone_path.append(&mut path.clone());
+
+        self.app.manager.load(&zone_path).handoff(&self.handle, &path);
+    }
+
+    /// Take data and listeners handed off by the formerly delegated `Zone` at relative `path`.
+    /// Listeners are handed over the same way as when delegating.
+    fn reclaimed(&mut self, path: Path, tree: NodeTree, listeners: Vec<Listener>) {
+        let mut zone_path = self.path();
+
+        zone_path.append(&mut path.clone());
+
+        // Listeners that still match here were never removed from this Zone
+        let listeners = listeners
+            .iter()
+            .filter(|l| self.listeners.iter().all(|e| e.bind != l.bind))
+            .map(|l| l.reclaim(&path))
+            .collect();
+
+        self.merge_with_listeners(tree.node.prepend_path(&path.path).noop_vis(), listeners);
+
+        // Old file is only safe to delete once reclaimed data has been saved here
+        self.reclaimed.push(zone_path);
+    }
+
+    /// Give up all data and listeners to the parent `Zone` and retire.
+    fn handoff(&mut self) -> (NodeTree, Vec<Listener>) {
+        let tree = mem::replace(&mut self.data.tree, Default::default());
+        let listeners = mem::replace(&mut self.listeners, vec![]);
+
+        self.retired = true;
+        self.state.set(ZoneState::IDLE);
+        self.set_loaded_bytes(0);
+        self.app.manager.zone_retired(self.handle.clone());
+
+        // Drop own sender, so this Zone stops once all other handles are gone
+        self.handle.tx = channel().0;
+
+        (tree, listeners)
+    }
+
     /// Read value(s)
     pub fn read(&self, path: &Path) -> (Option<Update>, Vec<DelegatedMatch>) {
         // TODO verify path
@@ -529,17 +1074,35 @@ impl Zone {
 
             self.data.tree = data.tree;
             self.state.set(ZoneState::ACTIVE);
+            self.retries = 0;
+
+            let size = self.size();
+
+            self.set_loaded_bytes(size);
+
+            if self.hibernated {
+                self.hibernated = false;
+                self.app.stats.zones.reloads.increment();
+            }
         }
         else {
             unimplemented!()
         }
     }
 
-    /// Callback to notify Zone to hibernate.
+    /// Callback to notify Zone to hibernate. Logged deltas are saved first, so hibernated Zones
+    /// don't only live in their logs.
     pub fn hibernate(&mut self) {
+        if self.state.is_active() && self.deltas > 0 {
+            self.dirty();
+        }
+
         if self.state.is_active() {
             self.state.set(ZoneState::IDLE);
             self.data.tree = Default::default();
+            self.set_loaded_bytes(0);
+            self.hibernated = true;
+            self.app.stats.zones.evictions.increment();
             self.app.manager.zone_hibernated(self.handle.clone());
         }
         else {
@@ -550,6 +1113,14 @@ impl Zone {
     /// Callback to notify Zone of available resources to persist dirty data.
     pub fn save(&mut self) {
         if self.state.is_dirty() {
+            self.gc();
+            self.deleting.append(&mut self.reclaimed);
+            self.deltas = 0;
+            self.saved = self.app.clock.now();
+
+            let size = self.size();
+
+            self.set_loaded_bytes(size);
             self.app.store.write(&self.handle, &self.path, &self.data);
             self.state.set(ZoneState::WRITING);
         }
@@ -560,8 +1131,16 @@ impl Zone {
 
     /// Callback to notify Zone that data was persisted.
     pub fn saved(&mut self) {
+        // Diffs logged before the write are in the saved data
+        self.app.store.truncate_log(&self.path);
+        self.retries = 0;
+
         if self.state.is_writing() {
             self.state.set(ZoneState::ACTIVE);
+
+            for tx in self.flushing.drain(..) {
+                tx.send(()).is_ok(); // ignore if caller goes away
+            }
         }
         else if self.state.is_dirty() {
             // Zone dirtied itself during a write
@@ -570,6 +1149,85 @@ impl Zone {
         else {
             unimplemented!();
         }
+
+        for path in self.deleting.drain(..) {
+            self.app.store.delete(&path);
+        }
+
+        self.finish_handoff();
+    }
+
+    /// Hands off to the parent waiting for the write that was in flight.
+    fn finish_handoff(&mut self) {
+        if let Some((parent, path)) = self.handing_off.take() {
+            let (tree, listeners) = self.handoff();
+
+            parent.reclaimed(&path, tree, listeners);
+        }
+    }
+
+    /// Callback for stores to notify Zone that loading or saving data failed. Usually called by a
+    /// `Store` process.
+    pub fn set_error(&mut self, err: String) {
+        self.app.stats.zones.errors.increment();
+
+        if self.state.is_loading() {
+            error!("Error loading zone {:?}: {}", self.path, err);
+            self.state.set(ZoneState::ERROR);
+
+            // Clients waiting for data find out instead of hanging
+            for call in mem::replace(&mut self.queued, VecDeque::new()) {
+                match call {
+                    ZoneCall::UserCommand(cmd) => self.reject(cmd),
+                    ZoneCall::SyncLog(_) => {}, // queued changes are not persisted yet
+                    ZoneCall::Flush(reply) => {
+                        reply.send(()).is_ok(); // no data to save
+                    },
+                    call => self.queued.push_back(call)
+                }
+            }
+        }
+        else if self.state.is_writing() || self.state.is_dirty() {
+            error!("Error saving zone {:?}: {}", self.path, err);
+
+            // Logged deltas still hold the changes, so keep serving and save again later. Callers
+            // waiting for the save find out by their replies being dropped.
+            self.state.set(ZoneState::DIRTY);
+            self.flushing.clear();
+            self.reclaimed.append(&mut self.deleting);
+            self.finish_handoff();
+        }
+        else {
+            return;
+        }
+
+        let delay = cmp::min(RETRY_MIN_MS << cmp::min(self.retries, 16), RETRY_MAX_MS);
+        let tx = self.handle.tx.clone();
+
+        self.retries += 1;
+
+        mioco::spawn(move|| {
+            mioco::sleep(Duration::from_millis(delay));
+            tx.send(ZoneCall::Retry).is_ok(); // ignore if Zone went away
+        });
+    }
+
+    /// Retries a failed load or save.
+    fn retry(&mut self) {
+        if self.state.is_error() {
+            self.app.store.load(&self.handle, &self.path);
+            self.state.set(ZoneState::LOADING);
+        }
+        else if self.state.is_dirty() {
+            self.app.store.request_write(&self.handle);
+        }
+    }
+
+    /// Replies to a user command that cannot be handled.
+    fn reject(&self, cmd: UserCommand) {
+        let result = ZoneResult { error: Some("Zone unavailable"), ..Default::default() };
+
+        cmd.reply.send(result).is_ok(); // ignore if caller goes away
     }
 
     /// Get zone path.
@@ -588,6 +1246,19 @@ impl Zone {
         self.data.tree.node.total_byte_size()
     }
 
+    /// Updates the estimated size of loaded data, which `Manager` budgets memory with.
+    fn set_loaded_bytes(&mut self, bytes: usize) {
+        let previous = self.handle.set_loaded_bytes(bytes);
+        let stat = &self.app.stats.zones.local_bytes;
+
+        if bytes > previous {
+            stat.add(bytes - previous);
+        }
+        else {
+            stat.sub(previous - bytes);
+        }
+    }
+
     /// Get zone state.
     pub fn state(&self) -> ZoneState {
         self.state
@@ -601,6 +1272,52 @@ impl Zone {
         self.merge(diff.noop_vis(), true);
     }
 
+    /// Saves data including logged deltas, notifying `reply` once done.
+    fn flush(&mut self, reply: Sender<()>) {
+        if self.state.is_active() && self.deltas > 0 {
+            self.dirty();
+        }
+
+        if self.state.is_active() {
+            reply.send(()).is_ok(); // ignore if caller goes away
+        }
+        else {
+            self.flushing.push(reply);
+        }
+    }
+
+    /// Saves deltas logged a while ago, unless saved since.
+    fn snapshot(&mut self) {
+        let elapsed = clock::millis(self.app.clock.now() - self.saved);
+
+        if self.state.is_active() && self.deltas > 0 && elapsed >= SNAPSHOT_INTERVAL_MS {
+            self.dirty();
+        }
+    }
+
+    /// Asks the Store to make logged diffs durable, and delegated Zones sent changes since the last
+    /// sync to do the same. Returns their notifications.
+    fn sync_log(&mut self) -> Vec<Receiver<Result<(), String>>> {
+        let mut synced = vec![];
+
+        if self.logged {
+            synced.push(self.app.store.sync_log(&self.path));
+            self.logged = false;
+        }
+
+        synced.extend(self.spilled.drain(..).map(|zone| zone.sync_log()));
+        synced
+    }
+
+    /// Clears tombstones that are no longer needed.
+    fn gc(&mut self) {
+        let removed = self.data.tree.gc(self.app.clock.tombstone_horizon());
+
+        if removed > 0 {
+            self.app.stats.zones.tombstones_cleared.add(removed);
+        }
+    }
+
     fn dirty(&mut self) {
         if self.state.is_dirty() {
             return; // already dirty
@@ -629,8 +1346,8 @@ impl Zone {
         });
     }
 
-    fn sub(&mut self, path: &Path, tx: Sender<String>) {
-        let listener = Listener::new(self.path.clone(), Arc::new(path.clone()), tx);
+    fn sub(&mut self, path: &Path, bind: BindId, tx: Sender<String>) {
+        let listener = Listener::new(bind, self.path.clone(), Arc::new(path.clone()), tx);
 
         self.listeners.push(listener);
     }
@@ -639,9 +1356,41 @@ impl Zone {
         if self.writes >= 10 {
             self.writes = 0;
 
-            if let Some(delegate_node) = delegate(&self.data.tree.node) {
+            let now = self.app.clock.now();
+
+            // Buckets are permanent, their parent would be too wide
+            let foldable = self.path.path.last().map_or(false, |p| ! is_bucket(p));
+
+            let (delegate_node, undelegate) = {
+                let zone = ZoneInfo {
+                    path: &self.path,
+                    node: &self.data.tree.node,
+                    listeners: &self.listeners,
+                    writes: &self.child_writes,
+                    elapsed: clock::millis(now - self.checked)
+                };
+
+                match self.app.delegation.delegate(&zone, now) {
+                    Some(delegate_node) => (Some(delegate_node), false),
+                    None => (None, foldable && self.app.delegation.undelegate(&zone))
+                }
+            };
+
+            self.child_writes.clear();
+            self.checked = now;
+
+            if let Some(delegate_node) = delegate_node {
                 self.merge(delegate_node.noop_vis(), true);
             }
+            else if undelegate {
+                let mut parent = self.path();
+
+                parent.pop();
+
+                let (prefix, zone) = self.app.manager.find_nearest(&parent);
+
+                zone.undelegate(&self.path.slice(prefix.len()));
+            }
         }
     }
 }
@@ -655,6 +1404,11 @@ impl ZoneData {
     }
 }
 
+/// Halves `count` for each half-life passed between epochs `from` and `to`.
+fn decay(count: usize, from: usize, to: usize) -> usize {
+    count.checked_shr(to.saturating_sub(from) as u32).unwrap_or(0)
+}
+
 #[test]
 fn test_zone_state() {
     let mut state: ZoneState = Default::default();
@@ -677,4 +1431,318 @@ fn test_zone_state() {
     state.set(ZoneState::WRITING);
     assert!(state.is_writing());
     assert!(state.is_ready());
+
+    state.set(ZoneState::ERROR);
+    assert!(state.is_error());
+    assert!(!state.is_ready());
+}
+
+#[test]
+fn test_unbind() {
+    use app;
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let app = app::App::new(id);
+    let mut zone = Zone::new(app.handle(), &path!());
+
+    let (tx, _rx) = channel();
+
+    zone.bind(&path!(moo), BindId::new(1, 1), tx.clone());
+    zone.bind(&path!(moo), BindId::new(1, 2), tx.clone());
+    zone.bind(&path!(moo), BindId::new(2, 1), tx);
+    assert_eq!(zone.listeners.len(), 3);
+
+    zone.unbind(&path!(moo), BindId::new(1, 1));
+    assert_eq!(zone.listeners.len(), 2);
+    assert!(zone.listeners.iter().all(|l| l.bind != BindId::new(1, 1)));
+}
+
+#[test]
+fn test_cas() {
+    use app;
+    use serde_json;
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let app = app::App::new(id);
+    let mut zone = Zone::new(app.handle(), &path!());
+
+    zone.state.set(ZoneState::ACTIVE);
+    zone.write(&path!(), 1, serde_json::from_str("{}").unwrap());
+
+    let params = serde_json::from_str(r#"{ "expect": null, "value": 42 }"#).unwrap();
+    let (applied, _, _) = zone.cas(&path!(moo), 1000, params);
+    assert_eq!(applied, Some(true));
+
+    let params = serde_json::from_str(r#"{ "expect": 41, "value": 43 }"#).unwrap();
+    let (applied, update, _) = zone.cas(&path!(moo), 2000, params);
+    assert_eq!(applied, Some(false));
+    assert!(update.is_some());
+
+    let params = serde_json::from_str(r#"{ "updated": 1000, "value": 43 }"#).unwrap();
+    let (applied, _, _) = zone.cas(&path!(moo), 3000, params);
+    assert_eq!(applied, Some(true));
+    assert_eq!(zone.data.tree.get(&path!(moo)), (::value::Value::I64(43), 3000));
+}
+
+#[test]
+fn test_incr() {
+    use app;
+    use serde_json;
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let app = app::App::new(id);
+    let mut zone = Zone::new(app.handle(), &path!());
+
+    zone.state.set(ZoneState::ACTIVE);
+    zone.write(&path!(), 1, serde_json::from_str("{}").unwrap());
+
+    zone.incr(&path!(moo), 1000, 2);
+    let (update, _) = zone.incr(&path!(moo), 2000, 3);
+
+    assert_eq!(update.unwrap().to_json()[0]["moo"][2], 5);
+
+    // Increments keep the counter's original timestamp
+    match zone.data.tree.get(&path!(moo)) {
+        (NodeValue::Counter(c), 1000) => assert_eq!(c.value(), 5),
+        v => panic!("Unexpected counter {:?}", v)
+    }
+}
+
+#[test]
+fn test_kill() {
+    use app;
+    use command::Call;
+    use serde_json::Value;
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let app = app::App::new(id);
+    let mut zone = Zone::new(app.handle(), &path!());
+    let (listener, _) = channel();
+
+    zone.state.set(ZoneState::ACTIVE);
+
+    // Deletes count towards delegation checks like writes, so emptied Zones are folded back
+    for ts in 1..11 {
+        let command = Command {
+            id: ts, call: Call::Kill, path: path!(moo), params: Value::Null, timestamp: ts, durable: false, acks: 0
+        };
+
+        zone.dispatch(command, 1, listener.clone());
+    }
+
+    assert_eq!(zone.writes, 0);
+
+    // User commands are replied to once their changes are logged
+    let (tx, rx) = channel();
+    let command = Command {
+        id: 11, call: Call::Kill, path: path!(moo), params: Value::Null, timestamp: 11, durable: false, acks: 0
+    };
+
+    zone.handle_call(ZoneCall::UserCommand(UserCommand { command: command, client: 1, reply: tx, listener: listener }));
+    assert_eq!(rx.recv().unwrap().appended.len(), 1);
+    assert!(zone.appended.is_none());
+}
+
+#[test]
+fn test_handoff() {
+    use app;
+    use serde_json;
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let app = app::App::new(id);
+    let mut zone = Zone::new(app.handle(), &path!(moo));
+
+    zone.state.set(ZoneState::ACTIVE);
+    zone.write(&path!(cow), 1000, serde_json::from_str("42").unwrap());
+
+    let (tx, _rx) = channel();
+
+    zone.bind(&path!(cow), BindId::new(1, 1), tx);
+
+    let (tree, listeners) = zone.handoff();
+
+    assert_eq!(tree.node.len(), 1);
+    assert_eq!(listeners.len(), 1);
+    assert_eq!(listeners[0].reclaim(&path!(moo)).path, path!(moo.cow));
+
+    // Retired Zone holds nothing
+    assert!(zone.retired);
+    assert!(zone.listeners.is_empty());
+    assert_eq!(zone.dump(), Default::default());
+
+    // Data is only handed off once the write in flight is done
+    let parent = Zone::new(app.handle(), &path!());
+    let mut zone = Zone::new(app.handle(), &path!(moo));
+
+    zone.state.set(ZoneState::ACTIVE);
+    zone.write(&path!(cow), 1000, serde_json::from_str("42").unwrap());
+    zone.state.set(ZoneState::WRITING);
+
+    zone.handle_call(ZoneCall::Handoff(parent.handle.clone(), path!(moo)));
+    assert!(! zone.retired);
+    assert!(parent.rx.try_recv().is_err());
+
+    zone.saved();
+    assert!(zone.retired);
+
+    match parent.rx.try_recv() {
+        Ok(ZoneCall::Reclaimed(path, tree, _)) => {
+            assert_eq!(path, path!(moo));
+            assert_eq!(tree.node.len(), 1);
+        },
+        _ => panic!("Expected reclaimed data")
+    }
+}
+
+#[test]
+fn test_deltas() {
+    use app;
+    use serde_json;
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let app = app::App::new(id);
+    let mut zone = Zone::new(app.handle(), &path!());
+
+    zone.state.set(ZoneState::ACTIVE);
+
+    // Deltas are logged without saving full data, overwritten values don't add up
+    zone.write(&path!(moo), 1, serde_json::from_str("42").unwrap());
+
+    let size = zone.handle.loaded_bytes();
+
+    for ts in 2..MAX_DELTAS {
+        zone.write(&path!(moo), ts, serde_json::from_str("42").unwrap());
+    }
+
+    assert!(zone.state.is_active());
+    assert_eq!(zone.handle.loaded_bytes(), size);
+
+    zone.write(&path!(moo), MAX_DELTAS, serde_json::from_str("42").unwrap());
+    assert!(zone.state.is_dirty());
+
+    zone.save();
+    assert_eq!(zone.deltas, 0);
+
+    // Idle Zones save deltas once the first one is old enough
+    zone.state.set(ZoneState::ACTIVE);
+    zone.write(&path!(moo), MAX_DELTAS + 1, serde_json::from_str("42").unwrap());
+
+    zone.snapshot();
+    assert!(zone.state.is_active());
+
+    zone.saved = 0;
+    zone.snapshot();
+    assert!(zone.state.is_dirty());
+
+    // Hibernating Zones save their deltas first
+    zone.save();
+    zone.state.set(ZoneState::ACTIVE);
+    zone.write(&path!(moo), MAX_DELTAS + 2, serde_json::from_str("42").unwrap());
+
+    zone.hibernate();
+    assert!(zone.state.is_dirty());
+}
+
+#[test]
+fn test_set_error() {
+    use app;
+    use command::Call;
+    use serde_json::Value;
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let app = app::App::new(id);
+    let mut zone = Zone::new(app.handle(), &path!());
+
+    let (tx, rx) = channel();
+    let (listener, _) = channel();
+
+    let command = Command {
+        id: 1, call: Call::Read, path: path!(), params: Value::Null, timestamp: 1000, durable: false, acks: 0
+    };
+
+    zone.queued.push_back(ZoneCall::UserCommand(UserCommand { command: command, client: 1, reply: tx, listener: listener }));
+    zone.queued.push_back(ZoneCall::Merge(Default::default(), false));
+
+    let (flush_tx, flushed) = channel();
+
+    zone.queued.push_back(ZoneCall::Flush(flush_tx));
+    zone.state.set(ZoneState::LOADING);
+
+    // Queued commands are rejected, flushes answered, other calls wait for the retry
+    zone.set_error("moo".into());
+
+    assert!(zone.state.is_error());
+    assert_eq!(rx.recv().unwrap().error, Some("Zone unavailable"));
+    assert_eq!(flushed.recv(), Ok(()));
+    assert_eq!(zone.queued.len(), 1);
+
+    zone.retry();
+    assert!(zone.state.is_loading());
+
+    zone.loaded(Default::default());
+    assert!(zone.state.is_active());
+    assert_eq!(zone.retries, 0);
+
+    // Failed saves leave the Zone serving with dirty data, flushes fail
+    let (flush_tx, flushed) = channel();
+
+    zone.flushing.push(flush_tx);
+    zone.state.set(ZoneState::WRITING);
+    zone.set_error("moo".into());
+
+    assert!(zone.state.is_dirty());
+    assert_eq!(zone.retries, 1);
+    assert!(flushed.recv().is_err());
+}
+
+#[test]
+fn test_flush() {
+    use app;
+    use serde_json;
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let app = app::App::new(id);
+    let mut zone = Zone::new(app.handle(), &path!());
+
+    zone.state.set(ZoneState::ACTIVE);
+
+    // Clean Zones are flushed right away
+    let (tx, rx) = channel();
+
+    zone.flush(tx);
+    assert!(rx.try_recv().is_ok());
+
+    // Logged deltas are saved before the flush completes
+    zone.write(&path!(moo), 1000, serde_json::from_str("42").unwrap());
+
+    let (tx, rx) = channel();
+
+    zone.flush(tx);
+    assert!(zone.state.is_dirty());
+    assert!(rx.try_recv().is_err());
+
+    zone.save();
+    zone.saved();
+    assert!(zone.state.is_active());
+    assert!(rx.try_recv().is_ok());
+}
+
+#[test]
+fn test_accesses() {
+    let zone = ZoneHandle::test_handle(Arc::new(path!(moo)));
+
+    for _ in 0..4 {
+        zone.touch();
+    }
+
+    assert!(zone.accesses() >= 2); // other tests may pass a half-life meanwhile
+
+    // Counts decay as other Zones are accessed, not as Zones are evicted
+    ACCESSES.fetch_add(ACCESS_HALF_LIFE * 2, Ordering::Relaxed);
+    assert!(zone.accesses() <= 1);
+
+    zone.touch();
+    assert!(zone.accesses() >= 1 && zone.accesses() <= 2);
+    assert_eq!(decay(8, 1, 3), 2);
+    assert_eq!(decay(8, 1, 100), 0);
 }